@@ -23,6 +23,206 @@ pub fn xp_to_teff(xp: f64) -> f64 {
     }
 }
 
+// Casagrande et al. 2010 (A&A 512, A54) conversion between B-V and T_eff, for
+// dwarfs and subgiants with 0.18 <= B-V <= 1.29 and solar metallicity.
+pub fn bv_to_teff_casagrande(bv: f64) -> f64 {
+    let x = bv.clamp(0.18, 1.29);
+    5040.0 / (0.5665 + 0.4809 * x - 0.0060 * x * x)
+}
+
+// Mucciarelli & Bellazzini 2020 (RNAAS 4, 52) conversion between dereddened
+// BP-RP and T_eff, for dwarfs with 0.39 <= BP-RP <= 1.50 and solar metallicity.
+pub fn xp_to_teff_mucciarelli(xp: f64) -> f64 {
+    let x = xp.clamp(0.39, 1.50);
+    5040.0 / (0.4929 + 0.5092 * x - 0.0353 * x * x)
+}
+
+// Main-sequence T_eff, B-V and BP-RP from B0V to M6V, abridged from the table
+// of Pecaut & Mamajek 2013 (ApJS 208, 9).
+const PECAUT_MAMAJEK: [(f64, f64, f64); 19] = [
+    (31400.0, -0.30, -0.34),
+    (17000.0, -0.18, -0.17),
+    (12500.0, -0.11, -0.08),
+    (9700.0, 0.00, 0.00),
+    (8080.0, 0.15, 0.22),
+    (7220.0, 0.30, 0.43),
+    (6510.0, 0.44, 0.59),
+    (5920.0, 0.59, 0.75),
+    (5770.0, 0.65, 0.82),
+    (5490.0, 0.74, 0.91),
+    (5280.0, 0.82, 0.98),
+    (4840.0, 0.99, 1.16),
+    (4450.0, 1.15, 1.43),
+    (4050.0, 1.33, 1.70),
+    (3850.0, 1.41, 1.84),
+    (3560.0, 1.49, 2.19),
+    (3210.0, 1.62, 2.70),
+    (3000.0, 1.75, 3.10),
+    (2810.0, 1.90, 3.60),
+];
+
+// Interpolates linearly the T_eff of the main-sequence table, clamping the
+// colour index to the table.
+fn teff_pecaut_mamajek(index: ColorIndex, c: f64) -> f64 {
+    let col = |row: &(f64, f64, f64)| match index {
+        ColorIndex::BpRp => row.2,
+        ColorIndex::BV => row.1,
+    };
+    let first = &PECAUT_MAMAJEK[0];
+    if c <= col(first) {
+        return first.0;
+    }
+    for w in PECAUT_MAMAJEK.windows(2) {
+        if c <= col(&w[1]) {
+            return math::lint(c, col(&w[0]), col(&w[1]), w[0].0, w[1].0);
+        }
+    }
+    PECAUT_MAMAJEK[PECAUT_MAMAJEK.len() - 1].0
+}
+
+/// Colour indices used to compute T_eff.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorIndex {
+    BpRp,
+    BV,
+}
+
+/**
+ * Relation between a colour index and T_eff.
+ **/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TeffRelation {
+    // Jordi et al. (2010), BP-RP
+    Jordi2010,
+    // Mucciarelli & Bellazzini (2020), BP-RP
+    Mucciarelli2020,
+    // Ballesteros (2012), B-V
+    Ballesteros2012,
+    // Casagrande et al. (2010), B-V
+    Casagrande2010,
+    // Main-sequence table of Pecaut & Mamajek (2013), BP-RP and B-V
+    PecautMamajek2013,
+}
+
+impl TeffRelation {
+    pub const NAMES: [&'static str; 5] = [
+        "jordi2010",
+        "mucciarelli2020",
+        "ballesteros2012",
+        "casagrande2010",
+        "pecaut2013",
+    ];
+
+    pub fn from_str(name: &str) -> Option<TeffRelation> {
+        match name.to_lowercase().as_str() {
+            "jordi2010" => Some(TeffRelation::Jordi2010),
+            "mucciarelli2020" => Some(TeffRelation::Mucciarelli2020),
+            "ballesteros2012" => Some(TeffRelation::Ballesteros2012),
+            "casagrande2010" => Some(TeffRelation::Casagrande2010),
+            "pecaut2013" => Some(TeffRelation::PecautMamajek2013),
+            _ => None,
+        }
+    }
+
+    /// Whether the relation takes the given colour index.
+    pub fn supports(&self, index: ColorIndex) -> bool {
+        match self {
+            TeffRelation::Jordi2010 | TeffRelation::Mucciarelli2020 => index == ColorIndex::BpRp,
+            TeffRelation::Ballesteros2012 | TeffRelation::Casagrande2010 => index == ColorIndex::BV,
+            TeffRelation::PecautMamajek2013 => true,
+        }
+    }
+
+    /// Gets T_eff from the value of the given colour index, which must be
+    /// supported by the relation.
+    pub fn teff(&self, index: ColorIndex, c: f64) -> f64 {
+        match self {
+            TeffRelation::Jordi2010 => xp_to_teff(c),
+            TeffRelation::Mucciarelli2020 => xp_to_teff_mucciarelli(c),
+            TeffRelation::Ballesteros2012 => bv_to_teff_ballesteros(c),
+            TeffRelation::Casagrande2010 => bv_to_teff_casagrande(c),
+            TeffRelation::PecautMamajek2013 => teff_pecaut_mamajek(index, c),
+        }
+    }
+}
+
+/// B-V colour index of the Sun.
+pub const SOLAR_BV: f64 = 0.656;
+
+/**
+ * Relations used by a catalog to compute T_eff from each colour index,
+ * and the B-V given to the stars without colour index.
+ **/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TeffCalibration {
+    pub bp_rp: TeffRelation,
+    pub b_v: TeffRelation,
+    pub no_color_bv: f64,
+}
+
+impl Default for TeffCalibration {
+    fn default() -> Self {
+        TeffCalibration {
+            bp_rp: TeffRelation::Jordi2010,
+            b_v: TeffRelation::Ballesteros2012,
+            no_color_bv: SOLAR_BV,
+        }
+    }
+}
+
+impl TeffCalibration {
+    /// Parses the relations given as 'bprp=mucciarelli2020,bv=casagrande2010'.
+    /// Colour indices which are not given use the default relations, Jordi et
+    /// al. (2010) for BP-RP and Ballesteros (2012) for B-V. The B-V of the stars
+    /// without colour index is given as 'nocolor=0.8', and defaults to the solar one.
+    pub fn from_str(input: &str) -> Result<TeffCalibration, String> {
+        let mut cal = TeffCalibration::default();
+        for token in input.split(',').map(|t| t.trim()).filter(|t| !t.is_empty()) {
+            let (key, name) = token
+                .split_once('=')
+                .ok_or(format!("expected index=relation, got '{}'", token))?;
+            if key.trim().eq_ignore_ascii_case("nocolor") {
+                cal.no_color_bv = name
+                    .trim()
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|bv| bv.is_finite())
+                    .ok_or(format!("invalid B-V '{}' of nocolor", name.trim()))?;
+                continue;
+            }
+            let relation = TeffRelation::from_str(name.trim()).ok_or(format!(
+                "unknown T_eff relation '{}', must be one of {:?}",
+                name.trim(),
+                TeffRelation::NAMES
+            ))?;
+            let (index, slot) = match key.trim().to_lowercase().as_str() {
+                "bprp" => (ColorIndex::BpRp, &mut cal.bp_rp),
+                "bv" => (ColorIndex::BV, &mut cal.b_v),
+                k => return Err(format!("unknown colour index '{}'", k)),
+            };
+            if !relation.supports(index) {
+                return Err(format!("relation '{}' does not take {}", name.trim(), key));
+            }
+            *slot = relation;
+        }
+        Ok(cal)
+    }
+
+    /// Gets T_eff from the value of the given colour index.
+    pub fn teff(&self, index: ColorIndex, c: f64) -> f64 {
+        match index {
+            ColorIndex::BpRp => self.bp_rp.teff(index, c),
+            ColorIndex::BV => self.b_v.teff(index, c),
+        }
+    }
+
+    /// Gets T_eff of the stars without colour index, from the
+    /// no-colour B-V with the B-V relation.
+    pub fn teff_no_color(&self) -> f64 {
+        self.teff(ColorIndex::BV, self.no_color_bv)
+    }
+}
+
 // Converts effective temperature in Kelvin (1000-40000) to RGB.
 //
 // See https://www.tannerhelland.com/4435/convert-temperature-rgb-algorithm-code/ -- T_eff to RGB.
@@ -69,6 +269,169 @@ pub fn teff_to_rgb(teff: f64) -> (f32, f32, f32) {
     ((r / 255.0) as f32, (g / 255.0) as f32, (b / 255.0) as f32)
 }
 
+// CIE 1931 2-degree colour-matching functions x, y and z, from 380 to 780 nm
+// every 10 nm.
+const CIE_LAMBDA_MIN: f64 = 380.0;
+const CIE_LAMBDA_STEP: f64 = 10.0;
+const CIE_XYZ: [[f64; 3]; 41] = [
+    [0.0014, 0.0000, 0.0065],
+    [0.0042, 0.0001, 0.0201],
+    [0.0143, 0.0004, 0.0679],
+    [0.0435, 0.0012, 0.2074],
+    [0.1344, 0.0040, 0.6456],
+    [0.2839, 0.0116, 1.3856],
+    [0.3483, 0.0230, 1.7471],
+    [0.3362, 0.0380, 1.7721],
+    [0.2908, 0.0600, 1.6692],
+    [0.1954, 0.0910, 1.2876],
+    [0.0956, 0.1390, 0.8130],
+    [0.0320, 0.2080, 0.4652],
+    [0.0049, 0.3230, 0.2720],
+    [0.0093, 0.5030, 0.1582],
+    [0.0633, 0.7100, 0.0782],
+    [0.1655, 0.8620, 0.0422],
+    [0.2904, 0.9540, 0.0203],
+    [0.4334, 0.9950, 0.0087],
+    [0.5945, 0.9950, 0.0039],
+    [0.7621, 0.9520, 0.0021],
+    [0.9163, 0.8700, 0.0017],
+    [1.0263, 0.7570, 0.0011],
+    [1.0622, 0.6310, 0.0008],
+    [1.0026, 0.5030, 0.0003],
+    [0.8544, 0.3810, 0.0002],
+    [0.6424, 0.2650, 0.0000],
+    [0.4479, 0.1750, 0.0000],
+    [0.2835, 0.1070, 0.0000],
+    [0.1649, 0.0610, 0.0000],
+    [0.0874, 0.0320, 0.0000],
+    [0.0468, 0.0170, 0.0000],
+    [0.0227, 0.0082, 0.0000],
+    [0.0114, 0.0041, 0.0000],
+    [0.0058, 0.0021, 0.0000],
+    [0.0029, 0.0010, 0.0000],
+    [0.0014, 0.0005, 0.0000],
+    [0.0007, 0.0002, 0.0000],
+    [0.0003, 0.0001, 0.0000],
+    [0.0002, 0.0001, 0.0000],
+    [0.0001, 0.0000, 0.0000],
+    [0.0000, 0.0000, 0.0000],
+];
+
+// Second radiation constant hc/k, in m K
+const C2: f64 = 1.438776877e-2;
+
+// XYZ to linear sRGB (D65)
+const XYZ_TO_SRGB: [[f64; 3]; 3] = [
+    [3.2404542, -1.5371385, -0.4985314],
+    [-0.9692660, 1.8760108, 0.0415560],
+    [0.0556434, -0.2040259, 1.0572252],
+];
+
+/// Gets the CIE XYZ tristimulus values of a blackbody with the given
+/// temperature in Kelvin, normalized to Y = 1.
+pub fn planck_to_xyz(teff: f64) -> (f64, f64, f64) {
+    let (mut x, mut y, mut z) = (0.0, 0.0, 0.0);
+    for (i, cmf) in CIE_XYZ.iter().enumerate() {
+        let lambda = (CIE_LAMBDA_MIN + i as f64 * CIE_LAMBDA_STEP) * 1e-9;
+        let radiance = 1.0 / (lambda.powi(5) * (C2 / (lambda * teff)).exp_m1());
+        x += cmf[0] * radiance;
+        y += cmf[1] * radiance;
+        z += cmf[2] * radiance;
+    }
+    (x / y, 1.0, z / y)
+}
+
+fn xyz_to_linear_srgb(xyz: (f64, f64, f64)) -> [f64; 3] {
+    let m = &XYZ_TO_SRGB;
+    [
+        m[0][0] * xyz.0 + m[0][1] * xyz.1 + m[0][2] * xyz.2,
+        m[1][0] * xyz.0 + m[1][1] * xyz.1 + m[1][2] * xyz.2,
+        m[2][0] * xyz.0 + m[2][1] * xyz.1 + m[2][2] * xyz.2,
+    ]
+}
+
+fn srgb_gamma(c: f64) -> f64 {
+    if c <= 0.0031308 {
+        12.92 * c
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/**
+ * Model to convert effective temperatures to colours.
+ **/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorModel {
+    // Curve fit of Tanner Helland, see teff_to_rgb
+    Helland,
+    // Planck spectrum integrated against the CIE 1931 colour-matching
+    // functions, in linear or gamma-encoded sRGB. The white point scales the
+    // channels so that its colour is white (1, 1, 1 for D65).
+    Planck { linear: bool, white: [f64; 3] },
+}
+
+impl ColorModel {
+    /// Creates the model with the given name, 'helland' or 'planck', colour
+    /// space, 'srgb' or 'linear', and white point, 'd65' or the temperature in
+    /// Kelvin of the blackbody that is rendered white. The colour space and the
+    /// white point are only used by the Planck model.
+    pub fn from_str(model: &str, space: &str, white_point: &str) -> Result<ColorModel, String> {
+        match model.to_lowercase().as_str() {
+            "helland" => Ok(ColorModel::Helland),
+            "planck" => {
+                let linear = match space.to_lowercase().as_str() {
+                    "srgb" => false,
+                    "linear" => true,
+                    s => return Err(format!("unknown colour space '{}'", s)),
+                };
+                let white = match white_point.to_lowercase().as_str() {
+                    "d65" => [1.0, 1.0, 1.0],
+                    t => {
+                        let teff = t
+                            .parse::<f64>()
+                            .ok()
+                            .filter(|t| *t >= 1000.0 && *t <= 100000.0)
+                            .ok_or(format!("invalid white point '{}'", t))?;
+                        xyz_to_linear_srgb(planck_to_xyz(teff))
+                    }
+                };
+                Ok(ColorModel::Planck { linear, white })
+            }
+            m => Err(format!("unknown colour model '{}'", m)),
+        }
+    }
+
+    /// Converts the effective temperature in Kelvin to RGB, with components
+    /// in [0, 1].
+    pub fn teff_to_rgb(&self, teff: f64) -> (f32, f32, f32) {
+        match self {
+            ColorModel::Helland => teff_to_rgb(teff),
+            ColorModel::Planck { linear, white } => {
+                if !teff.is_finite() || teff <= 0.0 {
+                    return (1.0, 1.0, 1.0);
+                }
+                let mut rgb = xyz_to_linear_srgb(planck_to_xyz(teff));
+                for (c, w) in rgb.iter_mut().zip(white) {
+                    *c /= w;
+                }
+                // Colours out of the gamut are desaturated by adding white
+                let min = rgb.iter().cloned().fold(f64::INFINITY, f64::min);
+                if min < 0.0 {
+                    rgb.iter_mut().for_each(|c| *c -= min);
+                }
+                // Only the chromaticity matters, so the brightest channel is 1
+                let max = rgb.iter().cloned().fold(0.0, f64::max);
+                rgb.iter_mut().for_each(|c| *c /= max);
+                if !linear {
+                    rgb.iter_mut().for_each(|c| *c = srgb_gamma(*c));
+                }
+                (rgb[0] as f32, rgb[1] as f32, rgb[2] as f32)
+            }
+        }
+    }
+}
+
 /**
  * Packs the color components into an i32 with the format ABGR8888
  **/
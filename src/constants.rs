@@ -8,6 +8,12 @@ pub const S_TO_Y: f64 = 1.0 / Y_TO_S;
 pub const PC_TO_KM: f64 = 3.08567758149137e13;
 pub const PC_TO_M: f64 = PC_TO_KM * 1000.0;
 
+// Reference epochs (Julian years) of the Hipparcos and Gaia (E)DR3 catalogs,
+// and the standard J2000 epoch
+pub const HIP_EPOCH: f64 = 1991.25;
+pub const GAIA_EPOCH: f64 = 2016.0;
+pub const J2000_EPOCH: f64 = 2000.0;
+
 //
 //INTERNAL UNITS
 //
@@ -1,5 +1,4 @@
 use crate::constants;
-use crate::load;
 
 use std::collections::HashMap;
 use std::fmt;
@@ -9,8 +8,11 @@ use std::ops::Sub;
 /**
  * Represents a star. The cartesian
  * positions use double-precision floating point
- * numbers. The rest use single-precision.
+ * numbers. The rest use single-precision. Particles
+ * are only used while loading and merging; the catalog
+ * itself is kept in a StarStore.
  **/
+#[derive(Clone)]
 pub struct Particle {
     pub x: f64,
     pub y: f64,
@@ -28,49 +30,287 @@ pub struct Particle {
     pub size: f32,
     pub hip: i32,
     pub id: i64,
+    // Parallax error, NaN if not available
+    pub plx_err: f32,
+    // RUWE, NaN if not available
+    pub ruwe: f32,
     pub names: Vec<String>,
-    pub extra: HashMap<load::ColId, f32>,
 }
 
-impl Particle {
-    pub fn get_extra(&self, col_id: load::ColId) -> &f32 {
-        match self.extra.get(&col_id) {
-            Some(val) => &val,
-            None => &f32::NAN,
+/// Approximate size of a star in a StarStore, in bytes.
+pub const STAR_SIZE: usize = 3 * 8 + 13 * 4 + 4 + 8 + 4;
+
+/**
+ * A list of stars that can be accessed by index,
+ * either in memory or on disk.
+ **/
+pub trait StarSource {
+    fn len(&self) -> usize;
+    fn get(&self, i: usize) -> Particle;
+}
+
+/**
+ * Holds a list of stars as a structure of arrays, with one
+ * contiguous vector per attribute. The names of each star are
+ * joined with '|' and interned in a names table, so that stars
+ * without names only cost an index.
+ **/
+pub struct StarStore {
+    pub x: Vec<f64>,
+    pub y: Vec<f64>,
+    pub z: Vec<f64>,
+    pub pmx: Vec<f32>,
+    pub pmy: Vec<f32>,
+    pub pmz: Vec<f32>,
+    pub mualpha: Vec<f32>,
+    pub mudelta: Vec<f32>,
+    pub radvel: Vec<f32>,
+    pub appmag: Vec<f32>,
+    pub absmag: Vec<f32>,
+    pub col: Vec<f32>,
+    pub teff: Vec<f32>,
+    pub size: Vec<f32>,
+    pub hip: Vec<i32>,
+    pub id: Vec<i64>,
+    pub plx_err: Vec<f32>,
+    pub ruwe: Vec<f32>,
+    // Index of the names of each star in names_table
+    name_idx: Vec<u32>,
+    // Interned names, the first entry is the empty string
+    names_table: Vec<String>,
+    names_lookup: HashMap<String, u32>,
+}
+
+impl StarStore {
+    pub fn new() -> Self {
+        StarStore {
+            x: Vec::new(),
+            y: Vec::new(),
+            z: Vec::new(),
+            pmx: Vec::new(),
+            pmy: Vec::new(),
+            pmz: Vec::new(),
+            mualpha: Vec::new(),
+            mudelta: Vec::new(),
+            radvel: Vec::new(),
+            appmag: Vec::new(),
+            absmag: Vec::new(),
+            col: Vec::new(),
+            teff: Vec::new(),
+            size: Vec::new(),
+            hip: Vec::new(),
+            id: Vec::new(),
+            plx_err: Vec::new(),
+            ruwe: Vec::new(),
+            name_idx: Vec::new(),
+            names_table: vec![String::new()],
+            names_lookup: HashMap::new(),
         }
     }
 
-    pub fn copy(&self) -> Self {
-        // Copy names
-        let mut new_names = Vec::new();
-        for name in &self.names {
-            new_names.push(String::from(name));
+    pub fn len(&self) -> usize {
+        self.id.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.id.is_empty()
+    }
+
+    /// Adds a star at the end of the store.
+    pub fn push(&mut self, p: Particle) {
+        self.x.push(p.x);
+        self.y.push(p.y);
+        self.z.push(p.z);
+        self.pmx.push(p.pmx);
+        self.pmy.push(p.pmy);
+        self.pmz.push(p.pmz);
+        self.mualpha.push(p.mualpha);
+        self.mudelta.push(p.mudelta);
+        self.radvel.push(p.radvel);
+        self.appmag.push(p.appmag);
+        self.absmag.push(p.absmag);
+        self.col.push(p.col);
+        self.teff.push(p.teff);
+        self.size.push(p.size);
+        self.hip.push(p.hip);
+        self.id.push(p.id);
+        self.plx_err.push(p.plx_err);
+        self.ruwe.push(p.ruwe);
+        let idx = self.intern(p.names.join("|"));
+        self.name_idx.push(idx);
+    }
+
+    /// Gets a copy of the star at the given index.
+    pub fn get(&self, i: usize) -> Particle {
+        let names = self.names(i);
+        Particle {
+            x: self.x[i],
+            y: self.y[i],
+            z: self.z[i],
+            pmx: self.pmx[i],
+            pmy: self.pmy[i],
+            pmz: self.pmz[i],
+            mualpha: self.mualpha[i],
+            mudelta: self.mudelta[i],
+            radvel: self.radvel[i],
+            appmag: self.appmag[i],
+            absmag: self.absmag[i],
+            col: self.col[i],
+            teff: self.teff[i],
+            size: self.size[i],
+            hip: self.hip[i],
+            id: self.id[i],
+            plx_err: self.plx_err[i],
+            ruwe: self.ruwe[i],
+            names: if names.is_empty() {
+                Vec::new()
+            } else {
+                names.split('|').map(String::from).collect()
+            },
         }
-        // Copy extra
-        let mut new_extra = HashMap::new();
-        for key in self.extra.keys() {
-            new_extra.insert(*key, *self.extra.get(key).unwrap());
+    }
+
+    /// Sets the names of the star at the given index.
+    pub fn set_names(&mut self, i: usize, names: &[String]) {
+        self.name_idx[i] = self.intern(names.join("|"));
+    }
+
+    /// Gets the names of the star at the given index, joined with '|'.
+    pub fn names(&self, i: usize) -> &str {
+        &self.names_table[self.name_idx[i] as usize]
+    }
+
+    /// Gets the minimum and maximum cartesian coordinates of the stars.
+    pub fn bounds(&self) -> (Vec3, Vec3) {
+        let mut min = Vec3::with(1.0e50);
+        let mut max = Vec3::with(-1.0e50);
+        for x in self.x.iter() {
+            if *x < min.x {
+                min.x = *x;
+            }
+            if *x > max.x {
+                max.x = *x;
+            }
         }
-        Particle {
-            x: self.x,
-            y: self.y,
-            z: self.z,
-            pmx: self.pmx,
-            pmy: self.pmy,
-            pmz: self.pmz,
-            mualpha: self.mualpha,
-            mudelta: self.mudelta,
-            radvel: self.radvel,
-            appmag: self.appmag,
-            absmag: self.absmag,
-            col: self.col,
-            teff: self.teff,
-            size: self.size,
-            hip: self.hip,
-            id: self.id,
-            names: new_names,
-            extra: new_extra,
+        for y in self.y.iter() {
+            if *y < min.y {
+                min.y = *y;
+            }
+            if *y > max.y {
+                max.y = *y;
+            }
+        }
+        for z in self.z.iter() {
+            if *z < min.z {
+                min.z = *z;
+            }
+            if *z > max.z {
+                max.z = *z;
+            }
+        }
+        (min, max)
+    }
+
+    /// Moves all the stars of other to the end of this store.
+    pub fn append(&mut self, mut other: StarStore) {
+        self.x.append(&mut other.x);
+        self.y.append(&mut other.y);
+        self.z.append(&mut other.z);
+        self.pmx.append(&mut other.pmx);
+        self.pmy.append(&mut other.pmy);
+        self.pmz.append(&mut other.pmz);
+        self.mualpha.append(&mut other.mualpha);
+        self.mudelta.append(&mut other.mudelta);
+        self.radvel.append(&mut other.radvel);
+        self.appmag.append(&mut other.appmag);
+        self.absmag.append(&mut other.absmag);
+        self.col.append(&mut other.col);
+        self.teff.append(&mut other.teff);
+        self.size.append(&mut other.size);
+        self.hip.append(&mut other.hip);
+        self.id.append(&mut other.id);
+        self.plx_err.append(&mut other.plx_err);
+        self.ruwe.append(&mut other.ruwe);
+        // Names must be interned again in this table
+        let mut map = Vec::with_capacity(other.names_table.len());
+        for name in other.names_table {
+            map.push(self.intern(name));
         }
+        self.name_idx
+            .extend(other.name_idx.iter().map(|idx| map[*idx as usize]));
+    }
+
+    /// Keeps only the stars for which keep is true.
+    pub fn retain_mask(&mut self, keep: &[bool]) {
+        let order: Vec<usize> = (0..self.len()).filter(|i| keep[*i]).collect();
+        self.reorder(&order);
+    }
+
+    /// Sorts the stars by absolute magnitude. The sort is stable, and
+    /// non-comparable values (NaN) are considered equal to anything.
+    pub fn sort_by_absmag(&mut self) {
+        let absmag = &self.absmag;
+        let mut order: Vec<usize> = (0..self.len()).collect();
+        order.sort_by(|a, b| {
+            absmag[*a]
+                .partial_cmp(&absmag[*b])
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        self.reorder(&order);
+    }
+
+    /// Rebuilds every column with the stars at the given indices, in order.
+    fn reorder(&mut self, order: &[usize]) {
+        fn gather<T: Copy>(v: &mut Vec<T>, order: &[usize]) {
+            let gathered: Vec<T> = order.iter().map(|i| v[*i]).collect();
+            *v = gathered;
+        }
+        gather(&mut self.x, order);
+        gather(&mut self.y, order);
+        gather(&mut self.z, order);
+        gather(&mut self.pmx, order);
+        gather(&mut self.pmy, order);
+        gather(&mut self.pmz, order);
+        gather(&mut self.mualpha, order);
+        gather(&mut self.mudelta, order);
+        gather(&mut self.radvel, order);
+        gather(&mut self.appmag, order);
+        gather(&mut self.absmag, order);
+        gather(&mut self.col, order);
+        gather(&mut self.teff, order);
+        gather(&mut self.size, order);
+        gather(&mut self.hip, order);
+        gather(&mut self.id, order);
+        gather(&mut self.plx_err, order);
+        gather(&mut self.ruwe, order);
+        gather(&mut self.name_idx, order);
+    }
+
+    /// Gets the index of the given names in the names table,
+    /// adding them if needed.
+    fn intern(&mut self, names: String) -> u32 {
+        if names.is_empty() {
+            return 0;
+        }
+        match self.names_lookup.get(&names) {
+            Some(idx) => *idx,
+            None => {
+                let idx = self.names_table.len() as u32;
+                self.names_table.push(names.clone());
+                self.names_lookup.insert(names, idx);
+                idx
+            }
+        }
+    }
+}
+
+impl StarSource for StarStore {
+    fn len(&self) -> usize {
+        StarStore::len(self)
+    }
+
+    fn get(&self, i: usize) -> Particle {
+        StarStore::get(self, i)
     }
 }
 
@@ -264,11 +504,28 @@ pub struct Config {
     pub input: String,
     pub output: String,
     pub hip: String,
+    // format preset of the Hipparcos catalog (csv, esa1997 or vanleeuwen2007).
+    pub hip_format: String,
+    // column list of the Hipparcos catalog, overriding the one of the format.
+    pub hip_columns: String,
+    // parallax error threshold for Hipparcos stars, where plx_err/plx < hip_plx_err.
+    pub hip_plx_err: f64,
+    // maximum parallax error in mas of Hipparcos stars.
+    pub hip_plx_err_cap: f64,
+    // relations to compute T_eff from the colour indices of Hipparcos stars.
+    pub hip_teff_cal: String,
     pub max_part: usize,
     // limit ruwe value.
     pub ruwe_cap: f32,
     // limit distance in parsecs.
     pub distpc_cap: f64,
+    // apparent and absolute magnitude limits (NaN if not set)
+    pub mag_bright: f64,
+    pub mag_faint: f64,
+    pub absmag_bright: f64,
+    pub absmag_faint: f64,
+    // distance in pc of the volume-complete mode (NaN if not set)
+    pub volume_dist: f64,
     // ignore parallax cuts and use all stars with photometric distances.
     pub photdist: bool,
     // parallax error threshold for faint stars (gmag >= 13.1), where plx_err/plx < plx_err_faint.
@@ -277,7 +534,28 @@ pub struct Config {
     pub plx_err_bright: f64,
     pub plx_zeropoint: f64,
     pub mag_corrections: u8,
+    // extinction model (analytical or a dust map file), and the coefficients of dust maps
+    pub extinction: String,
+    pub ext_coeffs: String,
+    // law to compute the reddening from A_G: constant or riello2021
+    pub ext_law: String,
+    // apply the G magnitude corrections of Gaia DR3
+    pub g_corrections: bool,
+    // BP/RP flux excess factor cut in sigmas, NaN for no cut
+    pub excess_nsigma: f64,
+    // colour model (helland or planck), and colour space and white point of the planck model
+    pub color_model: String,
+    pub color_space: String,
+    pub white_point: String,
+    // relations to compute T_eff from the colour indices of Gaia stars, like bprp=jordi2010
+    pub teff_cal: String,
     pub allow_negative_plx: bool,
+    // estimator to compute distances from parallaxes (plx, edsd or lk).
+    pub dist_estimator: String,
+    // length scale in parsecs of the edsd prior.
+    pub dist_length_scale: f64,
+    // epoch (Julian year) to propagate the stars to, NaN to keep their reference epochs.
+    pub target_epoch: f64,
     // Put the centre of the octree at the reference system origin (0 0 0).
     pub centre_origin: bool,
     // post-process the octree to try to flatten it.
@@ -285,8 +563,33 @@ pub struct Config {
     pub child_count: usize,
     pub parent_count: usize,
     pub additional: String,
+    // Expression to filter the stars of the main catalog with
+    pub filter: String,
+    // Sky regions to restrict the catalogs to (kind:values)
+    pub regions: Vec<String>,
+    // Whether to write the rejected stars of the main catalog, and the fraction to write
+    pub dump_rejected: bool,
+    pub rejected_fraction: f64,
     pub xmatch: String,
+    // specs of the secondary catalogs to merge after Hipparcos, in precedence order.
+    pub secondary: Vec<String>,
+    // search radius in arcseconds of the positional crossmatch, used when there is no xmatch file.
+    pub xmatch_radius: f64,
+    // maximum magnitude difference of the positional crossmatch, infinite for no check.
+    pub xmatch_max_dmag: f32,
+    // field=rule pairs of the policy to merge Gaia and Hipparcos stars.
+    pub merge_policy: String,
+    // kind=file pairs of the star name lookup files.
+    pub names: String,
+    // name kinds in priority order.
+    pub name_priority: String,
     pub columns: String,
+    // build the column indices of each csv file from its header row, instead of using columns.
+    pub header_columns: bool,
+    // number of threads used to load the input files.
+    pub threads: usize,
+    // memory budget in MB for out-of-core generation, 0 to keep everything in memory.
+    pub mem_budget: usize,
     pub file_num_cap: i32,
     pub star_num_cap: i32,
     pub dry_run: bool,
@@ -299,22 +602,57 @@ impl fmt::Debug for Config {
             .field("input", &self.input)
             .field("output", &self.output)
             .field("hip", &self.hip)
+            .field("hip_format", &self.hip_format)
+            .field("hip_columns", &self.hip_columns)
+            .field("hip_plx_err", &self.hip_plx_err)
+            .field("hip_plx_err_cap", &self.hip_plx_err_cap)
+            .field("hip_teff_cal", &self.hip_teff_cal)
             .field("max_part", &self.max_part)
             .field("ruwe_cap", &self.ruwe_cap)
             .field("distpc_cap", &self.distpc_cap)
+            .field("mag_bright", &self.mag_bright)
+            .field("mag_faint", &self.mag_faint)
+            .field("absmag_bright", &self.absmag_bright)
+            .field("absmag_faint", &self.absmag_faint)
+            .field("volume_dist", &self.volume_dist)
             .field("plx_err_faint", &self.plx_err_faint)
             .field("plx_err_bright", &self.plx_err_bright)
             .field("plx_zeropoint", &self.plx_zeropoint)
             .field("mag_corrections", &self.mag_corrections)
+            .field("extinction", &self.extinction)
+            .field("ext_coeffs", &self.ext_coeffs)
+            .field("ext_law", &self.ext_law)
+            .field("g_corrections", &self.g_corrections)
+            .field("excess_nsigma", &self.excess_nsigma)
+            .field("color_model", &self.color_model)
+            .field("color_space", &self.color_space)
+            .field("white_point", &self.white_point)
+            .field("teff_cal", &self.teff_cal)
             .field("allow_negative_plx", &self.allow_negative_plx)
+            .field("dist_estimator", &self.dist_estimator)
+            .field("dist_length_scale", &self.dist_length_scale)
+            .field("target_epoch", &self.target_epoch)
             .field("photdist", &self.photdist)
             .field("centre_origin", &self.centre_origin)
             .field("postprocess", &self.postprocess)
             .field("child_count", &self.child_count)
             .field("parent_count", &self.parent_count)
             .field("additional", &self.additional)
+            .field("filter", &self.filter)
+            .field("regions", &self.regions)
+            .field("dump_rejected", &self.dump_rejected)
+            .field("rejected_fraction", &self.rejected_fraction)
             .field("xmatch", &self.xmatch)
+            .field("secondary", &self.secondary)
+            .field("xmatch_radius", &self.xmatch_radius)
+            .field("xmatch_max_dmag", &self.xmatch_max_dmag)
+            .field("merge_policy", &self.merge_policy)
+            .field("names", &self.names)
+            .field("name_priority", &self.name_priority)
             .field("columns", &self.columns)
+            .field("header_columns", &self.header_columns)
+            .field("threads", &self.threads)
+            .field("mem_budget", &self.mem_budget)
             .field("file_num_cap", &self.file_num_cap)
             .field("star_num_cap", &self.star_num_cap)
             .field("dry_run", &self.dry_run)
@@ -0,0 +1,126 @@
+use std::fmt;
+
+/**
+ * Estimators to compute distances from parallaxes.
+ * Parallaxes and their errors are in mas, distances in pc.
+ **/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DistEstimator {
+    // Plain inverse parallax, 1/plx
+    InvPlx,
+    // Mode of the posterior with an exponentially decreasing space density
+    // prior (Bailer-Jones 2015), with the given length scale in pc
+    Edsd { length_scale: f64 },
+    // Mode of the posterior with a uniform space density prior, which
+    // corrects for the Lutz-Kelker bias
+    LutzKelker,
+}
+
+impl DistEstimator {
+    /// Gets the estimator with the given name (plx, edsd or lk). The
+    /// length scale is only used by the EDSD estimator.
+    pub fn from_str(name: &str, length_scale: f64) -> Option<DistEstimator> {
+        match name.to_lowercase().as_str() {
+            "plx" | "invplx" => Some(DistEstimator::InvPlx),
+            "edsd" => Some(DistEstimator::Edsd { length_scale }),
+            "lk" | "lutzkelker" => Some(DistEstimator::LutzKelker),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            DistEstimator::InvPlx => "plx",
+            DistEstimator::Edsd { .. } => "edsd",
+            DistEstimator::LutzKelker => "lk",
+        }
+    }
+
+    /// Whether the estimator gives meaningful distances for non-positive parallaxes.
+    pub fn accepts_negative_plx(&self) -> bool {
+        matches!(self, DistEstimator::Edsd { .. })
+    }
+
+    /// Estimates the distance in pc from the parallax and its error, in mas.
+    /// Returns None if the estimator can't produce a distance.
+    pub fn distance(&self, plx: f64, plx_err: f64) -> Option<f64> {
+        match *self {
+            DistEstimator::InvPlx => Some(1000.0 / plx),
+            DistEstimator::Edsd { length_scale } => edsd_mode(plx, plx_err, length_scale),
+            DistEstimator::LutzKelker => lutz_kelker_mode(plx, plx_err),
+        }
+    }
+}
+
+impl fmt::Display for DistEstimator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DistEstimator::Edsd { length_scale } => {
+                write!(f, "{} (length scale {} pc)", self.name(), length_scale)
+            }
+            _ => write!(f, "{}", self.name()),
+        }
+    }
+}
+
+/// Mode of the EDSD posterior, which is the root of
+/// r^3/L - 2r^2 + (plx/s^2)r - 1/s^2 = 0, with r and L in kpc.
+/// If there are three real roots, the mode is the smallest one
+/// for positive parallaxes, and the largest one otherwise.
+fn edsd_mode(plx: f64, plx_err: f64, length_scale: f64) -> Option<f64> {
+    if !plx.is_finite() || !plx_err.is_finite() || plx_err <= 0.0 || length_scale <= 0.0 {
+        return None;
+    }
+    let l = length_scale / 1000.0;
+    let s2 = plx_err * plx_err;
+    let roots = cubic_roots(1.0 / l, -2.0, plx / s2, -1.0 / s2);
+    let positive = roots.iter().filter(|r| **r > 0.0);
+    let mode = if plx >= 0.0 {
+        positive.copied().reduce(f64::min)
+    } else {
+        positive.copied().reduce(f64::max)
+    };
+    mode.map(|r| r * 1000.0)
+}
+
+/// Mode of the posterior with a uniform space density prior, which is
+/// the smallest root of 2s^2r^2 - plx*r + 1 = 0, with r in kpc. It only
+/// exists for relative parallax errors below 1/sqrt(8).
+fn lutz_kelker_mode(plx: f64, plx_err: f64) -> Option<f64> {
+    if !plx.is_finite() || plx <= 0.0 || !plx_err.is_finite() || plx_err < 0.0 {
+        return None;
+    }
+    if plx_err == 0.0 {
+        return Some(1000.0 / plx);
+    }
+    let s2 = plx_err * plx_err;
+    let disc = plx * plx - 8.0 * s2;
+    if disc < 0.0 {
+        return None;
+    }
+    // Equivalent to (plx - sqrt(disc)) / (4s^2), without the cancellation
+    Some(1000.0 * 2.0 / (plx + disc.sqrt()))
+}
+
+/// Real roots of a*x^3 + b*x^2 + c*x + d = 0, with a != 0.
+fn cubic_roots(a: f64, b: f64, c: f64, d: f64) -> Vec<f64> {
+    // Depressed cubic t^3 + pt + q = 0, with x = t - b/3a
+    let (b, c, d) = (b / a, c / a, d / a);
+    let p = c - b * b / 3.0;
+    let q = 2.0 * b * b * b / 27.0 - b * c / 3.0 + d;
+    let shift = -b / 3.0;
+    let disc = q * q / 4.0 + p * p * p / 27.0;
+    if disc > 0.0 {
+        let sq = disc.sqrt();
+        vec![(-q / 2.0 + sq).cbrt() + (-q / 2.0 - sq).cbrt() + shift]
+    } else if p == 0.0 {
+        vec![shift]
+    } else {
+        // Three real roots, trigonometric method
+        let m = 2.0 * (-p / 3.0).sqrt();
+        let theta = (3.0 * q / (p * m)).clamp(-1.0, 1.0).acos() / 3.0;
+        (0..3)
+            .map(|k| m * (theta - 2.0 * std::f64::consts::PI * k as f64 / 3.0).cos() + shift)
+            .collect()
+    }
+}
@@ -0,0 +1,214 @@
+extern crate flate2;
+
+use flate2::read::GzDecoder;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use crate::table::{Table, TableColumn};
+
+/// Reads the given ECSV file, which may be gzipped. The column names and
+/// units come from the YAML header, and the header row must match them.
+/// Empty values and 'null' are treated as nulls.
+pub fn read(file: &str) -> Result<Table, String> {
+    let f = File::open(file).map_err(|e| format!("{}: {}", file, e))?;
+    let reader: Box<dyn BufRead> = if file.ends_with(".gz") {
+        Box::new(BufReader::new(GzDecoder::new(f)))
+    } else {
+        Box::new(BufReader::new(f))
+    };
+    parse(reader).map_err(|e| format!("{}: {}", file, e))
+}
+
+/// Parses the ECSV in the given reader.
+pub fn parse(reader: Box<dyn BufRead>) -> Result<Table, String> {
+    let mut lines = reader.lines();
+
+    // YAML header, in lines starting with '#'
+    let mut header: Vec<String> = Vec::new();
+    let mut names_line: Option<String> = None;
+    for line in lines.by_ref() {
+        let line = line.map_err(|e| e.to_string())?;
+        match line.strip_prefix('#') {
+            Some(yaml) => header.push(yaml.strip_prefix(' ').unwrap_or(yaml).to_string()),
+            None if line.trim().is_empty() => (),
+            None => {
+                names_line = Some(line);
+                break;
+            }
+        }
+    }
+    if !header
+        .first()
+        .map(|l| l.starts_with("%ECSV"))
+        .unwrap_or(false)
+    {
+        return Err("not an ECSV file, missing '%ECSV' header".to_string());
+    }
+    let (columns, delimiter) = parse_header(&header)?;
+
+    // Header row, which must be consistent with the YAML header
+    let names = split(&names_line.ok_or("no header row")?, delimiter);
+    let names: Vec<&str> = names.iter().map(|n| n.as_deref().unwrap_or("")).collect();
+    let expected: Vec<&str> = columns.iter().map(|c| c.name.as_str()).collect();
+    if names != expected {
+        return Err(format!(
+            "header row {:?} does not match the columns in the YAML header {:?}",
+            names, expected
+        ));
+    }
+
+    let mut rows = Vec::new();
+    for line in lines {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut row = split(&line, delimiter);
+        row.resize(columns.len(), None);
+        rows.push(row);
+    }
+    Ok(Table { columns, rows })
+}
+
+/// Parses the relevant parts of the YAML header, namely the
+/// column list (name and unit) and the delimiter.
+fn parse_header(header: &[String]) -> Result<(Vec<TableColumn>, char), String> {
+    let mut columns = Vec::new();
+    let mut delimiter = ' ';
+
+    // Group the lines of each entry in the datatype list. An entry starts
+    // with '- ' and may continue in indented lines.
+    let mut entries: Vec<String> = Vec::new();
+    let mut in_datatype = false;
+    for line in header.iter().skip(1) {
+        if line.trim() == "---" {
+            continue;
+        }
+        let top_level = !line.starts_with(' ') && !line.starts_with('-');
+        if top_level {
+            in_datatype = line.trim_end() == "datatype:";
+            if let Some(value) = line.strip_prefix("delimiter:") {
+                delimiter = unquote(value.trim()).chars().next().unwrap_or(' ');
+            }
+        } else if in_datatype {
+            match line.trim_start().strip_prefix("- ") {
+                Some(entry) if line.find('-') <= Some(2) => entries.push(entry.to_string()),
+                _ => {
+                    if let Some(entry) = entries.last_mut() {
+                        entry.push('\n');
+                        entry.push_str(line);
+                    }
+                }
+            }
+        }
+    }
+
+    for entry in entries {
+        let mut name = None;
+        let mut unit = String::new();
+        for (key, value) in parse_mapping(&entry) {
+            match key.as_str() {
+                "name" => name = Some(value),
+                "unit" => unit = value,
+                _ => (),
+            }
+        }
+        let name = name.ok_or(format!("column without name in header: {}", entry))?;
+        columns.push(TableColumn { name, unit });
+    }
+    if columns.is_empty() {
+        return Err("no columns in header".to_string());
+    }
+    Ok((columns, delimiter))
+}
+
+/// Parses a YAML mapping, either in flow style ({name: ra, unit: deg})
+/// or in block style (one 'key: value' per line). Only scalar values are
+/// supported, which is enough for the column descriptions.
+fn parse_mapping(entry: &str) -> Vec<(String, String)> {
+    let entry = entry.trim();
+    let items: Vec<String> = match entry.strip_prefix('{') {
+        Some(flow) => {
+            // Split by commas outside of quotes
+            let flow = flow.strip_suffix('}').unwrap_or(flow).replace('\n', " ");
+            let mut items = vec![String::new()];
+            let mut quote: Option<char> = None;
+            for c in flow.chars() {
+                match (c, quote) {
+                    (',', None) => items.push(String::new()),
+                    ('\'' | '"', None) => quote = Some(c),
+                    (c, Some(q)) if c == q => quote = None,
+                    _ => (),
+                }
+                if c != ',' || quote.is_some() {
+                    items.last_mut().unwrap().push(c);
+                }
+            }
+            items
+        }
+        None => entry.lines().map(|l| l.to_string()).collect(),
+    };
+    items
+        .iter()
+        .filter_map(|item| {
+            let (key, value) = item.split_once(':')?;
+            Some((key.trim().to_string(), unquote(value.trim()).to_string()))
+        })
+        .collect()
+}
+
+fn unquote(value: &str) -> &str {
+    for quote in ['\'', '"'] {
+        if value.len() >= 2 && value.starts_with(quote) && value.ends_with(quote) {
+            return &value[1..value.len() - 1];
+        }
+    }
+    value
+}
+
+/// Splits a line by the given delimiter, respecting double quotes. Empty
+/// tokens and 'null' are returned as None. With the space delimiter, runs
+/// of spaces count as one.
+fn split(line: &str, delimiter: char) -> Vec<Option<String>> {
+    let mut tokens = Vec::new();
+    let mut token = String::new();
+    let mut quoted = false;
+    let mut was_quoted = false;
+    let mut chars = line.trim_end_matches(['\r', '\n']).chars().peekable();
+    if delimiter == ' ' {
+        while chars.peek() == Some(&' ') {
+            chars.next();
+        }
+    }
+    let mut push = |token: &mut String, was_quoted: bool| {
+        if token.is_empty() && !was_quoted || token == "null" {
+            tokens.push(None);
+        } else {
+            tokens.push(Some(std::mem::take(token)));
+        }
+        token.clear();
+    };
+    while let Some(c) = chars.next() {
+        if c == '"' {
+            if quoted && chars.peek() == Some(&'"') {
+                token.push('"');
+                chars.next();
+            } else {
+                quoted = !quoted;
+                was_quoted = true;
+            }
+        } else if c == delimiter && !quoted {
+            push(&mut token, was_quoted);
+            was_quoted = false;
+            if delimiter == ' ' {
+                while chars.peek() == Some(&' ') {
+                    chars.next();
+                }
+            }
+        } else {
+            token.push(c);
+        }
+    }
+    push(&mut token, was_quoted);
+    tokens
+}
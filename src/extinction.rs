@@ -0,0 +1,324 @@
+//! Interstellar extinction models used with --magcorrections 2 for the stars
+//! without A_G or E(BP-RP) in the catalog: the analytical slab law, or a
+//! local 3D dust map sampled at the galactic position of each star.
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::BufRead;
+
+use flate2::read::GzDecoder;
+
+use crate::region;
+
+/**
+ * 3D dust map. Values are in the unit of the map (A_0, E(B-V)...), which
+ * are converted to A_G and E(BP-RP) with the extinction coefficients.
+ *
+ * Files are optionally gzipped text files, where lines starting with '#' are
+ * comments, whose first line is a header giving the kind of map:
+ *  - 'grid nx ny nz x0 y0 z0 step': Cartesian grid of extinction densities
+ *    (per pc), centred on the Sun in galactic coordinates, with the centre of
+ *    the first voxel at (x0, y0, z0) and the given voxel size, all in pc. The
+ *    values follow, x varying fastest, then y, then z. Densities are integrated
+ *    along the line of sight. Extinction outside the grid is zero.
+ *  - 'healpix nside nested|ring d1 d2 ... dn': HEALPix map in galactic
+ *    coordinates of the cumulative extinction up to the distances d1..dn (pc).
+ *    The n values of each pixel follow in one line, in pixel order. Values are
+ *    interpolated linearly in distance, from zero at the Sun, and kept
+ *    constant beyond dn.
+ **/
+#[derive(Debug)]
+pub enum DustMap {
+    Grid {
+        n: [usize; 3],
+        origin: [f64; 3],
+        step: f64,
+        values: Vec<f32>,
+    },
+    Healpix {
+        nside: u64,
+        nested: bool,
+        dists: Vec<f64>,
+        values: Vec<f32>,
+    },
+}
+
+impl DustMap {
+    pub fn load(file: &str) -> Result<DustMap, String> {
+        let f = File::open(file).map_err(|e| format!("{}: {}", file, e))?;
+        let reader: Box<dyn BufRead> = if file.ends_with(".gz") {
+            Box::new(io::BufReader::new(GzDecoder::new(f)))
+        } else {
+            Box::new(io::BufReader::new(f))
+        };
+        DustMap::parse(reader).map_err(|e| format!("{}: {}", file, e))
+    }
+
+    pub fn parse(reader: Box<dyn BufRead>) -> Result<DustMap, String> {
+        let mut header: Vec<String> = Vec::new();
+        let mut values = Vec::new();
+        for line in reader.lines() {
+            let line = line.map_err(|e| e.to_string())?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if header.is_empty() {
+                header = line.split_whitespace().map(|t| t.to_string()).collect();
+                continue;
+            }
+            for token in line.split_whitespace() {
+                values.push(
+                    token
+                        .parse::<f32>()
+                        .map_err(|_| format!("invalid value '{}'", token))?,
+                );
+            }
+        }
+        if header.is_empty() {
+            return Err("empty dust map".to_string());
+        }
+        let header: Vec<&str> = header.iter().map(|t| t.as_str()).collect();
+        let number = |t: &str| {
+            t.parse::<f64>()
+                .map_err(|_| format!("invalid value '{}' in header", t))
+        };
+
+        match header[0] {
+            "grid" if header.len() == 8 => {
+                let mut n = [0; 3];
+                for (i, n) in n.iter_mut().enumerate() {
+                    *n = header[i + 1]
+                        .parse::<usize>()
+                        .map_err(|_| format!("invalid grid size '{}'", header[i + 1]))?;
+                }
+                let origin = [number(header[4])?, number(header[5])?, number(header[6])?];
+                let step = number(header[7])?;
+                if step <= 0.0 {
+                    return Err(format!("invalid voxel size {}", step));
+                }
+                if values.len() != n[0] * n[1] * n[2] {
+                    return Err(format!(
+                        "expected {} values, got {}",
+                        n[0] * n[1] * n[2],
+                        values.len()
+                    ));
+                }
+                Ok(DustMap::Grid {
+                    n,
+                    origin,
+                    step,
+                    values,
+                })
+            }
+            "healpix" if header.len() >= 4 => {
+                let nside = header[1]
+                    .parse::<u64>()
+                    .map_err(|_| format!("invalid nside '{}'", header[1]))?;
+                let nested = match header[2] {
+                    "nested" | "nest" => true,
+                    "ring" => false,
+                    s => return Err(format!("unknown HEALPix scheme '{}'", s)),
+                };
+                if nside == 0 || (nested && !nside.is_power_of_two()) {
+                    return Err(format!("invalid nside {}", nside));
+                }
+                let dists = header[3..]
+                    .iter()
+                    .map(|t| number(t))
+                    .collect::<Result<Vec<f64>, String>>()?;
+                if dists.windows(2).any(|w| w[0] >= w[1]) || dists[0] <= 0.0 {
+                    return Err("distances must be positive and increasing".to_string());
+                }
+                let n = (12 * nside * nside) as usize * dists.len();
+                if values.len() != n {
+                    return Err(format!("expected {} values, got {}", n, values.len()));
+                }
+                Ok(DustMap::Healpix {
+                    nside,
+                    nested,
+                    dists,
+                    values,
+                })
+            }
+            kind => Err(format!("unknown dust map header '{}'", kind)),
+        }
+    }
+
+    /// Gets the extinction, in the unit of the map, up to the given galactic
+    /// longitude and latitude (radians) and distance (pc).
+    pub fn sample(&self, l: f64, b: f64, dist_pc: f64) -> f64 {
+        if dist_pc.is_nan() || dist_pc <= 0.0 {
+            return 0.0;
+        }
+        match self {
+            DustMap::Grid {
+                n,
+                origin,
+                step,
+                values,
+            } => {
+                // Unit vector towards the star, in galactic Cartesian coordinates
+                let dir = [b.cos() * l.cos(), b.cos() * l.sin(), b.sin()];
+                // Integrate up to the star or the border of the grid, whichever is
+                // closer, with steps of half a voxel, sampling the nearest voxel
+                let mut t_max = dist_pc;
+                for axis in 0..3 {
+                    let lo = origin[axis] - step / 2.0;
+                    let hi = lo + n[axis] as f64 * step;
+                    if dir[axis] > 0.0 {
+                        t_max = t_max.min(hi / dir[axis]);
+                    } else if dir[axis] < 0.0 {
+                        t_max = t_max.min(lo / dir[axis]);
+                    }
+                }
+                if t_max.is_nan() || t_max <= 0.0 {
+                    return 0.0;
+                }
+                let n_steps = (2.0 * t_max / step).ceil().max(1.0) as usize;
+                let ds = t_max / n_steps as f64;
+                let mut total = 0.0;
+                for s in 0..n_steps {
+                    let t = (s as f64 + 0.5) * ds;
+                    let mut index = 0;
+                    let mut stride = 1;
+                    let mut inside = true;
+                    for axis in 0..3 {
+                        let i = ((t * dir[axis] - origin[axis]) / step).round();
+                        if i < 0.0 || i >= n[axis] as f64 {
+                            inside = false;
+                            break;
+                        }
+                        index += i as usize * stride;
+                        stride *= n[axis];
+                    }
+                    if inside {
+                        total += values[index] as f64 * ds;
+                    }
+                }
+                total
+            }
+            DustMap::Healpix {
+                nside,
+                nested,
+                dists,
+                values,
+            } => {
+                let pixel = region::ang2pix(*nside, *nested, l, b) as usize;
+                let row = &values[pixel * dists.len()..(pixel + 1) * dists.len()];
+                match dists.iter().position(|d| *d >= dist_pc) {
+                    Some(0) => row[0] as f64 * dist_pc / dists[0],
+                    Some(i) => {
+                        let f = (dist_pc - dists[i - 1]) / (dists[i] - dists[i - 1]);
+                        row[i - 1] as f64 + f * (row[i] - row[i - 1]) as f64
+                    }
+                    None => row[dists.len() - 1] as f64,
+                }
+            }
+        }
+    }
+}
+
+/// Model to compute the extinction A_G and the reddening E(BP-RP) of a star.
+#[derive(Debug)]
+pub enum Extinction {
+    // Slab model: A_G = min(3.2, d * 5.9e-4) and E(BP-RP) = min(1.6, d * 2.9e-4),
+    // where d = min(dist_pc, 150 / |sin b|)
+    Analytical,
+    // Dust map, with the coefficients to convert its values to A_G and E(BP-RP)
+    Map {
+        file: String,
+        map: DustMap,
+        ag_coeff: f64,
+        ebr_coeff: f64,
+    },
+}
+
+impl Extinction {
+    /// Default coefficients, for maps of E(B-V): R_G = A_G/E(B-V) and
+    /// E(BP-RP)/E(B-V) of Casagrande & VandenBerg (2018).
+    pub const AG_COEFF: f64 = 2.740;
+    pub const EBR_COEFF: f64 = 1.339;
+
+    /// Creates the model with the given name: 'analytical', or the file of
+    /// a dust map. The coefficients are given as 'ag=2.740,ebr=1.339', and
+    /// default to those of E(B-V) maps.
+    pub fn from_str(model: &str, coeffs: &str) -> Result<Extinction, String> {
+        if model.is_empty() || model == "analytical" {
+            return Ok(Extinction::Analytical);
+        }
+        let (mut ag_coeff, mut ebr_coeff) = (Extinction::AG_COEFF, Extinction::EBR_COEFF);
+        for token in coeffs
+            .split(',')
+            .map(|t| t.trim())
+            .filter(|t| !t.is_empty())
+        {
+            let (key, value) = token
+                .split_once('=')
+                .ok_or(format!("expected key=value, got '{}'", token))?;
+            let value = value
+                .trim()
+                .parse::<f64>()
+                .map_err(|_| format!("invalid coefficient '{}'", token))?;
+            match key.trim() {
+                "ag" => ag_coeff = value,
+                "ebr" => ebr_coeff = value,
+                k => return Err(format!("unknown coefficient '{}'", k)),
+            }
+        }
+        Ok(Extinction::Map {
+            file: model.to_string(),
+            map: DustMap::load(model)?,
+            ag_coeff,
+            ebr_coeff,
+        })
+    }
+
+    /// Gets A_G and E(BP-RP) of the star at the given galactic longitude and
+    /// latitude (radians) and distance (pc).
+    pub fn extinction(&self, l: f64, b: f64, dist_pc: f64) -> (f64, f64) {
+        match self {
+            Extinction::Analytical => {
+                let magcorraux = f64::min(dist_pc, 150.0 / b.sin().abs());
+                (
+                    f64::min(3.2, magcorraux * 5.9e-4),
+                    f64::min(1.6, magcorraux * 2.9e-4),
+                )
+            }
+            Extinction::Map {
+                map,
+                ag_coeff,
+                ebr_coeff,
+                ..
+            } => {
+                let value = map.sample(l, b, dist_pc);
+                (value * ag_coeff, value * ebr_coeff)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Extinction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Extinction::Analytical => write!(f, "analytical"),
+            Extinction::Map {
+                file,
+                map,
+                ag_coeff,
+                ebr_coeff,
+            } => {
+                let kind = match map {
+                    DustMap::Grid { .. } => "grid",
+                    DustMap::Healpix { .. } => "HEALPix",
+                };
+                write!(
+                    f,
+                    "{} dust map {} (A_G = {} x value, E(BP-RP) = {} x value)",
+                    kind, file, ag_coeff, ebr_coeff
+                )
+            }
+        }
+    }
+}
@@ -0,0 +1,409 @@
+use std::fmt;
+
+use crate::load::ColId;
+
+/// A variable of a filter expression: a column of the catalog, or a
+/// column of the additional catalogs which is not a known column.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Var {
+    Column(ColId),
+    Additional(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    And,
+    Or,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Func {
+    Abs,
+    Sqrt,
+    Log10,
+    IsFinite,
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    Num(f64),
+    // Index in Filter::vars
+    Var(usize),
+    Neg(Box<Expr>),
+    Not(Box<Expr>),
+    Bin(Op, Box<Expr>, Box<Expr>),
+    Call(Func, Box<Expr>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Num(f64),
+    Ident(String),
+    Op(&'static str),
+}
+
+/**
+ * A row filter compiled from an expression over the columns, like
+ * 'ruwe < 1.4 && (plx/plx_err > 10 || gmag < 12)'. Expressions have the
+ * usual arithmetic (+ - * /), comparison (< <= > >= == !=) and logical
+ * (&& || !) operators, parentheses, numbers, column names and the
+ * functions abs, sqrt, log10 and isfinite. Comparisons with NaN values
+ * are false. The terms of the top-level && are the clauses of the filter,
+ * whose rejections are counted separately.
+ **/
+#[derive(Clone)]
+pub struct Filter {
+    // Variables used by the expression, in order of appearance
+    pub vars: Vec<Var>,
+    // Text and expression of each clause
+    clauses: Vec<(String, Expr)>,
+}
+
+impl Filter {
+    /// Compiles the given expression. Column names are resolved to
+    /// variables with the given function.
+    pub fn compile(input: &str, resolve: &dyn Fn(&str) -> Option<Var>) -> Result<Filter, String> {
+        let tokens = tokenize(input)?;
+        if tokens.is_empty() {
+            return Err("empty filter".to_string());
+        }
+        let mut parser = Parser {
+            input,
+            tokens,
+            pos: 0,
+            vars: Vec::new(),
+            resolve,
+        };
+        let clauses = parser.parse_clauses()?;
+        if parser.pos < parser.tokens.len() {
+            return Err(format!(
+                "unexpected '{}' at position {}",
+                parser.tokens[parser.pos].0,
+                parser.tokens[parser.pos].1 + 1
+            ));
+        }
+        Ok(Filter {
+            vars: parser.vars,
+            clauses,
+        })
+    }
+
+    /// Gets the text of the clauses.
+    pub fn clauses(&self) -> Vec<&str> {
+        self.clauses.iter().map(|(text, _)| text.as_str()).collect()
+    }
+
+    /// Evaluates the filter with the given values of the variables. Returns
+    /// None if the row passes, or the index of the first clause it fails.
+    pub fn eval(&self, values: &[f64]) -> Option<usize> {
+        self.clauses
+            .iter()
+            .position(|(_, expr)| !truthy(eval(expr, values)))
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Num(n) => write!(f, "{}", n),
+            Token::Ident(name) => write!(f, "{}", name),
+            Token::Op(op) => write!(f, "{}", op),
+        }
+    }
+}
+
+fn truthy(value: f64) -> bool {
+    value != 0.0 && !value.is_nan()
+}
+
+fn boolean(value: bool) -> f64 {
+    if value {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+fn eval(expr: &Expr, values: &[f64]) -> f64 {
+    match expr {
+        Expr::Num(n) => *n,
+        Expr::Var(i) => values[*i],
+        Expr::Neg(e) => -eval(e, values),
+        Expr::Not(e) => boolean(!truthy(eval(e, values))),
+        Expr::Call(func, e) => {
+            let v = eval(e, values);
+            match func {
+                Func::Abs => v.abs(),
+                Func::Sqrt => v.sqrt(),
+                Func::Log10 => v.log10(),
+                Func::IsFinite => boolean(v.is_finite()),
+            }
+        }
+        Expr::Bin(Op::And, l, r) => boolean(truthy(eval(l, values)) && truthy(eval(r, values))),
+        Expr::Bin(Op::Or, l, r) => boolean(truthy(eval(l, values)) || truthy(eval(r, values))),
+        Expr::Bin(op, l, r) => {
+            let (l, r) = (eval(l, values), eval(r, values));
+            match op {
+                Op::Add => l + r,
+                Op::Sub => l - r,
+                Op::Mul => l * r,
+                Op::Div => l / r,
+                Op::Lt => boolean(l < r),
+                Op::Le => boolean(l <= r),
+                Op::Gt => boolean(l > r),
+                Op::Ge => boolean(l >= r),
+                Op::Eq => boolean(l == r),
+                Op::Ne => boolean(!l.is_nan() && !r.is_nan() && l != r),
+                Op::And | Op::Or => unreachable!(),
+            }
+        }
+    }
+}
+
+/// Splits the input in tokens, with their positions.
+fn tokenize(input: &str) -> Result<Vec<(Token, usize)>, String> {
+    const OPS: [&str; 17] = [
+        "&&", "||", "<=", ">=", "==", "!=", "<", ">", "!", "+", "-", "*", "/", "(", ")", "&", "|",
+    ];
+    let mut tokens = Vec::new();
+    let bytes = input.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i] as char;
+        if !c.is_ascii() {
+            return Err(format!("unexpected character at position {}", i + 1));
+        } else if c.is_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit() || c == '.' {
+            let start = i;
+            while i < bytes.len()
+                && ((bytes[i] as char).is_ascii_alphanumeric()
+                    || bytes[i] == b'.'
+                    || (matches!(bytes[i], b'+' | b'-') && matches!(bytes[i - 1], b'e' | b'E')))
+            {
+                i += 1;
+            }
+            let number = input[start..i]
+                .parse::<f64>()
+                .map_err(|_| format!("invalid number '{}'", &input[start..i]))?;
+            tokens.push((Token::Num(number), start));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let start = i;
+            while i < bytes.len()
+                && ((bytes[i] as char).is_ascii_alphanumeric() || bytes[i] == b'_')
+            {
+                i += 1;
+            }
+            tokens.push((Token::Ident(input[start..i].to_string()), start));
+        } else {
+            match OPS.iter().find(|op| input[i..].starts_with(**op)) {
+                // Single & and | are typos of && and ||
+                Some(&op) if op == "&" || op == "|" => {
+                    return Err(format!("unknown operator '{}', use '{}{}'", op, op, op))
+                }
+                Some(op) => {
+                    tokens.push((Token::Op(op), i));
+                    i += op.len();
+                }
+                None => return Err(format!("unexpected character '{}'", c)),
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser of filter expressions.
+struct Parser<'a> {
+    input: &'a str,
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+    vars: Vec<Var>,
+    resolve: &'a dyn Fn(&str) -> Option<Var>,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    fn accept(&mut self, op: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Op(o)) if *o == op) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Position in the input of the current token, or the end of the input.
+    fn offset(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .map_or(self.input.len(), |(_, offset)| *offset)
+    }
+
+    /// Parses the top level, which is a list of clauses joined by &&
+    /// unless there is a top-level ||.
+    fn parse_clauses(&mut self) -> Result<Vec<(String, Expr)>, String> {
+        let mut clauses = Vec::new();
+        let start = self.pos;
+        loop {
+            let clause_start = self.offset();
+            let expr = self.parse_not()?;
+            clauses.push((
+                self.input[clause_start..self.offset()].trim().to_string(),
+                expr,
+            ));
+            if !self.accept("&&") {
+                break;
+            }
+        }
+        if self.peek() == Some(&Token::Op("||")) {
+            // The whole expression is a single clause
+            self.pos = start;
+            let expr = self.parse_or()?;
+            return Ok(vec![(self.input.trim().to_string(), expr)]);
+        }
+        Ok(clauses)
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_and()?;
+        while self.accept("||") {
+            expr = Expr::Bin(Op::Or, Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_not()?;
+        while self.accept("&&") {
+            expr = Expr::Bin(Op::And, Box::new(expr), Box::new(self.parse_not()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_not(&mut self) -> Result<Expr, String> {
+        if self.accept("!") {
+            Ok(Expr::Not(Box::new(self.parse_not()?)))
+        } else {
+            self.parse_comparison()
+        }
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expr, String> {
+        let expr = self.parse_sum()?;
+        for (text, op) in [
+            ("<=", Op::Le),
+            (">=", Op::Ge),
+            ("==", Op::Eq),
+            ("!=", Op::Ne),
+            ("<", Op::Lt),
+            (">", Op::Gt),
+        ] {
+            if self.accept(text) {
+                return Ok(Expr::Bin(op, Box::new(expr), Box::new(self.parse_sum()?)));
+            }
+        }
+        Ok(expr)
+    }
+
+    fn parse_sum(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_product()?;
+        loop {
+            let op = if self.accept("+") {
+                Op::Add
+            } else if self.accept("-") {
+                Op::Sub
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::Bin(op, Box::new(expr), Box::new(self.parse_product()?));
+        }
+    }
+
+    fn parse_product(&mut self) -> Result<Expr, String> {
+        let mut expr = self.parse_unary()?;
+        loop {
+            let op = if self.accept("*") {
+                Op::Mul
+            } else if self.accept("/") {
+                Op::Div
+            } else {
+                return Ok(expr);
+            };
+            expr = Expr::Bin(op, Box::new(expr), Box::new(self.parse_unary()?));
+        }
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        if self.accept("-") {
+            Ok(Expr::Neg(Box::new(self.parse_unary()?)))
+        } else if self.accept("!") {
+            Ok(Expr::Not(Box::new(self.parse_unary()?)))
+        } else {
+            self.parse_primary()
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expr, String> {
+        let offset = self.offset();
+        let token = self.peek().cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Num(n)) => Ok(Expr::Num(n)),
+            Some(Token::Op("(")) => {
+                let expr = self.parse_or()?;
+                if !self.accept(")") {
+                    return Err(format!("missing ')' at position {}", self.offset() + 1));
+                }
+                Ok(expr)
+            }
+            Some(Token::Ident(name)) => {
+                let func = match name.to_lowercase().as_str() {
+                    "abs" => Some(Func::Abs),
+                    "sqrt" => Some(Func::Sqrt),
+                    "log10" => Some(Func::Log10),
+                    "isfinite" => Some(Func::IsFinite),
+                    _ => None,
+                };
+                match func {
+                    Some(func) if self.accept("(") => {
+                        let expr = self.parse_or()?;
+                        if !self.accept(")") {
+                            return Err(format!("missing ')' at position {}", self.offset() + 1));
+                        }
+                        Ok(Expr::Call(func, Box::new(expr)))
+                    }
+                    _ => {
+                        let var = (self.resolve)(&name).ok_or(format!(
+                            "unknown column '{}' at position {}",
+                            name,
+                            offset + 1
+                        ))?;
+                        let index = match self.vars.iter().position(|v| *v == var) {
+                            Some(index) => index,
+                            None => {
+                                self.vars.push(var);
+                                self.vars.len() - 1
+                            }
+                        };
+                        Ok(Expr::Var(index))
+                    }
+                }
+            }
+            Some(token) => Err(format!("unexpected '{}' at position {}", token, offset + 1)),
+            None => Err("unexpected end of filter".to_string()),
+        }
+    }
+}
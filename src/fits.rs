@@ -0,0 +1,291 @@
+extern crate flate2;
+
+use flate2::read::GzDecoder;
+use std::fs::File;
+use std::io::Read;
+
+// FITS files are organized in blocks of 2880 bytes,
+// and header cards are 80 characters long.
+const BLOCK_SIZE: usize = 2880;
+const CARD_SIZE: usize = 80;
+
+/**
+ * A column of a FITS binary table, as described
+ * by its TTYPEn, TFORMn, TNULLn, TSCALn and TZEROn
+ * header keywords.
+ **/
+pub struct FitsColumn {
+    pub name: String,
+    // Type code of TFORM (L, B, I, J, K, A, E, D, ...)
+    pub format: char,
+    pub repeat: usize,
+    // Offset of the column in the row, in bytes
+    pub offset: usize,
+    pub null: Option<i64>,
+    pub scale: f64,
+    pub zero: f64,
+}
+
+impl FitsColumn {
+    fn width(&self) -> usize {
+        let size = match self.format {
+            'L' | 'B' | 'A' => 1,
+            'I' => 2,
+            'J' | 'E' => 4,
+            'K' | 'D' | 'C' | 'P' => 8,
+            'M' | 'Q' => 16,
+            // Bit arrays are packed
+            'X' => return self.repeat.div_ceil(8),
+            _ => 0,
+        };
+        size * self.repeat
+    }
+}
+
+/**
+ * The first binary table (BINTABLE extension)
+ * of a FITS file, fully read into memory.
+ **/
+pub struct FitsTable {
+    pub columns: Vec<FitsColumn>,
+    pub n_rows: usize,
+    row_len: usize,
+    data: Vec<u8>,
+}
+
+impl FitsTable {
+    /// Reads the first binary table of the given FITS file. The
+    /// file may be gzipped (.gz).
+    pub fn open(file: &str) -> Result<Self, String> {
+        let mut f = File::open(file).map_err(|e| format!("{}: {}", file, e))?;
+        let mut bytes = Vec::new();
+        if file.ends_with(".gz") {
+            GzDecoder::new(f)
+                .read_to_end(&mut bytes)
+                .map_err(|e| format!("{}: {}", file, e))?;
+        } else {
+            f.read_to_end(&mut bytes)
+                .map_err(|e| format!("{}: {}", file, e))?;
+        }
+        Self::parse(bytes).map_err(|e| format!("{}: {}", file, e))
+    }
+
+    /// Parses the FITS bytes and keeps the data of the first binary table.
+    pub fn parse(bytes: Vec<u8>) -> Result<Self, String> {
+        let mut pos: usize = 0;
+        loop {
+            if pos >= bytes.len() {
+                return Err("no binary table found".to_string());
+            }
+            let (cards, data_pos) = read_header(&bytes, pos)?;
+            let get = |key: &str| -> Option<&str> {
+                cards
+                    .iter()
+                    .find(|(k, _)| k == key)
+                    .map(|(_, v)| v.as_str())
+            };
+            let get_int = |key: &str| -> i64 { get(key).and_then(|v| v.parse().ok()).unwrap_or(0) };
+
+            let bitpix = get_int("BITPIX");
+            let naxis = get_int("NAXIS");
+            let mut data_size: i64 = if naxis > 0 { 1 } else { 0 };
+            for i in 1..=naxis {
+                data_size *= get_int(&format!("NAXIS{}", i));
+            }
+            let gcount = get("GCOUNT").and_then(|v| v.parse().ok()).unwrap_or(1);
+            data_size = (bitpix.abs() / 8) * gcount * (get_int("PCOUNT") + data_size);
+            let data_size = data_size as usize;
+
+            if get("XTENSION") == Some("BINTABLE") {
+                let row_len = get_int("NAXIS1") as usize;
+                let n_rows = get_int("NAXIS2") as usize;
+                let n_cols = get_int("TFIELDS") as usize;
+
+                let mut columns = Vec::with_capacity(n_cols);
+                let mut offset: usize = 0;
+                for i in 1..=n_cols {
+                    let (repeat, format) = parse_tform(get(&format!("TFORM{}", i)).unwrap_or(""))?;
+                    let column = FitsColumn {
+                        name: get(&format!("TTYPE{}", i))
+                            .map(|v| v.to_string())
+                            .unwrap_or(format!("col{}", i)),
+                        format,
+                        repeat,
+                        offset,
+                        null: get(&format!("TNULL{}", i)).and_then(|v| v.parse().ok()),
+                        scale: get(&format!("TSCAL{}", i))
+                            .and_then(|v| v.parse().ok())
+                            .unwrap_or(1.0),
+                        zero: get(&format!("TZERO{}", i))
+                            .and_then(|v| v.parse().ok())
+                            .unwrap_or(0.0),
+                    };
+                    offset += column.width();
+                    columns.push(column);
+                }
+                if offset != row_len {
+                    return Err(format!(
+                        "row length mismatch (NAXIS1={}, columns={})",
+                        row_len, offset
+                    ));
+                }
+                let end = data_pos + row_len * n_rows;
+                if end > bytes.len() {
+                    return Err("binary table is truncated".to_string());
+                }
+                let mut data = bytes;
+                data.truncate(end);
+                data.drain(..data_pos);
+                return Ok(FitsTable {
+                    columns,
+                    n_rows,
+                    row_len,
+                    data,
+                });
+            }
+
+            // Skip this HDU's data, padded to the block size
+            pos = data_pos + data_size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        }
+    }
+
+    /// Gets the column names (TTYPEn).
+    pub fn names(&self) -> Vec<&str> {
+        self.columns.iter().map(|c| c.name.as_str()).collect()
+    }
+
+    fn cell(&self, row: usize, col: usize) -> &[u8] {
+        let column = &self.columns[col];
+        let start = row * self.row_len + column.offset;
+        &self.data[start..start + column.width()]
+    }
+
+    /// Gets the stored value of an integer or logical cell, before
+    /// scaling. Returns None if the value is null (TNULLn) or the
+    /// column is not an integer.
+    fn get_raw(&self, row: usize, col: usize) -> Option<i64> {
+        let column = &self.columns[col];
+        let cell = self.cell(row, col);
+        let raw: i64 = match column.format {
+            'B' => cell[0] as i64,
+            'I' => i16::from_be_bytes([cell[0], cell[1]]) as i64,
+            'J' => i32::from_be_bytes(cell[0..4].try_into().unwrap()) as i64,
+            'K' => i64::from_be_bytes(cell[0..8].try_into().unwrap()),
+            'L' => match cell[0] {
+                b'T' => 1,
+                b'F' => 0,
+                _ => return None,
+            },
+            _ => return None,
+        };
+        Some(raw).filter(|raw| column.null != Some(*raw))
+    }
+
+    /// Gets the value of the given cell as an f64. Returns None if
+    /// the value is null (TNULLn or NaN) or is not numeric.
+    pub fn get_f64(&self, row: usize, col: usize) -> Option<f64> {
+        let column = &self.columns[col];
+        let cell = self.cell(row, col);
+        let val = match column.format {
+            'E' => f32::from_be_bytes(cell[0..4].try_into().unwrap()) as f64,
+            'D' => f64::from_be_bytes(cell[0..8].try_into().unwrap()),
+            'A' => return self.get_str(row, col).and_then(|s| s.trim().parse().ok()),
+            _ => self.get_raw(row, col)? as f64,
+        };
+        Some(val * column.scale + column.zero).filter(|v| !v.is_nan())
+    }
+
+    /// Gets the value of the given cell as an i64. Integer values without
+    /// scaling are read as they are, so that 64-bit IDs are preserved.
+    /// Returns None if the value is null or is not numeric.
+    pub fn get_i64(&self, row: usize, col: usize) -> Option<i64> {
+        let column = &self.columns[col];
+        match column.format {
+            'A' => self.get_str(row, col).and_then(|s| s.trim().parse().ok()),
+            'L' | 'B' | 'I' | 'J' | 'K' if column.scale == 1.0 && column.zero == 0.0 => {
+                self.get_raw(row, col)
+            }
+            _ => self.get_f64(row, col).map(|v| v as i64),
+        }
+    }
+
+    /// Gets the value of the given character (A) cell, without the
+    /// trailing blanks. Returns None if the value is empty or the
+    /// column is not a character column.
+    pub fn get_str(&self, row: usize, col: usize) -> Option<&str> {
+        if self.columns[col].format != 'A' {
+            return None;
+        }
+        let s = std::str::from_utf8(self.cell(row, col)).ok()?;
+        Some(s.trim_end_matches(['\0', ' '])).filter(|s| !s.is_empty())
+    }
+}
+
+/// Reads the header starting at the given position. Returns the
+/// (keyword, value) pairs and the position where the data starts.
+fn read_header(bytes: &[u8], start: usize) -> Result<(Vec<(String, String)>, usize), String> {
+    let mut cards = Vec::new();
+    let mut pos = start;
+    loop {
+        if pos + CARD_SIZE > bytes.len() {
+            return Err("unexpected end of header".to_string());
+        }
+        let card = String::from_utf8_lossy(&bytes[pos..pos + CARD_SIZE]);
+        pos += CARD_SIZE;
+        let key = card[..8].trim().to_string();
+        if key == "END" {
+            break;
+        }
+        if &card[8..10] == "= " {
+            cards.push((key, parse_value(&card[10..])));
+        }
+    }
+    // Data starts at the next block
+    Ok((
+        cards,
+        start + (pos - start).div_ceil(BLOCK_SIZE) * BLOCK_SIZE,
+    ))
+}
+
+/// Parses the value of a header card, removing quotes and comments.
+fn parse_value(value: &str) -> String {
+    let value = value.trim_start();
+    if let Some(rest) = value.strip_prefix('\'') {
+        // String value, quotes are escaped as ''
+        let mut s = String::new();
+        let mut chars = rest.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c == '\'' {
+                if chars.peek() == Some(&'\'') {
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            s.push(c);
+        }
+        s.trim_end().to_string()
+    } else {
+        match value.find('/') {
+            Some(i) => value[..i].trim().to_string(),
+            None => value.trim().to_string(),
+        }
+    }
+}
+
+/// Parses a TFORMn value (i.e. '1D', 'K', '20A') into its repeat count
+/// and type code.
+fn parse_tform(tform: &str) -> Result<(usize, char), String> {
+    let tform = tform.trim();
+    let digits: String = tform.chars().take_while(|c| c.is_ascii_digit()).collect();
+    let format = tform[digits.len()..]
+        .chars()
+        .next()
+        .ok_or(format!("invalid TFORM '{}'", tform))?;
+    let repeat = if digits.is_empty() {
+        1
+    } else {
+        digits.parse().unwrap_or(1)
+    };
+    Ok((repeat, format))
+}
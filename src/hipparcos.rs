@@ -0,0 +1,82 @@
+/**
+ * Layout of the files of a secondary catalog, like Hipparcos: the column
+ * list in the format of --columns (column names in order, with an optional
+ * unit in brackets), the value separator and whether the first line is a
+ * header. There are presets for the Hipparcos catalogs and for plain csv.
+ **/
+#[derive(Clone, Debug)]
+pub struct CatalogFormat {
+    pub name: &'static str,
+    pub columns: String,
+    pub sep: &'static str,
+    pub header: bool,
+}
+
+impl CatalogFormat {
+    pub const NAMES: [&'static str; 3] = ["csv", "esa1997", "vanleeuwen2007"];
+
+    /// Gets the preset with the given name:
+    ///  - csv: comma-separated file with a header and the columns
+    ///    hip,names,ra,dec,plx,plx_err,pmra,pmdec,gmag,col_idx.
+    ///  - esa1997: hip_main.dat of the original ESA (1997) catalogue (I/239),
+    ///    with '|'-separated fields H0 to H77 and no header.
+    ///  - vanleeuwen2007: hip2.dat of the van Leeuwen (2007) reduction (I/311),
+    ///    with whitespace-separated fields, positions in radians and no header.
+    ///
+    /// The van Leeuwen (2007) reduction has no V magnitudes, so its Hp
+    /// magnitudes are used instead.
+    pub fn from_str(name: &str) -> Option<CatalogFormat> {
+        match name.to_lowercase().as_str() {
+            "csv" => Some(CatalogFormat {
+                name: "csv",
+                columns: "hip,names,ra,dec,plx,plx_err,pmra,pmdec,gmag,col_idx".to_string(),
+                sep: r",",
+                header: true,
+            }),
+            "esa1997" | "hip1997" => Some(CatalogFormat {
+                name: "esa1997",
+                columns: columns_at(&[
+                    (1, "hip"),
+                    (5, "gmag"),
+                    (8, "ra"),
+                    (9, "dec"),
+                    (11, "plx"),
+                    (12, "pmra"),
+                    (13, "pmdec"),
+                    (16, "plx_err"),
+                    (37, "col_idx"),
+                ]),
+                sep: r"\s*\|\s*",
+                header: false,
+            }),
+            "vanleeuwen2007" | "hip2007" => Some(CatalogFormat {
+                name: "vanleeuwen2007",
+                columns: columns_at(&[
+                    (0, "hip"),
+                    (4, "ra[rad]"),
+                    (5, "dec[rad]"),
+                    (6, "plx"),
+                    (7, "pmra"),
+                    (8, "pmdec"),
+                    (11, "plx_err"),
+                    (19, "gmag"),
+                    (23, "col_idx"),
+                ]),
+                sep: r"\s+",
+                header: false,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Builds a column list with the given columns at the given indices,
+/// and 'empty' everywhere else.
+fn columns_at(columns: &[(usize, &str)]) -> String {
+    let n = columns.iter().map(|(i, _)| i + 1).max().unwrap_or(0);
+    let mut list = vec!["empty"; n];
+    for (i, name) in columns {
+        list[*i] = name;
+    }
+    list.join(",")
+}
@@ -0,0 +1,132 @@
+use std::fmt;
+
+/**
+ * Magnitude limits that define magnitude-limited and volume-limited
+ * subsets of the catalogs. Limits are NaN if not set. Apparent magnitudes
+ * are the observed ones (the magnitude column of each catalog), and
+ * absolute magnitudes are computed from them and the distance, without
+ * extinction.
+ **/
+#[derive(Clone, Copy, Debug)]
+pub struct MagLimits {
+    // Apparent magnitude limits: stars brighter than bright or fainter
+    // than faint are discarded
+    pub bright: f64,
+    pub faint: f64,
+    // Absolute magnitude limits
+    pub abs_bright: f64,
+    pub abs_faint: f64,
+    // Distance (pc) up to which the catalog must be complete: only stars
+    // within it which would be brighter than the faint limit at that
+    // distance are kept
+    pub volume_dist: f64,
+}
+
+impl Default for MagLimits {
+    fn default() -> Self {
+        MagLimits {
+            bright: f64::NAN,
+            faint: f64::NAN,
+            abs_bright: f64::NAN,
+            abs_faint: f64::NAN,
+            volume_dist: f64::NAN,
+        }
+    }
+}
+
+impl MagLimits {
+    /// Creates the limits, checking that they are consistent. The
+    /// volume-complete mode needs the faint apparent limit.
+    pub fn new(
+        bright: f64,
+        faint: f64,
+        abs_bright: f64,
+        abs_faint: f64,
+        volume_dist: f64,
+    ) -> Result<Self, String> {
+        if bright > faint || abs_bright > abs_faint {
+            return Err("the bright limits must be smaller than the faint limits".to_string());
+        }
+        if !volume_dist.is_nan() && (volume_dist <= 0.0 || !faint.is_finite()) {
+            return Err(
+                "the volume-complete mode needs a positive distance and a faint limit".to_string(),
+            );
+        }
+        Ok(MagLimits {
+            bright,
+            faint,
+            abs_bright,
+            abs_faint,
+            volume_dist,
+        })
+    }
+
+    pub fn is_none(&self) -> bool {
+        self.bright.is_nan()
+            && self.faint.is_nan()
+            && self.abs_bright.is_nan()
+            && self.abs_faint.is_nan()
+            && self.volume_dist.is_nan()
+    }
+
+    pub fn has_absolute(&self) -> bool {
+        !self.abs_bright.is_nan() || !self.abs_faint.is_nan()
+    }
+
+    pub fn has_volume(&self) -> bool {
+        !self.volume_dist.is_nan()
+    }
+
+    /// Whether the apparent magnitude is within the limits.
+    pub fn accept_apparent(&self, appmag: f64) -> bool {
+        !(appmag < self.bright || appmag > self.faint)
+    }
+
+    /// Whether the absolute magnitude is within the limits. Stars without
+    /// absolute magnitude are rejected if there are limits.
+    pub fn accept_absolute(&self, absmag: f64) -> bool {
+        !self.has_absolute()
+            || absmag.is_finite() && !(absmag < self.abs_bright || absmag > self.abs_faint)
+    }
+
+    /// Absolute magnitude of the faintest stars that are complete up
+    /// to the volume distance, or NaN if there is no volume mode.
+    pub fn volume_absmag(&self) -> f64 {
+        self.faint - 5.0 * f64::log10(self.volume_dist) + 5.0
+    }
+
+    /// Whether the star at the given distance (pc) with the given absolute
+    /// magnitude is in the complete volume.
+    pub fn accept_volume(&self, dist_pc: f64, absmag: f64) -> bool {
+        !self.has_volume() || dist_pc <= self.volume_dist && absmag <= self.volume_absmag()
+    }
+}
+
+impl fmt::Display for MagLimits {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_none() {
+            return write!(f, "none");
+        }
+        let range = |bright: f64, faint: f64| match (bright.is_nan(), faint.is_nan()) {
+            (false, false) => format!("[{}, {}]", bright, faint),
+            (false, true) => format!(">= {}", bright),
+            (true, false) => format!("<= {}", faint),
+            (true, true) => "any".to_string(),
+        };
+        write!(
+            f,
+            "apparent {}, absolute {}",
+            range(self.bright, self.faint),
+            range(self.abs_bright, self.abs_faint)
+        )?;
+        if self.has_volume() {
+            write!(
+                f,
+                ", complete to {} pc (absolute <= {:.2})",
+                self.volume_dist,
+                self.volume_absmag()
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -4,27 +4,81 @@ extern crate nalgebra as na;
 extern crate regex;
 
 use crate::color;
+use crate::color::{ColorIndex, ColorModel, TeffCalibration};
 use crate::constants;
 use crate::coord;
 use crate::data;
+use crate::distance::DistEstimator;
+use crate::ecsv;
+use crate::extinction::Extinction;
+use crate::filter::{Filter, Var};
+use crate::fits;
+use crate::limits::MagLimits;
 use crate::mem;
 use crate::parse;
+use crate::photometry;
+use crate::photometry::ExtLaw;
+use crate::region::Region;
+use crate::rejected::{RejectedDump, Rejection};
+use crate::table;
 use crate::util;
+use crate::votable;
+use crate::zeropoint;
 
 use memmap::Mmap;
 use regex::Regex;
-use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
 use std::io;
 use std::io::BufRead;
+use std::ops::AddAssign;
 use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
 use std::{f32, f64, fs::File};
 
 use flate2::read::GzDecoder;
 use glob::glob;
 use na::base::Vector3;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::record::Field;
+use parquet::schema::types::Type as SchemaType;
+
+use data::{LargeLongMap, Particle, StarStore};
+use zeropoint::{Solution, ZeroPoint};
+
+/// Checks whether the given file name has one of the given extensions,
+/// optionally gzipped.
+fn has_extension(file: &str, extensions: &[&str]) -> bool {
+    let file = file.strip_suffix(".gz").unwrap_or(file);
+    extensions.iter().any(|ext| file.ends_with(ext))
+}
+
+fn is_fits(file: &str) -> bool {
+    has_extension(file, &[".fits", ".fit"])
+}
+
+fn is_votable(file: &str) -> bool {
+    has_extension(file, &[".vot", ".votable", ".xml"])
+}
+
+fn is_ecsv(file: &str) -> bool {
+    has_extension(file, &[".ecsv"])
+}
 
-use data::{LargeLongMap, Particle};
+/// Checks whether the given file is a text table: any gzipped file, or a
+/// csv, txt or dat file. Catalogs split in parts, whose names end in the
+/// part number like tyc2.dat.00, are text tables too.
+fn is_text(file: &str) -> bool {
+    if file.ends_with(".gz") {
+        return true;
+    }
+    let file = match file.rsplit_once('.') {
+        Some((stem, part)) if !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit()) => stem,
+        _ => file,
+    };
+    has_extension(file, &[".csv", ".txt", ".dat"])
+}
 
 /// Column content type identifier.
 #[allow(non_camel_case_types, dead_code)]
@@ -59,6 +113,11 @@ pub enum ColId {
     geodist,
     fidelity,
     phot_dist,
+    nu_eff,
+    pseudocolour,
+    ecl_lat,
+    params_solved,
+    excess_factor,
     empty,
 }
 
@@ -77,6 +136,8 @@ impl ColId {
             ColId::pmra => "pmra",
             ColId::pmdec => "pmdec",
             ColId::radvel => "radvel",
+            ColId::pmra_err => "pmra_err",
+            ColId::pmdec_err => "pmdec_err",
             ColId::radvel_err => "radvel_err",
             ColId::gmag => "gmag",
             ColId::bpmag => "bpmag",
@@ -91,6 +152,11 @@ impl ColId {
             ColId::geodist => "geodist",
             ColId::fidelity => "fidelity_v1",
             ColId::phot_dist => "phot_dist",
+            ColId::nu_eff => "nu_eff",
+            ColId::pseudocolour => "pseudocolour",
+            ColId::ecl_lat => "ecl_lat",
+            ColId::params_solved => "params_solved",
+            ColId::excess_factor => "phot_bp_rp_excess_factor",
             ColId::empty => "empty",
             _ => "*none*",
         }
@@ -123,11 +189,18 @@ impl ColId {
             "pllx_error" => Some(ColId::plx_err),
             "pllx_err" => Some(ColId::plx_err),
             "pllx_e" => Some(ColId::plx_err),
+            "parallax_error" => Some(ColId::plx_err),
             "pmra" => Some(ColId::pmra),
             "pmdec" => Some(ColId::pmdec),
             "pmde" => Some(ColId::pmdec),
+            "pmra_error" => Some(ColId::pmra_err),
+            "pmra_err" => Some(ColId::pmra_err),
+            "pmdec_error" => Some(ColId::pmdec_err),
+            "pmdec_err" => Some(ColId::pmdec_err),
             "radvel" => Some(ColId::radvel),
             "rv" => Some(ColId::radvel),
+            "radial_velocity" => Some(ColId::radvel),
+            "radial_velocity_error" => Some(ColId::radvel_err),
             "radvel_err" => Some(ColId::radvel_err),
             "radvel_e" => Some(ColId::radvel_err),
             "rv_err" => Some(ColId::radvel_err),
@@ -163,6 +236,15 @@ impl ColId {
             "distance_gspphot" => Some(ColId::phot_dist),
             "dist_phot" => Some(ColId::phot_dist),
             "phot_dist" => Some(ColId::phot_dist),
+            "nu_eff_used_in_astrometry" => Some(ColId::nu_eff),
+            "nu_eff" => Some(ColId::nu_eff),
+            "pseudocolour" => Some(ColId::pseudocolour),
+            "pseudocolor" => Some(ColId::pseudocolour),
+            "ecl_lat" => Some(ColId::ecl_lat),
+            "astrometric_params_solved" => Some(ColId::params_solved),
+            "params_solved" => Some(ColId::params_solved),
+            "phot_bp_rp_excess_factor" => Some(ColId::excess_factor),
+            "excess_factor" => Some(ColId::excess_factor),
             "empty" => Some(ColId::empty),
             _ => None,
         }
@@ -196,7 +278,7 @@ impl Additional {
         let path = Path::new(dir);
         if path.exists() {
             let mut addit: Self = Self::empty();
-            if path.is_file() && dir.ends_with(".gz") {
+            if path.is_file() && (dir.ends_with(".gz") || is_fits(dir)) {
                 addit.load_file(dir);
                 return Some(addit);
             } else {
@@ -223,6 +305,10 @@ impl Additional {
     }
 
     fn load_file(&mut self, file: &str) {
+        if is_fits(file) {
+            self.load_fits(file);
+            return;
+        }
         let mut total: u64 = 0;
         // Read csv.gz using GzDecoder
         let f = File::open(file).expect("Error: file not found");
@@ -285,6 +371,63 @@ impl Additional {
         }
     }
 
+    /// Loads additional columns from the first binary table of a FITS file.
+    /// The source ID column is found by name, and the rest of the columns are
+    /// resolved with their TTYPE keywords. Nulls (TNULL or NaN) are stored as NaN.
+    fn load_fits(&mut self, file: &str) {
+        let table = match fits::FitsTable::open(file) {
+            Ok(table) => table,
+            Err(e) => {
+                log::error!("Error reading FITS table: {}", e);
+                return;
+            }
+        };
+        let names = table.names();
+        let sid_col = match names
+            .iter()
+            .position(|name| ColId::from_str(name) == Some(ColId::source_id))
+        {
+            Some(i) => i,
+            None => {
+                log::error!(
+                    "Error: additional FITS table {} has no '{}' column",
+                    file,
+                    ColId::source_id.to_str()
+                );
+                return;
+            }
+        };
+
+        // Value columns, keyed by their column ID name if we know it
+        let mut cols = Vec::new();
+        for (i, name) in names.iter().enumerate() {
+            if i == sid_col {
+                continue;
+            }
+            let key = match ColId::from_str(name) {
+                Some(col_id) => col_id.to_str().to_string(),
+                None => name.to_string(),
+            };
+            self.indices.entry(key).or_insert(cols.len());
+            cols.push(i);
+        }
+
+        for row in 0..table.n_rows {
+            let source_id = match table.get_i64(row, sid_col) {
+                Some(sid) => sid,
+                None => continue,
+            };
+            let vals: Vec<f64> = cols
+                .iter()
+                .map(|col| table.get_f64(row, *col).unwrap_or(f64::NAN))
+                .collect();
+            self.values.insert(source_id, vals);
+            if (row + 1) % 100000 == 0 {
+                log::debug!("   object {}", row + 1);
+            }
+        }
+    }
+
     pub fn n_cols(&self) -> usize {
         self.indices.len()
     }
@@ -296,6 +439,17 @@ impl Additional {
         self.indices.contains_key(col_id.to_str())
     }
 
+    pub fn has_name(&self, name: &str) -> bool {
+        self.indices.contains_key(name)
+    }
+
+    /// Gets the value of the column with the given name, which may not
+    /// be a known column.
+    pub fn get_by_name(&self, name: &str, source_id: i64) -> Option<f64> {
+        let index = self.indices.get(name)?;
+        self.values.get(source_id)?.get(*index).copied()
+    }
+
     pub fn get(&self, col_id: ColId, source_id: i64) -> Option<f64> {
         if self.has_col(col_id) {
             let index: usize = *self
@@ -319,7 +473,285 @@ impl Additional {
     }
 }
 
-pub struct Loader {
+/// Default columns of the main catalog csv files.
+pub const DEFAULT_COLUMNS: &str =
+    "source_id,ra,dec,plx,ra_err,dec_err,plx_err,pmra,pmdec,radvel,gmag,bpmag,rpmag,ruwe,ref_epoch";
+
+/**
+ * Configuration of a loader. The defaults are the ones of the main
+ * catalog, so that only the options that differ need to be set.
+ * The string options are parsed when the loader is created.
+ **/
+pub struct LoaderConfig {
+    // Regular expression to separate values in file
+    pub sep: Regex,
+    // Maximum number of files to load in a directory (negative for no limit)
+    pub max_files: i32,
+    // Maximum number of records to load per file (negative for no limit)
+    pub max_records: i32,
+    pub plx_zeropoint: f64,
+    pub ruwe_cap: f32,
+    pub distpc_cap: f64,
+    pub plx_err_faint: f64,
+    pub plx_err_bright: f64,
+    pub plx_err_cap: f64,
+    pub use_phot_dist: bool,
+    pub mag_corrections: u8,
+    pub extinction: Arc<Extinction>,
+    pub ext_law: ExtLaw,
+    pub g_corrections: bool,
+    pub excess_nsigma: f64,
+    pub color_model: ColorModel,
+    pub teff_cal: TeffCalibration,
+    pub allow_negative_plx: bool,
+    pub dist_estimator: DistEstimator,
+    pub mag_limits: MagLimits,
+    pub ref_epoch: f64,
+    pub target_epoch: f64,
+    pub must_load: Option<HashSet<i64>>,
+    // Comma-separated list of files or directories with additional columns
+    pub additional: String,
+    // Row filter expression, empty for none
+    pub filter: String,
+    // Sky regions as kind:values
+    pub regions: Vec<String>,
+    pub rejected_dump: Option<RejectedDump>,
+    // Comma-separated list of the columns of csv files, with optional units
+    pub columns: String,
+    pub has_header: bool,
+    pub header_columns: bool,
+    pub n_threads: usize,
+}
+
+impl Default for LoaderConfig {
+    fn default() -> Self {
+        LoaderConfig {
+            sep: Regex::new(r"\s+|,").unwrap(),
+            max_files: -1,
+            max_records: -1,
+            plx_zeropoint: 0.0,
+            ruwe_cap: f32::NAN,
+            distpc_cap: 1.0e6,
+            plx_err_faint: 10.0,
+            plx_err_bright: 10.0,
+            plx_err_cap: 1.0,
+            use_phot_dist: false,
+            mag_corrections: 2,
+            extinction: Arc::new(Extinction::Analytical),
+            ext_law: ExtLaw::Constant,
+            g_corrections: false,
+            excess_nsigma: f64::NAN,
+            color_model: ColorModel::Helland,
+            teff_cal: TeffCalibration::default(),
+            allow_negative_plx: false,
+            dist_estimator: DistEstimator::InvPlx,
+            mag_limits: MagLimits::default(),
+            ref_epoch: constants::GAIA_EPOCH,
+            target_epoch: f64::NAN,
+            must_load: None,
+            additional: String::new(),
+            filter: String::new(),
+            regions: Vec::new(),
+            rejected_dump: None,
+            columns: DEFAULT_COLUMNS.to_string(),
+            has_header: true,
+            header_columns: false,
+            n_threads: 1,
+        }
+    }
+}
+
+/// A row of a catalog file, whose values are looked up by column ID.
+/// Missing columns and null values are NaN, 0 or None.
+trait Row {
+    fn f64(&self, col_id: ColId) -> f64;
+    fn i64(&self, col_id: ColId) -> i64;
+    fn str(&self, col_id: ColId) -> Option<&str>;
+}
+
+/// Row of text tokens, as in csv files. The numeric values of the
+/// columns with a conversion factor are converted to the expected units.
+struct TokenRow<'a> {
+    tokens: &'a [Option<&'a str>],
+    indices: &'a HashMap<ColId, usize>,
+    factors: Option<&'a HashMap<usize, f64>>,
+}
+
+impl TokenRow<'_> {
+    fn token(&self, col_id: ColId) -> Option<&&str> {
+        let index = self.indices.get(&col_id)?;
+        self.tokens.get(*index)?.as_ref()
+    }
+}
+
+impl Row for TokenRow<'_> {
+    fn f64(&self, col_id: ColId) -> f64 {
+        let value = parse::parse_f64(self.token(col_id));
+        match self
+            .indices
+            .get(&col_id)
+            .and_then(|index| self.factors?.get(index))
+        {
+            Some(factor) => value * factor,
+            None => value,
+        }
+    }
+
+    fn i64(&self, col_id: ColId) -> i64 {
+        parse::parse_i64(self.token(col_id))
+    }
+
+    fn str(&self, col_id: ColId) -> Option<&str> {
+        self.token(col_id).copied().filter(|t| !t.is_empty())
+    }
+}
+
+/// Row of typed parquet fields, which are read without going through text.
+struct ParquetRow<'a> {
+    fields: Vec<&'a Field>,
+    indices: &'a HashMap<ColId, usize>,
+}
+
+impl ParquetRow<'_> {
+    fn field(&self, col_id: ColId) -> Option<&Field> {
+        let index = self.indices.get(&col_id)?;
+        self.fields.get(*index).copied()
+    }
+}
+
+impl Row for ParquetRow<'_> {
+    fn f64(&self, col_id: ColId) -> f64 {
+        match self.field(col_id) {
+            Some(Field::Double(v)) => *v,
+            Some(Field::Float(v)) => *v as f64,
+            Some(Field::Float16(v)) => v.to_f64(),
+            Some(Field::Long(v)) => *v as f64,
+            Some(Field::Int(v)) => *v as f64,
+            Some(Field::Short(v)) => *v as f64,
+            Some(Field::Byte(v)) => *v as f64,
+            Some(Field::ULong(v)) => *v as f64,
+            Some(Field::UInt(v)) => *v as f64,
+            Some(Field::UShort(v)) => *v as f64,
+            Some(Field::UByte(v)) => *v as f64,
+            Some(Field::Bool(v)) => f64::from(u8::from(*v)),
+            Some(Field::Str(v)) => parse::parse_f64(Some(&v.as_str())),
+            _ => f64::NAN,
+        }
+    }
+
+    fn i64(&self, col_id: ColId) -> i64 {
+        match self.field(col_id) {
+            Some(Field::Long(v)) => *v,
+            Some(Field::Int(v)) => *v as i64,
+            Some(Field::Short(v)) => *v as i64,
+            Some(Field::Byte(v)) => *v as i64,
+            Some(Field::ULong(v)) => *v as i64,
+            Some(Field::UInt(v)) => *v as i64,
+            Some(Field::UShort(v)) => *v as i64,
+            Some(Field::UByte(v)) => *v as i64,
+            Some(Field::Str(v)) => parse::parse_i64(Some(&v.as_str())),
+            _ => 0,
+        }
+    }
+
+    fn str(&self, col_id: ColId) -> Option<&str> {
+        match self.field(col_id) {
+            Some(Field::Str(v)) if !v.is_empty() => Some(v),
+            _ => None,
+        }
+    }
+}
+
+/// Row of a FITS binary table, whose typed values are read without going through text.
+struct FitsRow<'a> {
+    table: &'a fits::FitsTable,
+    row: usize,
+    indices: &'a HashMap<ColId, usize>,
+}
+
+impl Row for FitsRow<'_> {
+    fn f64(&self, col_id: ColId) -> f64 {
+        self.indices
+            .get(&col_id)
+            .and_then(|col| self.table.get_f64(self.row, *col))
+            .unwrap_or(f64::NAN)
+    }
+
+    fn i64(&self, col_id: ColId) -> i64 {
+        self.indices
+            .get(&col_id)
+            .and_then(|col| self.table.get_i64(self.row, *col))
+            .unwrap_or(0)
+    }
+
+    fn str(&self, col_id: ColId) -> Option<&str> {
+        let col = self.indices.get(&col_id)?;
+        self.table.get_str(self.row, *col)
+    }
+}
+
+/// Values of the columns of a row used to create a star. Missing
+/// values are NaN, or 0 for the ids.
+struct StarValues {
+    source_id: i64,
+    hip: i32,
+    names: Option<String>,
+    ra: f64,
+    dec: f64,
+    plx: f64,
+    phot_dist: f64,
+    plx_err: f64,
+    pmra: f64,
+    pmdec: f64,
+    radvel: f64,
+    gmag: f64,
+    bpmag: f64,
+    rpmag: f64,
+    col_idx: f64,
+    ruwe: f64,
+    ag: f64,
+    ebp_min_rp: f64,
+    teff: f64,
+    ref_epoch: f64,
+    params_solved: f64,
+    excess_factor: f64,
+}
+
+impl StarValues {
+    fn read(row: &dyn Row) -> Self {
+        StarValues {
+            source_id: row.i64(ColId::source_id),
+            hip: row.i64(ColId::hip) as i32,
+            names: row.str(ColId::names).map(String::from),
+            ra: row.f64(ColId::ra),
+            dec: row.f64(ColId::dec),
+            plx: row.f64(ColId::plx),
+            phot_dist: row.f64(ColId::phot_dist),
+            plx_err: row.f64(ColId::plx_err),
+            pmra: row.f64(ColId::pmra),
+            pmdec: row.f64(ColId::pmdec),
+            radvel: row.f64(ColId::radvel),
+            gmag: row.f64(ColId::gmag),
+            bpmag: row.f64(ColId::bpmag),
+            rpmag: row.f64(ColId::rpmag),
+            col_idx: row.f64(ColId::col_idx),
+            ruwe: row.f64(ColId::ruwe),
+            ag: row.f64(ColId::ag),
+            ebp_min_rp: row.f64(ColId::ebp_min_rp),
+            teff: row.f64(ColId::teff),
+            ref_epoch: row.f64(ColId::ref_epoch),
+            params_solved: row.f64(ColId::params_solved),
+            excess_factor: row.f64(ColId::excess_factor),
+        }
+    }
+}
+
+/**
+ * Settings of a loader, parsed from its LoaderConfig. They don't change
+ * while loading, so the worker loaders of a parallel load share them.
+ **/
+#[allow(dead_code)]
+pub struct LoaderSettings {
     // Regular expression to separate values in file
     pub sep: Regex,
     // Maximum number of files to load in a directory
@@ -345,25 +777,101 @@ pub struct Loader {
     // 1 - only if ag and ebr_min_rp are in the catalog
     // 2 - also use analytical alternatives
     pub mag_corrections: u8,
+    // Extinction model for the stars without extinction in the catalog, with mag_corrections 2
+    pub extinction: Arc<Extinction>,
+    // Law to compute the reddening of the stars with A_G but without E(BP-RP) in the catalog
+    pub ext_law: ExtLaw,
+    // Whether to apply the G magnitude corrections of Gaia DR3
+    pub g_corrections: bool,
+    // Maximum corrected BP/RP flux excess factor, in sigmas, or NaN for no cut
+    pub excess_nsigma: f64,
+    // Model to convert effective temperatures to colours
+    pub color_model: ColorModel,
+    // Relations to compute T_eff from the colour indices
+    pub teff_cal: TeffCalibration,
     // If set to true, negative parallaxes will be transformed to the default 0.04 arcsec value
     pub allow_negative_plx: bool,
+    // Estimator to compute distances from parallaxes
+    pub dist_estimator: DistEstimator,
+    // Apparent, absolute and volume-complete magnitude limits
+    pub mag_limits: MagLimits,
+    // Reference epoch of the catalog (Julian year), used when there is no ref_epoch column
+    pub ref_epoch: f64,
+    // Epoch (Julian year) to propagate the stars to, or NaN to keep them at their reference epoch
+    pub target_epoch: f64,
     // Must-load star ids
     pub must_load: Option<HashSet<i64>>,
     // Additional columns
     pub additional: Vec<Additional>,
-    // Indices
-    pub indices: HashMap<ColId, usize>,
+    // Row filter expression
+    pub filter: Option<Filter>,
+    // Sky regions (stars in any of them are loaded), or empty for the whole sky
+    pub regions: Vec<Region>,
+    // Unit conversion factors of the csv columns given with a unit, by index
+    pub factors: HashMap<usize, f64>,
+    // Whether the first line of csv files is a header
+    pub has_header: bool,
+    // Whether to build the indices of each csv file from its header row
+    pub header_columns: bool,
+    // Number of threads to load files with
+    pub n_threads: usize,
     // Coordinate conversion
     pub coord: coord::Coord,
-    // Star counts per magnitude
-    pub counts_per_mag: RefCell<[u32; 22]>,
+    // File to write the rejected stars to
+    pub rejected_dump: Option<RejectedDump>,
+}
+
+/**
+ * Epoch (Julian years) of the positions of a set of stars. It is only
+ * defined when all the stars share it.
+ **/
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Epochs {
+    // No stars
+    #[default]
+    None,
+    // All the stars are at this epoch
+    Single(f64),
+    // The stars are at different epochs
+    Mixed,
+}
+
+impl Epochs {
+    /// Adds the epoch of one star.
+    pub fn add(&mut self, epoch: f64) {
+        *self += Epochs::Single(epoch);
+    }
+}
 
-    // Counts
+impl AddAssign for Epochs {
+    fn add_assign(&mut self, other: Epochs) {
+        *self = match (*self, other) {
+            (Epochs::None, epochs) | (epochs, Epochs::None) => epochs,
+            (Epochs::Single(a), Epochs::Single(b)) if a == b => Epochs::Single(a),
+            _ => Epochs::Mixed,
+        };
+    }
+}
+
+/**
+ * Counts of the processed, loaded and rejected stars of a loader. The
+ * counts of the worker loaders of a parallel load are added up with +=.
+ **/
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Counters {
+    // Star counts per magnitude
+    pub counts_per_mag: [u32; 22],
     pub total_processed: u64,
     pub total_loaded: u64,
+    pub total_zeropoint: u64,
+    // Zero points of stars outside the calibrated magnitude and colour range
+    pub total_zeropoint_uncalibrated: u64,
+    pub total_propagated: u64,
+    pub total_no_color: u64,
     pub rejected_dist: u64,
     pub rejected_dist_inf: u64,
     pub rejected_dist_neg: u64,
+    pub rejected_dist_estimator: u64,
     pub rejected_geodist: u64,
     pub rejected_fidelity: u64,
     pub rejected_plx: u64,
@@ -371,27 +879,112 @@ pub struct Loader {
     pub rejected_plx_neg: u64,
     pub rejected_ruwe: u64,
     pub rejected_mag: u64,
+    pub rejected_excess: u64,
+    pub rejected_region: u64,
+    pub rejected_maglim: u64,
+    pub rejected_absmaglim: u64,
+    pub rejected_volume: u64,
+    // Rejections of each clause of the filter
+    pub rejected_filter: Vec<u64>,
+    // Epochs of the positions of the loaded stars
+    pub epochs: Epochs,
+}
+
+impl Counters {
+    /// Counts a star rejected by the given clause of the filter.
+    fn reject_filter(&mut self, clause: usize) {
+        if self.rejected_filter.len() <= clause {
+            self.rejected_filter.resize(clause + 1, 0);
+        }
+        self.rejected_filter[clause] += 1;
+    }
+}
+
+impl AddAssign for Counters {
+    fn add_assign(&mut self, other: Counters) {
+        for (count, other_count) in self.counts_per_mag.iter_mut().zip(other.counts_per_mag) {
+            *count += other_count;
+        }
+        self.total_processed += other.total_processed;
+        self.total_loaded += other.total_loaded;
+        self.total_zeropoint += other.total_zeropoint;
+        self.total_zeropoint_uncalibrated += other.total_zeropoint_uncalibrated;
+        self.total_propagated += other.total_propagated;
+        self.total_no_color += other.total_no_color;
+        self.rejected_dist += other.rejected_dist;
+        self.rejected_dist_inf += other.rejected_dist_inf;
+        self.rejected_dist_neg += other.rejected_dist_neg;
+        self.rejected_dist_estimator += other.rejected_dist_estimator;
+        self.rejected_geodist += other.rejected_geodist;
+        self.rejected_fidelity += other.rejected_fidelity;
+        self.rejected_plx += other.rejected_plx;
+        self.rejected_plx_crit += other.rejected_plx_crit;
+        self.rejected_plx_neg += other.rejected_plx_neg;
+        self.rejected_ruwe += other.rejected_ruwe;
+        self.rejected_mag += other.rejected_mag;
+        self.rejected_excess += other.rejected_excess;
+        self.rejected_region += other.rejected_region;
+        self.rejected_maglim += other.rejected_maglim;
+        self.rejected_absmaglim += other.rejected_absmaglim;
+        self.rejected_volume += other.rejected_volume;
+        self.epochs += other.epochs;
+        // The counters of the workers only have the clauses that rejected stars
+        if self.rejected_filter.len() < other.rejected_filter.len() {
+            self.rejected_filter.resize(other.rejected_filter.len(), 0);
+        }
+        for (count, other_count) in self.rejected_filter.iter_mut().zip(other.rejected_filter) {
+            *count += other_count;
+        }
+    }
+}
+
+pub struct Loader {
+    pub settings: Arc<LoaderSettings>,
+    // Indices of the csv columns, replaced by the ones of the header
+    // row while loading a file if header_columns is set
+    pub indices: HashMap<ColId, usize>,
+    pub counters: Counters,
+    // Rejected rows waiting to be written to the rejected dump
+    rejected_rows: Vec<Rejection>,
 }
 
 #[allow(dead_code)]
 impl Loader {
-    pub fn new(
-        sep: Regex,
-        max_files: i32,
-        max_records: i32,
-        plx_zeropoint: f64,
-        ruwe_cap: f32,
-        distpc_cap: f64,
-        plx_err_faint: f64,
-        plx_err_bright: f64,
-        plx_err_cap: f64,
-        use_phot_dist: bool,
-        mag_corrections: u8,
-        allow_negative_plx: bool,
-        must_load: Option<HashSet<i64>>,
-        additional_str: &str,
-        indices_str: &str,
-    ) -> Self {
+    pub fn new(config: LoaderConfig) -> Self {
+        let LoaderConfig {
+            sep,
+            max_files,
+            max_records,
+            plx_zeropoint,
+            ruwe_cap,
+            distpc_cap,
+            plx_err_faint,
+            plx_err_bright,
+            plx_err_cap,
+            use_phot_dist,
+            mag_corrections,
+            extinction,
+            ext_law,
+            g_corrections,
+            excess_nsigma,
+            color_model,
+            teff_cal,
+            allow_negative_plx,
+            dist_estimator,
+            mag_limits,
+            ref_epoch,
+            target_epoch,
+            must_load,
+            additional: additional_str,
+            filter: filter_str,
+            regions: regions_str,
+            rejected_dump,
+            columns: indices_str,
+            has_header,
+            header_columns,
+            n_threads,
+        } = config;
+
         // Additional
         let mut additional = Vec::new();
         if !additional_str.is_empty() {
@@ -409,102 +1002,283 @@ impl Loader {
             }
         }
 
+        // Filter, whose columns may be in the additional catalogs
+        let filter = if filter_str.trim().is_empty() {
+            None
+        } else {
+            let resolve = |name: &str| match ColId::from_str(name) {
+                Some(col_id) => Some(Var::Column(col_id)),
+                None if additional.iter().any(|a| a.has_name(name)) => {
+                    Some(Var::Additional(name.to_string()))
+                }
+                None => None,
+            };
+            let filter = Filter::compile(&filter_str, &resolve)
+                .unwrap_or_else(|e| panic!("Error: invalid filter '{}': {}", filter_str, e));
+            log::info!("Filter clauses: {}", filter.clauses().join(" | "));
+            Some(filter)
+        };
+        let rejected_filter = vec![0; filter.as_ref().map_or(0, |f| f.clauses().len())];
+
+        // Regions
+        let regions: Vec<Region> = regions_str
+            .iter()
+            .map(|r| Region::from_str(r).unwrap_or_else(|e| panic!("Error: invalid region: {}", e)))
+            .collect();
+
         // Indices
         let mut indices = HashMap::new();
-        let cols: Vec<&str> = indices_str.split(',').collect();
-        for i in 0..cols.len() {
-            indices.insert(ColId::from_str(cols.get(i).unwrap()).unwrap(), i);
+        let mut factors = HashMap::new();
+        for (i, col) in indices_str.split(',').enumerate() {
+            // Columns may have a unit in brackets, like ra[rad]
+            let (name, unit) = match col.trim().split_once('[') {
+                Some((name, unit)) => (name, unit.trim_end_matches(']')),
+                None => (col.trim(), ""),
+            };
+            let col_id =
+                ColId::from_str(name).unwrap_or_else(|| panic!("Error: unknown column '{}'", name));
+            let factor = table::unit_factor(col_id, unit)
+                .unwrap_or_else(|| panic!("Error: unknown unit '{}' of column {}", unit, name));
+            if factor != 1.0 {
+                factors.insert(i, factor);
+            }
+            indices.insert(col_id, i);
         }
 
         Loader {
-            sep,
-            max_files,
-            max_records,
-            plx_zeropoint,
-            ruwe_cap,
-            distpc_cap,
-            plx_err_faint,
-            plx_err_bright,
-            plx_err_cap,
-            use_phot_dist,
-            mag_corrections,
-            allow_negative_plx,
-            must_load,
-            additional,
+            settings: Arc::new(LoaderSettings {
+                sep,
+                max_files,
+                max_records,
+                plx_zeropoint,
+                ruwe_cap,
+                distpc_cap,
+                plx_err_faint,
+                plx_err_bright,
+                plx_err_cap,
+                use_phot_dist,
+                mag_corrections,
+                extinction,
+                ext_law,
+                g_corrections,
+                excess_nsigma,
+                color_model,
+                teff_cal,
+                allow_negative_plx,
+                dist_estimator,
+                mag_limits,
+                ref_epoch,
+                target_epoch,
+                must_load,
+                additional,
+                filter,
+                regions,
+                factors,
+                has_header,
+                header_columns,
+                n_threads,
+                coord: coord::Coord::new(),
+                rejected_dump,
+            }),
             indices,
-            coord: coord::Coord::new(),
-            counts_per_mag: RefCell::new([0; 22]),
-
-            total_processed: 0,
-            total_loaded: 0,
-            rejected_dist: 0,
-            rejected_dist_inf: 0,
-            rejected_dist_neg: 0,
-            rejected_geodist: 0,
-            rejected_fidelity: 0,
-            rejected_plx: 0,
-            rejected_plx_crit: 0,
-            rejected_plx_neg: 0,
-            rejected_ruwe: 0,
-            rejected_mag: 0,
+            counters: Counters {
+                rejected_filter,
+                ..Default::default()
+            },
+            rejected_rows: Vec::new(),
         }
     }
 
-    pub fn load_dir(&mut self, dir: &str) -> Result<Vec<Particle>, &str> {
-        let mut list: Vec<Particle> = Vec::new();
-        let mut i = 0;
+    pub fn load_dir(&mut self, dir: &str) -> Result<StarStore, &str> {
+        let mut list = StarStore::new();
+        self.load_dir_chunked(dir, usize::MAX, &mut |chunk| list.append(chunk));
+        Ok(list)
+    }
+
+    /// Loads the given file or directory, passing the loaded stars to the sink
+    /// in chunks of at least max_stars stars (the last one may be smaller). Chunks
+    /// are only cut between files, and they come in file order.
+    pub fn load_dir_chunked(
+        &mut self,
+        dir: &str,
+        max_stars: usize,
+        sink: &mut dyn FnMut(StarStore),
+    ) {
+        let mut list = StarStore::new();
 
+        let mut files: Vec<String> = Vec::new();
         if Path::new(dir).is_file() {
-            self.load_file(dir, &mut list, 1, 1);
+            files.push(dir.to_string());
         } else {
             // glob directory
             let mut dir_glob: String = String::from(dir);
             dir_glob.push_str("/*");
-            let dir_count = glob(&dir_glob).expect("Error reading glob pattern").count();
-            let count = if self.max_files > 0 {
-                usize::min(self.max_files as usize, dir_count)
-            } else {
-                dir_count
-            };
             for entry in glob(&dir_glob).expect("Error reading glob pattern") {
-                if self.max_files >= 0 && i >= self.max_files {
-                    return Ok(list);
-                }
                 match entry {
                     Ok(path) => {
-                        self.load_file(
-                            path.to_str().expect("Error: path not valid"),
-                            &mut list,
-                            (i + 1) as usize,
-                            count,
-                        );
+                        files.push(path.to_str().expect("Error: path not valid").to_string())
                     }
                     Err(e) => log::error!("Error: {:?}", e),
                 }
-                i += 1;
-                if i % 10 == 0 {
+            }
+        }
+        if self.settings.max_files >= 0 {
+            files.truncate(self.settings.max_files as usize);
+        }
+        let count = files.len();
+
+        if self.settings.n_threads <= 1 || count <= 1 {
+            for (i, file) in files.iter().enumerate() {
+                self.load_file(file, &mut list, i + 1, count);
+                self.write_rejected();
+                if (i + 1).is_multiple_of(10) {
                     mem::log_mem();
                 }
+                if list.len() >= max_stars {
+                    sink(std::mem::replace(&mut list, StarStore::new()));
+                }
+            }
+        } else {
+            log::info!(
+                "Loading {} files using {} threads",
+                count,
+                usize::min(self.settings.n_threads, count)
+            );
+            // When chunking, load a few files per thread at a time
+            let batch = if max_stars == usize::MAX {
+                count
+            } else {
+                4 * self.settings.n_threads
+            };
+            for (b, batch_files) in files.chunks(batch).enumerate() {
+                self.load_files_parallel(batch_files, b * batch, count, &mut list);
+                if list.len() >= max_stars {
+                    sink(std::mem::replace(&mut list, StarStore::new()));
+                }
             }
         }
-        Ok(list)
+        if !list.is_empty() {
+            sink(list);
+        }
+        if let Some(dump) = &self.settings.rejected_dump {
+            dump.finish();
+        }
     }
 
-    /// Loads a single file, being it csv.gz or csv
-    /// The format is hard-coded for now, with csv.gz being in eDR3 format,
-    /// and csv being in hipparcos format.
+    /// Writes the rejected rows of the last file to the rejected dump.
+    fn write_rejected(&mut self) {
+        if let Some(dump) = &self.settings.rejected_dump {
+            dump.write(&self.rejected_rows);
+        }
+        self.rejected_rows.clear();
+    }
+
+    /// Loads the given files using n_threads worker loaders, which pick
+    /// the next file from a shared counter. The particles are appended to
+    /// the list and their rejected rows are written to the dump in file order,
+    /// and the counters of the workers are merged afterwards, so that the result
+    /// is the same as loading the files sequentially.
+    /// The files are numbered from first + 1 in the logs, out of file_count.
+    fn load_files_parallel(
+        &mut self,
+        files: &[String],
+        first: usize,
+        file_count: usize,
+        list: &mut StarStore,
+    ) {
+        let count = files.len();
+        let n_threads = usize::min(self.settings.n_threads, count);
+
+        let next = AtomicUsize::new(0);
+        // Stars and rejected rows of each file
+        type FileResult = (StarStore, Vec<Rejection>);
+        let results: Mutex<Vec<Option<FileResult>>> =
+            Mutex::new((0..count).map(|_| None).collect());
+        let workers: Vec<Loader> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..n_threads)
+                .map(|_| {
+                    let mut worker = self.worker();
+                    let next = &next;
+                    let results = &results;
+                    scope.spawn(move || {
+                        loop {
+                            let i = next.fetch_add(1, Ordering::Relaxed);
+                            if i >= count {
+                                break;
+                            }
+                            let mut part = StarStore::new();
+                            worker.load_file(&files[i], &mut part, first + i + 1, file_count);
+                            let rows = std::mem::take(&mut worker.rejected_rows);
+                            results.lock().unwrap()[i] = Some((part, rows));
+                            if (first + i + 1).is_multiple_of(10) {
+                                mem::log_mem();
+                            }
+                        }
+                        worker
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|h| h.join().expect("Error in loader thread"))
+                .collect()
+        });
+
+        for worker in workers {
+            self.counters += worker.counters;
+        }
+        for (part, rows) in results.into_inner().unwrap().into_iter().flatten() {
+            list.append(part);
+            self.rejected_rows = rows;
+            self.write_rejected();
+        }
+    }
+
+    /// Creates a loader with the same settings and indices as this one and
+    /// zeroed counters, to be used in a worker thread.
+    fn worker(&self) -> Loader {
+        Loader {
+            settings: self.settings.clone(),
+            indices: self.indices.clone(),
+            counters: Counters::default(),
+            rejected_rows: Vec::new(),
+        }
+    }
+
+    /// Loads a single file, being it csv.gz, csv, parquet, FITS, VOTable or ECSV.
+    /// The columns of csv files are given by self.indices, while the columns
+    /// of the other formats are matched by name.
     pub fn load_file(
         &mut self,
         file: &str,
-        list: &mut Vec<Particle>,
+        list: &mut StarStore,
         file_num: usize,
         file_count: usize,
     ) {
+        if file.ends_with(".parquet") {
+            self.load_parquet(file, list, file_num, file_count);
+            return;
+        }
+        if is_fits(file) {
+            self.load_fits(file, list, file_num, file_count);
+            return;
+        }
+        if is_votable(file) || is_ecsv(file) {
+            let table = if is_votable(file) {
+                votable::read(file)
+            } else {
+                ecsv::read(file)
+            };
+            let table = table.unwrap_or_else(|e| panic!("Error reading table: {}", e));
+            self.load_table(table, file, list, file_num, file_count);
+            return;
+        }
         let mut total: usize = 0;
         let mut loaded: usize = 0;
         let mut skipped: usize = 0;
         // Skip weird files
-        if !(file.ends_with(".gz") || file.ends_with(".csv") || file.ends_with(".txt")) {
+        if !is_text(file) {
+            log::info!("Skipping file with unknown extension: {}", file);
             return;
         }
         let is_gz = file.ends_with(".gz") || file.ends_with(".gzip");
@@ -518,9 +1292,20 @@ impl Loader {
             reader = Box::new(&mmap[..]);
         }
 
+        let header_lines = usize::from(self.settings.has_header);
+        let mut indices_backup = None;
         for line in reader.lines() {
-            // Skip header
-            if total > 0 {
+            if total == 0 && self.settings.has_header {
+                // Header, only used if the columns come from it
+                if self.settings.header_columns {
+                    match self.indices_from_header(&line.expect("Error reading line"), file) {
+                        Some(indices) => {
+                            indices_backup = Some(std::mem::replace(&mut self.indices, indices))
+                        }
+                        None => return,
+                    }
+                }
+            } else {
                 match self.parse_line(line.expect("Error reading line")) {
                     Some(part) => {
                         list.push(part);
@@ -530,17 +1315,272 @@ impl Loader {
                 }
             }
             total += 1;
-            if self.max_records >= 0 && (total - 1) as i32 >= self.max_records {
+            if self.settings.max_records >= 0
+                && (total - header_lines) as i32 >= self.settings.max_records
+            {
+                break;
+            }
+        }
+        if let Some(indices) = indices_backup {
+            self.indices = indices;
+        }
+        self.log_file(
+            loaded,
+            total.saturating_sub(header_lines),
+            skipped,
+            file,
+            file_num,
+            file_count,
+        );
+    }
+
+    /// Loads a single parquet file. The columns are matched by name
+    /// using ColId::from_str, so self.indices is not used. Only the
+    /// recognised columns are read, and their typed values are used
+    /// directly, with nulls as missing values.
+    fn load_parquet(
+        &mut self,
+        file: &str,
+        list: &mut StarStore,
+        file_num: usize,
+        file_count: usize,
+    ) {
+        let mut total: usize = 0;
+        let mut loaded: usize = 0;
+        let mut skipped: usize = 0;
+
+        let f = File::open(file).expect("Error: file not found");
+        let reader = SerializedFileReader::new(f)
+            .unwrap_or_else(|e| panic!("Error reading parquet file {}: {}", file, e));
+
+        // Project the schema onto the columns we know about.
+        let schema = reader.metadata().file_metadata().schema();
+        let mut fields = Vec::new();
+        let mut names = Vec::new();
+        for field in schema.get_fields() {
+            match ColId::from_str(field.name()) {
+                Some(ColId::empty) | None => (),
+                Some(_) => {
+                    names.push(field.name());
+                    fields.push(field.clone());
+                }
+            }
+        }
+        let (indices, _) = Self::indices_from_names(&names);
+        if !self.check_required_columns(&indices, file) {
+            return;
+        }
+        let projection = SchemaType::group_type_builder(schema.name())
+            .with_fields(fields)
+            .build()
+            .expect("Error building parquet projection");
+
+        let rows = reader
+            .get_row_iter(Some(projection))
+            .unwrap_or_else(|e| panic!("Error reading rows of {}: {}", file, e));
+
+        // Use the file's own indices while parsing its rows.
+        let indices_backup = std::mem::replace(&mut self.indices, indices);
+        for row in rows {
+            let row = row.unwrap_or_else(|e| panic!("Error reading row of {}: {}", file, e));
+            let row = ParquetRow {
+                fields: row.get_column_iter().map(|(_, field)| field).collect(),
+                indices: &self.indices,
+            };
+            let (star, clause, zeropoint) = self.read_row(&row);
+            match self.create_or_reject(star, clause, zeropoint) {
+                Some(part) => {
+                    list.push(part);
+                    loaded += 1;
+                }
+                None => skipped += 1,
+            }
+            total += 1;
+            if self.settings.max_records >= 0 && total as i32 >= self.settings.max_records {
+                break;
+            }
+        }
+        self.indices = indices_backup;
+        self.log_file(loaded, total, skipped, file, file_num, file_count);
+    }
+
+    /// Loads the first binary table of a FITS file. The columns are matched
+    /// by their TTYPE keywords using ColId::from_str, so self.indices is not used.
+    /// The typed values are read directly, and null values (TNULL or NaN) are missing.
+    fn load_fits(&mut self, file: &str, list: &mut StarStore, file_num: usize, file_count: usize) {
+        let mut total: usize = 0;
+        let mut loaded: usize = 0;
+        let mut skipped: usize = 0;
+
+        let table = fits::FitsTable::open(file)
+            .unwrap_or_else(|e| panic!("Error reading FITS table: {}", e));
+        let (indices, _) = Self::indices_from_names(&table.names());
+        if !self.check_required_columns(&indices, file) {
+            return;
+        }
+
+        // Use the file's own indices while parsing its rows.
+        let indices_backup = std::mem::replace(&mut self.indices, indices);
+        for row in 0..table.n_rows {
+            let row = FitsRow {
+                table: &table,
+                row,
+                indices: &self.indices,
+            };
+            let (star, clause, zeropoint) = self.read_row(&row);
+            match self.create_or_reject(star, clause, zeropoint) {
+                Some(part) => {
+                    list.push(part);
+                    loaded += 1;
+                }
+                None => skipped += 1,
+            }
+            total += 1;
+            if self.settings.max_records >= 0 && total as i32 >= self.settings.max_records {
+                break;
+            }
+        }
+        self.indices = indices_backup;
+        self.log_file(loaded, total, skipped, file, file_num, file_count);
+    }
+
+    /// Loads a self-describing table (VOTable, ECSV). The columns are matched
+    /// by name using ColId::from_str, and the values are converted to the
+    /// expected units using the units in the table header.
+    fn load_table(
+        &mut self,
+        table: table::Table,
+        file: &str,
+        list: &mut StarStore,
+        file_num: usize,
+        file_count: usize,
+    ) {
+        let mut total: usize = 0;
+        let mut loaded: usize = 0;
+        let mut skipped: usize = 0;
+
+        let (indices, unknown) = Self::indices_from_names(&table.names());
+        if !unknown.is_empty() {
+            log::debug!("{}: ignoring columns {:?}", file, unknown);
+        }
+        if !self.check_required_columns(&indices, file) {
+            return;
+        }
+
+        // Conversion factors of the used columns, if they are not in the expected units
+        let mut factors = HashMap::new();
+        for (col_id, index) in &indices {
+            let column = &table.columns[*index];
+            match table::unit_factor(*col_id, &column.unit) {
+                Some(factor) if factor != 1.0 => {
+                    factors.insert(*index, factor);
+                }
+                Some(_) => (),
+                None => log::warn!(
+                    "{}: unknown unit '{}' of column {}, using values as they are",
+                    file,
+                    column.unit,
+                    column.name
+                ),
+            }
+        }
+
+        // Use the file's own indices while parsing its rows.
+        let indices_backup = std::mem::replace(&mut self.indices, indices);
+        for row in &table.rows {
+            let tokens: Vec<Option<&str>> = row.iter().map(|v| v.as_deref()).collect();
+            let row = TokenRow {
+                tokens: &tokens,
+                indices: &self.indices,
+                factors: Some(&factors),
+            };
+            let (star, clause, zeropoint) = self.read_row(&row);
+            match self.create_or_reject(star, clause, zeropoint) {
+                Some(part) => {
+                    list.push(part);
+                    loaded += 1;
+                }
+                None => skipped += 1,
+            }
+            total += 1;
+            if self.settings.max_records >= 0 && total as i32 >= self.settings.max_records {
                 break;
             }
         }
+        self.indices = indices_backup;
         self.log_file(loaded, total, skipped, file, file_num, file_count);
     }
 
+    /// Checks that the columns matched by name contain the positions and the
+    /// parallax. Otherwise, logs the missing columns and the file, which is
+    /// skipped, and returns false. The parallax is replaced by the photometric
+    /// distance if those are used, and is not needed if geometric distances come
+    /// from an additional catalog.
+    fn check_required_columns(&self, indices: &HashMap<ColId, usize>, file: &str) -> bool {
+        let mut required = vec![ColId::ra, ColId::dec];
+        if self.settings.use_phot_dist {
+            required.push(ColId::phot_dist);
+        } else if !self.has_additional_col(ColId::geodist) {
+            required.push(ColId::plx);
+        }
+        let missing: Vec<&str> = required
+            .iter()
+            .filter(|col_id| !indices.contains_key(col_id))
+            .map(|col_id| col_id.to_str())
+            .collect();
+        if !missing.is_empty() {
+            log::error!(
+                "Error: required columns {:?} not found in {}, skipping file",
+                missing,
+                file
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Builds the column indices of a csv file from its header row, split
+    /// with the loader's separator. Unrecognised names are reported. Returns
+    /// None if the required columns are missing.
+    fn indices_from_header(&self, header: &str, file: &str) -> Option<HashMap<ColId, usize>> {
+        let names: Vec<&str> = self.settings.sep.split(header.trim()).collect();
+        let (indices, unknown) = Self::indices_from_names(&names);
+        if !unknown.is_empty() {
+            log::warn!(
+                "{}: ignoring unrecognised columns in header: {}",
+                file,
+                unknown.join(", ")
+            );
+        }
+        if self.check_required_columns(&indices, file) {
+            Some(indices)
+        } else {
+            None
+        }
+    }
+
+    /// Builds the column indices map from a list of column names, typically
+    /// coming from the header of a file. Returns the indices map and the list
+    /// of names that could not be matched to any column ID. If several names map
+    /// to the same column ID, the first one is used.
+    pub fn indices_from_names(names: &[&str]) -> (HashMap<ColId, usize>, Vec<String>) {
+        let mut indices = HashMap::new();
+        let mut unknown = Vec::new();
+        for (i, name) in names.iter().enumerate() {
+            match ColId::from_str(name.trim()) {
+                Some(col_id) => {
+                    indices.entry(col_id).or_insert(i);
+                }
+                None => unknown.push(name.to_string()),
+            }
+        }
+        (indices, unknown)
+    }
+
     fn log_file(
         &self,
         loaded: usize,
-        total: usize,
+        records: usize,
         skipped: usize,
         file: &str,
         file_num: usize,
@@ -553,49 +1593,144 @@ impl Loader {
             100.0 * file_num as f32 / file_count as f32,
             Path::new(file).file_name().unwrap().to_str().unwrap(),
             loaded,
-            total - 1,
-            100.0 * loaded as f32 / (total as f32 - 1.0),
+            records,
+            100.0 * loaded as f32 / records as f32,
             skipped
         );
     }
 
-    fn get_index(&self, col_id: &ColId) -> usize {
-        match self.indices.get(col_id) {
-            Some(value) => *value,
-            // Set out of range so that tokens.get() produces None
-            None => 50000,
+    /// Parses a line using self.indices, converting the columns in the factors
+    fn parse_line(&mut self, line: String) -> Option<Particle> {
+        let tokens: Vec<Option<&str>> = self.settings.sep.split(line.trim()).map(Some).collect();
+        let row = TokenRow {
+            tokens: &tokens,
+            indices: &self.indices,
+            factors: if self.settings.header_columns {
+                None
+            } else {
+                Some(&self.settings.factors)
+            },
+        };
+        let (star, clause, zeropoint) = self.read_row(&row);
+        self.create_or_reject(star, clause, zeropoint)
+    }
+
+    /// Reads the values of a row, and evaluates the filter and the
+    /// parallax zero point on it. The zero point is only computed for
+    /// the rows that pass the filter.
+    fn read_row(&self, row: &dyn Row) -> (StarValues, Option<usize>, Option<ZeroPoint>) {
+        let star = StarValues::read(row);
+        let clause = self.filter_clause(row);
+        let zeropoint = if clause.is_none() {
+            self.lindegren_zeropoint(row)
+        } else {
+            None
+        };
+        (star, clause, zeropoint)
+    }
+
+    /// Creates a particle from the values of a row, or rejects it if
+    /// it failed the given filter clause.
+    fn create_or_reject(
+        &mut self,
+        star: StarValues,
+        clause: Option<usize>,
+        zeropoint: Option<ZeroPoint>,
+    ) -> Option<Particle> {
+        // Filter, which must-load stars skip like the other criteria
+        if let Some(clause) = clause {
+            self.counters.total_processed += 1;
+            self.counters.reject_filter(clause);
+            if self.settings.rejected_dump.is_some() {
+                let reason = format!(
+                    "filter: {}",
+                    self.settings.filter.as_ref().unwrap().clauses()[clause]
+                );
+                let values = [star.plx, star.plx_err, star.ruwe, star.gmag, f64::NAN];
+                self.dump_rejected(&reason, star.source_id, values);
+            }
+            return None;
+        }
+        self.create_particle(star, zeropoint)
+    }
+
+    /// Computes the parallax zero point of Lindegren et al. (2021) from the
+    /// magnitude, colour, ecliptic latitude and solution type columns. The
+    /// ecliptic latitude is computed from ra and dec if it is not in the catalog,
+    /// and the solution type is deduced from the colour columns if it is not
+    /// in the catalog either.
+    fn lindegren_zeropoint(&self, row: &dyn Row) -> Option<ZeroPoint> {
+        let nu_eff = row.f64(ColId::nu_eff);
+        let pseudocolour = row.f64(ColId::pseudocolour);
+        if !nu_eff.is_finite() && !pseudocolour.is_finite() {
+            return None;
+        }
+        let params_solved = row.f64(ColId::params_solved);
+        let solution = if params_solved.is_nan() {
+            if nu_eff.is_finite() {
+                Solution::FiveParam
+            } else {
+                Solution::SixParam
+            }
+        } else {
+            Solution::from_params_solved(params_solved as i32)?
+        };
+        let colour = match solution {
+            Solution::FiveParam => nu_eff,
+            Solution::SixParam => pseudocolour,
+        };
+        let mut ecl_lat = row.f64(ColId::ecl_lat);
+        if ecl_lat.is_nan() {
+            ecl_lat = zeropoint::ecliptic_latitude(row.f64(ColId::ra), row.f64(ColId::dec));
         }
+        zeropoint::zero_point(row.f64(ColId::gmag), colour, ecl_lat, solution)
     }
 
-    /// Parses a line using self.indices
-    fn parse_line(&mut self, line: String) -> Option<Particle> {
-        let tokens: Vec<&str> = self.sep.split(&line).collect();
-
-        self.create_particle(
-            tokens.get(self.get_index(&ColId::source_id)),
-            tokens.get(self.get_index(&ColId::hip)),
-            tokens.get(self.get_index(&ColId::names)),
-            tokens.get(self.get_index(&ColId::ra)),
-            tokens.get(self.get_index(&ColId::dec)),
-            tokens.get(self.get_index(&ColId::plx)),
-            tokens.get(self.get_index(&ColId::phot_dist)),
-            tokens.get(self.get_index(&ColId::plx_err)),
-            tokens.get(self.get_index(&ColId::pmra)),
-            tokens.get(self.get_index(&ColId::pmdec)),
-            tokens.get(self.get_index(&ColId::radvel)),
-            tokens.get(self.get_index(&ColId::gmag)),
-            tokens.get(self.get_index(&ColId::bpmag)),
-            tokens.get(self.get_index(&ColId::rpmag)),
-            tokens.get(self.get_index(&ColId::col_idx)),
-            tokens.get(self.get_index(&ColId::ruwe)),
-            tokens.get(self.get_index(&ColId::ag)),
-            tokens.get(self.get_index(&ColId::ebp_min_rp)),
-            tokens.get(self.get_index(&ColId::teff)),
-        )
+    /// Evaluates the filter on the given row. Returns the index of the first
+    /// clause the row fails, or None if it passes. Columns in the additional
+    /// catalogs take precedence over the columns of the row.
+    fn filter_clause(&self, row: &dyn Row) -> Option<usize> {
+        let filter = self.settings.filter.as_ref()?;
+        let source_id = row.i64(ColId::source_id);
+        if self.must_load_particle(source_id) {
+            return None;
+        }
+        let values: Vec<f64> = filter
+            .vars
+            .iter()
+            .map(|var| match var {
+                Var::Column(col_id) => {
+                    self.get_attribute_or_else(*col_id, source_id, row.f64(*col_id))
+                }
+                Var::Additional(name) => self
+                    .settings
+                    .additional
+                    .iter()
+                    .find(|a| a.has_name(name))
+                    .and_then(|a| a.get_by_name(name, source_id))
+                    .unwrap_or(f64::NAN),
+            })
+            .collect();
+        filter.eval(&values)
+    }
+
+    /// Adds the rejected star to the rows of the current file, which are
+    /// written to the rejected dump once the file is loaded, if there is a
+    /// dump and the star is in its sample. The values are plx, plx_err, ruwe,
+    /// appmag and dist_pc.
+    fn dump_rejected(&mut self, reason: &str, source_id: i64, values: [f64; 5]) {
+        match &self.settings.rejected_dump {
+            Some(dump) if dump.sampled(source_id) => self.rejected_rows.push(Rejection {
+                source_id,
+                reason: reason.to_string(),
+                values,
+            }),
+            _ => (),
+        }
     }
 
     fn must_load_particle(&self, id: i64) -> bool {
-        match &self.must_load {
+        match &self.settings.must_load {
             Some(must_load) => must_load.contains(&id),
             None => false,
         }
@@ -603,176 +1738,321 @@ impl Loader {
 
     fn create_particle(
         &mut self,
-        ssource_id: Option<&&str>,
-        ship_id: Option<&&str>,
-        snames: Option<&&str>,
-        sra: Option<&&str>,
-        sdec: Option<&&str>,
-        splx: Option<&&str>,
-        sphot_dist: Option<&&str>,
-        splx_e: Option<&&str>,
-        spmra: Option<&&str>,
-        spmdec: Option<&&str>,
-        srv: Option<&&str>,
-        sappmag: Option<&&str>,
-        sbp: Option<&&str>,
-        srp: Option<&&str>,
-        sbv: Option<&&str>,
-        sruwe: Option<&&str>,
-        sag: Option<&&str>,
-        sebp_min_rp: Option<&&str>,
-        steff: Option<&&str>,
+        star: StarValues,
+        zeropoint: Option<ZeroPoint>,
     ) -> Option<Particle> {
-        self.total_processed += 1;
+        self.counters.total_processed += 1;
         // Source ID
-        let mut source_id: i64 = parse::parse_i64(ssource_id);
+        let mut source_id: i64 = star.source_id;
 
         // First, check if we accept it given the current constraints
 
         // Parallax:
         // If it comes from additional, just take it (already zero point-corrected)
-        // Otherwise, apply zero point
-        let mut plx: f64 = self.get_attribute_or_else(
-            ColId::plx,
-            source_id,
-            parse::parse_f64(splx) - self.plx_zeropoint,
-        );
-        let plx_e: f64 = parse::parse_f64(splx_e);
+        // Otherwise, apply zero point (per-star if available, else global)
+        let mut plx: f64 = match self.get_additional(ColId::plx, source_id) {
+            Some(plx) if plx.is_finite() => plx,
+            _ => match zeropoint {
+                Some(zpt) if star.plx.is_finite() => {
+                    self.counters.total_zeropoint += 1;
+                    if !zpt.calibrated {
+                        self.counters.total_zeropoint_uncalibrated += 1;
+                    }
+                    star.plx - zpt.value
+                }
+                _ => star.plx - self.settings.plx_zeropoint,
+            },
+        };
+        let plx_e: f64 = star.plx_err;
 
         // Gmag: additional, else column, else use bp and rp
-        let mut appmag: f64 =
-            self.get_attribute_or_else(ColId::gmag, source_id, parse::parse_f64(sappmag));
+        let mut appmag: f64 = self.get_attribute_or_else(ColId::gmag, source_id, star.gmag);
+        let gmag_from_col = appmag.is_finite();
         if !appmag.is_finite() {
-            if !parse::is_empty(sbp) && !parse::is_empty(srp) {
-                let bp: f64 = parse::parse_f64(sbp);
-                let rp: f64 = parse::parse_f64(srp);
-                appmag = self.gmag_from_xp(bp, rp);
-            }
+            appmag = self.gmag_from_xp(star.bpmag, star.rpmag);
         }
 
         let has_fidelity = self.has_additional_col(ColId::fidelity);
         let has_geodist = self.has_additional_col(ColId::geodist);
 
         // Distance: photometric distance is in catalog.
-        let phot_dist = if self.use_phot_dist && !parse::is_empty(sphot_dist) {
-            parse::parse_f64(sphot_dist)
+        let phot_dist = if self.settings.use_phot_dist && star.phot_dist.is_finite() {
+            star.phot_dist
         } else {
             -1.0
         };
 
-        let hip: i32 = parse::parse_i32(ship_id);
+        let hip: i32 = star.hip;
         if source_id == 0 {
             source_id = hip as i64;
         }
 
         let must_load = self.must_load_particle(source_id);
+        let ruwe_val: f32 = self.get_ruwe(source_id, star.ruwe);
+
+        // Photometric quality cut and corrections of the G magnitude
+        if !self.settings.excess_nsigma.is_nan() || self.settings.g_corrections {
+            let bp_rp = star.bpmag - star.rpmag;
+            if !self.settings.excess_nsigma.is_nan() && !must_load {
+                let excess =
+                    self.get_attribute_or_else(ColId::excess_factor, source_id, star.excess_factor);
+                if !photometry::accept_excess(excess, bp_rp, appmag, self.settings.excess_nsigma) {
+                    self.counters.rejected_excess += 1;
+                    self.dump_rejected(
+                        "bp/rp excess factor",
+                        source_id,
+                        [plx, plx_e, ruwe_val as f64, appmag, f64::NAN],
+                    );
+                    return None;
+                }
+            }
+            if self.settings.g_corrections && gmag_from_col {
+                let params_solved = if star.params_solved.is_nan() {
+                    None
+                } else {
+                    Some(star.params_solved as i32)
+                };
+                appmag = photometry::correct_gmag(appmag, bp_rp, params_solved);
+            }
+        }
 
         // Fidelity test.
         if has_fidelity && !self.accept_fidelity(source_id) {
-            self.rejected_fidelity += 1;
+            self.counters.rejected_fidelity += 1;
+            self.dump_rejected(
+                "fidelity",
+                source_id,
+                [plx, plx_e, ruwe_val as f64, appmag, f64::NAN],
+            );
             return None;
         }
 
         if !self.accept_magnitude(appmag) {
             // Stars without magnitude are always rejected.
-            self.rejected_mag += 1;
+            self.counters.rejected_mag += 1;
+            self.dump_rejected(
+                "magnitude",
+                source_id,
+                [plx, plx_e, ruwe_val as f64, appmag, f64::NAN],
+            );
+            return None;
+        } else if !self.settings.mag_limits.accept_apparent(appmag) {
+            self.counters.rejected_maglim += 1;
+            self.dump_rejected(
+                "magnitude limits",
+                source_id,
+                [plx, plx_e, ruwe_val as f64, appmag, f64::NAN],
+            );
             return None;
-        } else if !has_geodist && !self.use_phot_dist {
+        } else if !has_geodist && !self.settings.use_phot_dist {
             // Parallax test, only if there are no geo_distances
             // and we are not using photometric distances (or phot_dist is invalid).
             if plx.is_finite() && plx <= 0.0 {
                 // If parallax is negative...
-                if self.allow_negative_plx {
+                if self.settings.dist_estimator.accepts_negative_plx() {
+                    // The distance estimator handles it, keep it as is.
+                } else if self.settings.allow_negative_plx {
                     // If allow negative, just set to default positive value (25 kpc).
                     plx = 0.04;
                 } else {
                     // Otherwise, reject.
-                    self.rejected_plx += 1;
-                    self.rejected_plx_neg += 1;
+                    self.counters.rejected_plx += 1;
+                    self.counters.rejected_plx_neg += 1;
+                    self.dump_rejected(
+                        "negative parallax",
+                        source_id,
+                        [plx, plx_e, ruwe_val as f64, appmag, f64::NAN],
+                    );
                     return None;
                 }
             } else if !must_load && !self.accept_parallax(appmag, plx, plx_e) {
                 // Reject due to parallax criteria.
-                self.rejected_plx += 1;
-                self.rejected_plx_crit += 1;
+                self.counters.rejected_plx += 1;
+                self.counters.rejected_plx_crit += 1;
+                self.dump_rejected(
+                    "parallax error",
+                    source_id,
+                    [plx, plx_e, ruwe_val as f64, appmag, f64::NAN],
+                );
                 return None;
             }
         }
 
-        // Extra attributes
-        let mut extra: HashMap<ColId, f32> = HashMap::with_capacity(2);
-
-        let ruwe_val: f32 = self.get_ruwe(source_id, sruwe);
         // RUWE test
         if !must_load && !self.accept_ruwe(ruwe_val) {
-            self.rejected_ruwe += 1;
+            self.counters.rejected_ruwe += 1;
+            self.dump_rejected(
+                "ruwe",
+                source_id,
+                [plx, plx_e, ruwe_val as f64, appmag, f64::NAN],
+            );
             return None;
         }
-        if ruwe_val.is_finite() {
-            extra.insert(ColId::ruwe, ruwe_val);
-        }
+        let ruwe = if ruwe_val.is_finite() {
+            ruwe_val
+        } else {
+            f32::NAN
+        };
 
         // If we have geometric distances, we only accept stars which have one, otherwise
         // we accept all.
         let geodist_pc = self.get_geodistance(source_id);
         let has_geodist_star = geodist_pc > 0.0;
         if !(must_load || !has_geodist || (has_geodist && has_geodist_star)) {
-            self.rejected_geodist += 1;
+            self.counters.rejected_geodist += 1;
+            self.dump_rejected(
+                "geodist",
+                source_id,
+                [plx, plx_e, ruwe_val as f64, appmag, f64::NAN],
+            );
+            return None;
+        }
+
+        // Positions and proper motions, propagated to the target epoch if needed
+        let mut ra: f64 = star.ra;
+        let mut dec: f64 = star.dec;
+        let mut mualphastar: f64 = star.pmra;
+        let mut mudelta: f64 = star.pmdec;
+        let mut radvel: f64 = star.radvel;
+        let mut dist_scale = 1.0;
+        let mut epoch = if star.ref_epoch.is_nan() {
+            self.settings.ref_epoch
+        } else {
+            star.ref_epoch
+        };
+        if self.settings.target_epoch.is_finite() && mualphastar.is_finite() && mudelta.is_finite()
+        {
+            let dt = self.settings.target_epoch - epoch;
+            if dt.is_finite() && dt != 0.0 {
+                let (ra_t, dec_t, plx_t, pmra_t, pmdec_t, radvel_t) = util::propagate_epoch(
+                    ra.to_radians(),
+                    dec.to_radians(),
+                    plx,
+                    mualphastar,
+                    mudelta,
+                    radvel,
+                    dt,
+                );
+                if plx > 0.0 {
+                    // Distances not coming from the parallax change in the same proportion
+                    dist_scale = plx / plx_t;
+                }
+                ra = ra_t.to_degrees();
+                dec = dec_t.to_degrees();
+                plx = plx_t;
+                mualphastar = pmra_t;
+                mudelta = pmdec_t;
+                radvel = radvel_t;
+                epoch = self.settings.target_epoch;
+                self.counters.total_propagated += 1;
+            }
+        }
+
+        // Region test
+        if !self.accept_region(ra, dec) {
+            self.counters.rejected_region += 1;
+            self.dump_rejected(
+                "region",
+                source_id,
+                [plx, plx_e, ruwe_val as f64, appmag, f64::NAN],
+            );
             return None;
         }
 
         // Distance
         let dist_pc: f64;
-        dist_pc = if self.use_phot_dist {
+        dist_pc = if self.settings.use_phot_dist {
             if phot_dist > 0.0 {
-                phot_dist
+                phot_dist * dist_scale
             } else {
                 -1.0
             }
         } else if has_geodist {
             if geodist_pc > 0.0 {
-                geodist_pc
+                geodist_pc * dist_scale
             } else {
                 -1.0
             }
         } else {
-            1000.0 / plx
+            match self.settings.dist_estimator.distance(plx, plx_e) {
+                Some(d) => d,
+                None if must_load => 1000.0 / plx,
+                None => {
+                    self.counters.rejected_dist_estimator += 1;
+                    self.dump_rejected(
+                        "distance estimator",
+                        source_id,
+                        [plx, plx_e, ruwe_val as f64, appmag, f64::NAN],
+                    );
+                    return None;
+                }
+            }
         };
 
         // Distance test
         if !must_load && !self.accept_distance(dist_pc) {
-            self.rejected_dist += 1;
+            self.counters.rejected_dist += 1;
 
             if !dist_pc.is_finite() {
-                self.rejected_dist_inf += 1;
+                self.counters.rejected_dist_inf += 1;
             }
             if dist_pc <= 0.0 {
-                self.rejected_dist_neg += 1;
+                self.counters.rejected_dist_neg += 1;
             }
+            self.dump_rejected(
+                "distance",
+                source_id,
+                [plx, plx_e, ruwe_val as f64, appmag, dist_pc],
+            );
 
             return None;
         }
+
+        // Absolute magnitude and volume-complete limits, without extinction.
+        // They need a distance, which must-load stars may not have, and
+        // those are kept like in the distance test.
+        let has_dist = dist_pc.is_finite() && dist_pc > 0.0;
+        if has_dist
+            && (self.settings.mag_limits.has_absolute() || self.settings.mag_limits.has_volume())
+        {
+            let absmag_obs = appmag - 5.0 * f64::log10(dist_pc) + 5.0;
+            if !self.settings.mag_limits.accept_absolute(absmag_obs) {
+                self.counters.rejected_absmaglim += 1;
+                self.dump_rejected(
+                    "absolute magnitude limits",
+                    source_id,
+                    [plx, plx_e, ruwe_val as f64, appmag, dist_pc],
+                );
+                return None;
+            }
+            if !self.settings.mag_limits.accept_volume(dist_pc, absmag_obs) {
+                self.counters.rejected_volume += 1;
+                self.dump_rejected(
+                    "volume-complete",
+                    source_id,
+                    [plx, plx_e, ruwe_val as f64, appmag, dist_pc],
+                );
+                return None;
+            }
+        }
         let dist: f64 = dist_pc * constants::PC_TO_U;
 
         // Parallax error
-        let plx_err: f32 = parse::parse_f32(splx_e);
-        if plx_err.is_finite() {
-            extra.insert(ColId::plx_err, plx_err);
+        let mut plx_err: f32 = star.plx_err as f32;
+        if !plx_err.is_finite() {
+            plx_err = f32::NAN;
         }
 
         // Name
         let mut name_vec = Vec::new();
-        if !parse::is_empty(snames) {
-            let name_tokens: Vec<&str> = snames.unwrap().split("|").collect();
+        if let Some(names) = &star.names {
+            let name_tokens: Vec<&str> = names.split("|").collect();
             for name_token in name_tokens {
                 name_vec.push(String::from(name_token));
             }
         }
 
-        // RA and DEC
-        let ra: f64 = parse::parse_f64(sra);
-        let dec: f64 = parse::parse_f64(sdec);
-
+        // Cartesian position
         let pos = util::spherical_to_cartesian(
             ra.to_radians(),
             dec.to_radians(),
@@ -780,9 +2060,6 @@ impl Loader {
         );
 
         // Proper motions
-        let mualphastar: f64 = parse::parse_f64(spmra);
-        let mudelta: f64 = parse::parse_f64(spmdec);
-        let radvel: f64 = parse::parse_f64(srv);
         let mut rv_val: f64 = radvel;
         if rv_val.is_nan() {
             rv_val = 0.0;
@@ -798,20 +2075,26 @@ impl Loader {
         );
 
         // Apparent magnitudes
-        let mut ag: f64 = self.get_attribute_or_else(ColId::ag, source_id, parse::parse_f64(sag));
-        let pos_eq: Vector3<f64> = Vector3::new(pos.x, pos.y, pos.z);
-        let pos_gal: Vector3<f64> = self.coord.eq_gal.transform_vector(&pos_eq);
-        let pos_gal_sph = util::cartesian_to_spherical(pos_gal.x, pos_gal.y, pos_gal.z);
-        let b = pos_gal_sph.y;
-        let magcorraux = f64::min(dist_pc, 150.0 / b.sin().abs());
-
-        // Analytical extinction, cap to 3.2
-        if self.mag_corrections == 2 && !ag.is_finite() {
-            ag = f64::min(3.2, magcorraux * 5.9e-4);
-        }
+        let ag: f64 = self.get_attribute_or_else(ColId::ag, source_id, star.ag);
+        let pebr = self.get_attribute_or_else(ColId::ebp_min_rp, source_id, star.ebp_min_rp);
+
+        // Extinction and reddening from the model, if not in the catalog
+        let (model_ag, model_ebr) =
+            if self.settings.mag_corrections == 2 && !(ag.is_finite() && pebr.is_finite()) {
+                let pos_eq: Vector3<f64> = Vector3::new(pos.x, pos.y, pos.z);
+                let pos_gal: Vector3<f64> = self.settings.coord.eq_gal.transform_vector(&pos_eq);
+                let pos_gal_sph = util::cartesian_to_spherical(pos_gal.x, pos_gal.y, pos_gal.z);
+                self.settings
+                    .extinction
+                    .extinction(pos_gal_sph.x, pos_gal_sph.y, dist_pc)
+            } else {
+                (f64::NAN, f64::NAN)
+            };
+        let bp_rp = star.bpmag - star.rpmag;
+        let (ag, ebr) = self.extinction_reddening(ag, pebr, model_ag, model_ebr, bp_rp);
 
         // Apply only if mag_corrections > 0
-        if self.mag_corrections > 0 && ag.is_finite() {
+        if self.settings.mag_corrections > 0 && ag.is_finite() {
             appmag -= ag;
         }
 
@@ -826,57 +2109,23 @@ impl Loader {
             + 5.0;
 
         // Size
-        let pseudo_l = f64::powf(10.0, -0.4 * absmag);
-        let size_fac = constants::PC_TO_M * constants::M_TO_U * 0.15;
-        let size: f32 = f64::min(pseudo_l.powf(0.5) * size_fac, 1e10) as f32;
-
-        // Color
-        let pebr =
-            self.get_attribute_or_else(ColId::ebp_min_rp, source_id, parse::parse_f64(sebp_min_rp));
-        let ebr: f64 = match self.mag_corrections {
-            // No corrections
-            0 => 0.0,
-            // Only form catalog
-            1 => {
-                if pebr.is_finite() {
-                    pebr
-                } else {
-                    0.0
-                }
-            }
-            // From catalog, if not, analytical
-            2 => {
-                if pebr.is_finite() {
-                    pebr
-                } else {
-                    f64::min(1.6, magcorraux * 2.9e-4)
-                }
-            }
-            // Default is zero
-            _ => 0.0,
-        };
+        let size: f32 = util::absmag_to_size(absmag);
 
         // T_eff and color.
         // If color is present and is XP or B-V, convert to T_eff and then to RGB.
         // If not, use T_eff to determine color.
-        let col_idx: f64;
-        let mut teff: f64 = parse::parse_f64(steff);
-        let teff_color;
-        if !parse::is_empty(sbp) && !parse::is_empty(srp) {
-            // XP -> T_eff
-            let bp: f64 = parse::parse_f64(sbp);
-            let rp: f64 = parse::parse_f64(srp);
-            col_idx = bp - rp - ebr;
-            teff_color = color::xp_to_teff(col_idx);
-        } else if !parse::is_empty(sbv) {
+        let mut teff: f64 = star.teff;
+        let teff_color = if bp_rp.is_finite() {
+            // Dereddened XP -> T_eff
+            self.settings.teff_cal.teff(ColorIndex::BpRp, bp_rp - ebr)
+        } else if star.col_idx.is_finite() {
             // B-V -> T_eff
-            col_idx = parse::parse_f64(sbv);
-            teff_color = color::bv_to_teff_ballesteros(col_idx);
+            self.settings.teff_cal.teff(ColorIndex::BV, star.col_idx)
         } else {
-            // Default color index.
-            col_idx = 0.656;
-            teff_color = color::bv_to_teff_ballesteros(col_idx);
-        }
+            // No colour index, use the B-V of the calibration (solar by default)
+            self.counters.total_no_color += 1;
+            self.settings.teff_cal.teff_no_color()
+        };
 
         // If we do not have T_eff from the catalog, use the one computed from the color index.
         if !teff.is_finite() {
@@ -884,16 +2133,17 @@ impl Loader {
         }
 
         // Find RGB from T_eff.
-        let (col_r, col_g, col_b) = color::teff_to_rgb(teff_color);
+        let (col_r, col_g, col_b) = self.settings.color_model.teff_to_rgb(teff_color);
         let color_packed: f32 = color::col_to_f32(col_r as f32, col_g as f32, col_b as f32, 1.0);
 
         // Update counts per mag
         let appmag_clamp = f64::clamp(appmag, 0.0, 21.0) as usize;
-        self.counts_per_mag.borrow_mut()[appmag_clamp] += 1;
+        self.counters.counts_per_mag[appmag_clamp] += 1;
 
-        self.total_loaded += 1;
-        if self.total_loaded % 100000 == 0 {
-            log::debug!("   object {}", self.total_loaded);
+        self.counters.total_loaded += 1;
+        self.counters.epochs.add(epoch);
+        if self.counters.total_loaded % 100000 == 0 {
+            log::debug!("   object {}", self.counters.total_loaded);
         }
         Some(Particle {
             x: pos.x,
@@ -912,11 +2162,61 @@ impl Loader {
             size,
             hip,
             id: source_id,
+            plx_err,
+            ruwe,
             names: name_vec,
-            extra,
         })
     }
 
+    /// Gets the extinction A_G to subtract from the G magnitude (NaN for none) and
+    /// the reddening E(BP-RP) of a star with the given observed BP-RP. The catalog
+    /// values take precedence over the ones of the extinction model, which are only
+    /// used with mag_corrections 2. With the Riello et al. (2021) law, A_0 and
+    /// (BP-RP)_0 are solved from the catalog A_G, else from the catalog E(BP-RP),
+    /// else from the model A_G, and both values are derived from them. Stars with
+    /// A_G and E(BP-RP) in the catalog, or without colour, use the values as they are.
+    fn extinction_reddening(
+        &self,
+        ag: f64,
+        ebr: f64,
+        model_ag: f64,
+        model_ebr: f64,
+        bp_rp: f64,
+    ) -> (f64, f64) {
+        let (ag_used, ebr_used) = match self.settings.mag_corrections {
+            // No corrections
+            0 => (f64::NAN, 0.0),
+            // Only from catalog
+            1 => (ag, if ebr.is_finite() { ebr } else { 0.0 }),
+            // From catalog, if not, from the extinction model
+            2 => (
+                if ag.is_finite() { ag } else { model_ag },
+                if ebr.is_finite() { ebr } else { model_ebr },
+            ),
+            _ => (f64::NAN, 0.0),
+        };
+        if self.settings.ext_law != ExtLaw::Riello2021
+            || self.settings.mag_corrections == 0
+            || (ag.is_finite() && ebr.is_finite())
+        {
+            return (ag_used, ebr_used);
+        }
+        let solved = if ag.is_finite() {
+            photometry::solve_a0(bp_rp, ag)
+        } else if ebr.is_finite() {
+            photometry::solve_a0_from_reddening(bp_rp, ebr)
+        } else {
+            photometry::solve_a0(bp_rp, ag_used)
+        };
+        match solved {
+            Some((a0, bp_rp0)) => {
+                let (a_g, a_bp, a_rp) = photometry::extinctions(a0, bp_rp0);
+                (a_g, a_bp - a_rp)
+            }
+            None => (ag_used, ebr_used),
+        }
+    }
+
     fn accept_magnitude(&self, appmag: f64) -> bool {
         appmag.is_finite()
     }
@@ -925,9 +2225,13 @@ impl Loader {
         if !plx.is_finite() {
             return false;
         } else if appmag < 13.1 {
-            return plx >= 0.0 && plx_e < plx * self.plx_err_bright && plx_e < self.plx_err_cap;
+            return plx >= 0.0
+                && plx_e < plx * self.settings.plx_err_bright
+                && plx_e < self.settings.plx_err_cap;
         } else {
-            return plx >= 0.0 && plx_e < plx * self.plx_err_faint && plx_e < self.plx_err_cap;
+            return plx >= 0.0
+                && plx_e < plx * self.settings.plx_err_faint
+                && plx_e < self.settings.plx_err_cap;
         }
     }
 
@@ -937,6 +2241,15 @@ impl Loader {
         dist_pc.is_finite() && dist_pc > 0.0
     }
 
+    fn accept_region(&self, ra: f64, dec: f64) -> bool {
+        self.settings.regions.is_empty()
+            || self
+                .settings
+                .regions
+                .iter()
+                .any(|r| r.contains(ra.to_radians(), dec.to_radians()))
+    }
+
     fn accept_fidelity(&self, source_id: i64) -> bool {
         let fidelity = self.get_additional(ColId::fidelity, source_id);
         match fidelity {
@@ -960,12 +2273,12 @@ impl Loader {
     }
 
     fn accept_ruwe(&self, ruwe: f32) -> bool {
-        ruwe.is_nan() || self.ruwe_cap.is_nan() || ruwe < self.ruwe_cap
+        ruwe.is_nan() || self.settings.ruwe_cap.is_nan() || ruwe < self.settings.ruwe_cap
     }
 
-    fn get_ruwe(&self, source_id: i64, sruwe: Option<&&str>) -> f32 {
+    fn get_ruwe(&self, source_id: i64, ruwe: f64) -> f32 {
         if self.has_col(ColId::ruwe) {
-            parse::parse_f32(sruwe)
+            ruwe as f32
         } else {
             let ruwe = self.get_additional(ColId::ruwe, source_id);
             match ruwe {
@@ -1003,10 +2316,10 @@ impl Loader {
     }
 
     fn get_additional(&self, col_id: ColId, source_id: i64) -> Option<f64> {
-        if self.additional.is_empty() {
+        if self.settings.additional.is_empty() {
             None
         } else {
-            for entry in &self.additional {
+            for entry in self.settings.additional.iter() {
                 if entry.has_col(col_id) {
                     return entry.get(col_id, source_id);
                 }
@@ -1022,10 +2335,10 @@ impl Loader {
     }
 
     fn has_additional_col(&self, col_id: ColId) -> bool {
-        if self.additional.is_empty() {
+        if self.settings.additional.is_empty() {
             false
         } else {
-            for entry in &self.additional {
+            for entry in self.settings.additional.iter() {
                 if entry.has_col(col_id) {
                     return true;
                 }
@@ -1059,33 +2372,117 @@ impl Loader {
     pub fn report_rejected(&self) {
         log::info!(
             "::: TOTAL LOADED/PROCESSED: {}/{}",
-            self.total_loaded,
-            self.total_processed
+            self.counters.total_loaded,
+            self.counters.total_processed
+        );
+        log::info!(
+            "   - Parallaxes corrected with the Lindegren et al. (2021) zero point: {}",
+            self.counters.total_zeropoint
+        );
+        if self.counters.total_zeropoint_uncalibrated > 0 {
+            log::warn!(
+                "   - Zero points outside the calibrated range (6 <= G <= 21, 1.1 <= nu_eff <= 1.9, 1.24 <= pseudocolour <= 1.72), with clipped G and colour: {}",
+                self.counters.total_zeropoint_uncalibrated
+            );
+        }
+        if self.settings.target_epoch.is_finite() {
+            log::info!(
+                "   - Propagated to epoch J{}: {}",
+                self.settings.target_epoch,
+                self.counters.total_propagated
+            );
+        }
+        log::info!(
+            "   - Without colour index (B-V = {}): {}",
+            self.settings.teff_cal.no_color_bv,
+            self.counters.total_no_color
         );
         log::info!(
             "   - Rejected due to parallax (criteria/negative): {}",
-            self.rejected_plx
+            self.counters.rejected_plx
         );
-        log::info!("            - criteria: {}", self.rejected_plx_crit);
-        log::info!("            - negative: {}", self.rejected_plx_neg);
+        log::info!(
+            "            - criteria: {}",
+            self.counters.rejected_plx_crit
+        );
+        log::info!("            - negative: {}", self.counters.rejected_plx_neg);
         log::info!(
             "   - Rejected due to non-finite magnitude: {}",
-            self.rejected_mag
+            self.counters.rejected_mag
+        );
+        if !self.settings.excess_nsigma.is_nan() {
+            log::info!(
+                "   - Rejected due to BP/RP flux excess factor (> {} sigma): {}",
+                self.settings.excess_nsigma,
+                self.counters.rejected_excess
+            );
+        }
+        log::info!(
+            "   - Rejected due to distance: {}",
+            self.counters.rejected_dist
+        );
+        log::info!(
+            "            - infinite: {}",
+            self.counters.rejected_dist_inf
+        );
+        log::info!(
+            "            - null/negative: {}",
+            self.counters.rejected_dist_neg
+        );
+        log::info!(
+            "   - Rejected by the distance estimator ({}): {}",
+            self.settings.dist_estimator.name(),
+            self.counters.rejected_dist_estimator
         );
-        log::info!("   - Rejected due to distance: {}", self.rejected_dist);
-        log::info!("            - infinite: {}", self.rejected_dist_inf);
-        log::info!("            - null/negative: {}", self.rejected_dist_neg);
         log::info!(
             "   - Rejected due to geo-distance (not present): {}",
-            self.rejected_geodist
+            self.counters.rejected_geodist
         );
         log::info!(
             "   - Rejected due to fidelity (criteria/): {}",
-            self.rejected_fidelity
+            self.counters.rejected_fidelity
         );
         log::info!(
             "   - Rejected due to ruwe (criteria): {}",
-            self.rejected_ruwe
+            self.counters.rejected_ruwe
         );
+        if !self.settings.mag_limits.is_none() {
+            log::info!("   - Magnitude limits: {}", self.settings.mag_limits);
+            log::info!(
+                "            - apparent magnitude: {}",
+                self.counters.rejected_maglim
+            );
+            log::info!(
+                "            - absolute magnitude: {}",
+                self.counters.rejected_absmaglim
+            );
+            log::info!(
+                "            - volume-complete: {}",
+                self.counters.rejected_volume
+            );
+        }
+        if !self.settings.regions.is_empty() {
+            log::info!(
+                "   - Rejected due to sky region: {}",
+                self.counters.rejected_region
+            );
+        }
+        if let Some(filter) = &self.settings.filter {
+            for (clause, count) in filter
+                .clauses()
+                .iter()
+                .zip(self.counters.rejected_filter.iter())
+            {
+                log::info!("   - Rejected by filter clause '{}': {}", clause, count);
+            }
+        }
+        if let Some(dump) = &self.settings.rejected_dump {
+            log::info!(
+                "   - Rejected stars written to {} (sample fraction {}): {}",
+                dump.path,
+                dump.fraction,
+                dump.n_written.lock().unwrap()
+            );
+        }
     }
 }
@@ -2,7 +2,7 @@ use crate::constants;
 use crate::data;
 
 use base_custom::BaseCustom;
-use data::{BoundingBox, Particle, Vec3};
+use data::{BoundingBox, StarStore, Vec3};
 use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::fmt;
@@ -76,14 +76,32 @@ impl Octree {
      * of stars actually added (i.e. not skipped
      * due to being too far) and the depth of the tree.
      **/
-    pub fn generate_octree(&self, list: &Vec<Particle>) -> (usize, usize, u32) {
-        self.start_generation(list);
+    pub fn generate_octree(&self, list: &StarStore) -> (usize, usize, u32) {
+        let (min, max) = list.bounds();
+        let positions = (0..list.len()).map(|i| (list.x[i], list.y[i], list.z[i]));
+        self.generate_octree_from(&min, &max, list.len(), positions)
+    }
+
+    /**
+     * Generates the octree from a stream with the positions
+     * of the stars, sorted by magnitude. The bounds and
+     * the number of stars must be known beforehand. Stars
+     * are identified by their index in the stream.
+     **/
+    pub fn generate_octree_from<I: Iterator<Item = (f64, f64, f64)>>(
+        &self,
+        min: &Vec3,
+        max: &Vec3,
+        cat_size: usize,
+        positions: I,
+    ) -> (usize, usize, u32) {
+        self.start_generation(min, max);
+        let mut positions = positions.peekable();
 
         let mut octree_star_num: usize = 0;
         let mut octree_node_num: usize = 1;
         let mut depth: u32 = 0;
         let mut cat_idx = 0;
-        let cat_size = list.len();
         for level in 0..=MAX_DEPTH {
             log::info!(
                 "Generating level {} ({} stars left)",
@@ -94,11 +112,7 @@ impl Octree {
             let mut n_stars_added_level: usize = 0;
             while cat_idx < cat_size {
                 // Add stars to nodes until we reach max_part
-                let star = list.get(cat_idx).expect("Error getting star");
-
-                let x = star.x;
-                let y = star.y;
-                let z = star.z;
+                let (x, y, z) = *positions.peek().expect("Error getting star");
 
                 let octant_id_op = self.position_octant_id(x, y, z, level);
                 if octant_id_op.is_none() {
@@ -130,6 +144,7 @@ impl Octree {
                     depth = level;
                 }
                 octree_star_num += 1;
+                positions.next();
                 cat_idx += 1;
 
                 // Print every 10 M to stdout
@@ -532,35 +547,11 @@ impl Octree {
      * If 'self.centre_origin' is true, the centre of the octree is
      * forcefully put very close to (0 0 0).
      **/
-    fn start_generation(&self, list: &Vec<Particle>) {
+    fn start_generation(&self, min: &Vec3, max: &Vec3) {
         log::info!("Starting generation of octree");
 
-        let mut min = Vec3::with(1.0e50);
-        let mut max = Vec3::with(-1.0e50);
-
-        for particle in list {
-            // Min
-            if particle.x < min.x {
-                min.x = particle.x;
-            }
-            if particle.y < min.y {
-                min.y = particle.y;
-            }
-            if particle.z < min.z {
-                min.z = particle.z;
-            }
-
-            // Max
-            if particle.x > max.x {
-                max.x = particle.x;
-            }
-            if particle.y > max.y {
-                max.y = particle.y;
-            }
-            if particle.z > max.z {
-                max.z = particle.z;
-            }
-        }
+        let mut min = min.copy();
+        let mut max = max.copy();
         if self.centre_origin {
             // Get the greatest in each dimension
             let mut gt = Vec3::empty();
@@ -2,22 +2,30 @@ extern crate argparse;
 extern crate base_custom;
 
 use std::{
-    cmp::Ordering,
     collections::{HashMap, HashSet},
     path,
+    sync::Arc,
+    thread,
 };
 
-use argparse::{ArgumentParser, Store, StoreTrue};
+use argparse::{ArgumentParser, Collect, Store, StoreTrue};
 
-use constants::NEGATIVE_DIST;
+use color::{ColorModel, TeffCalibration};
 use data::Config;
+use distance::DistEstimator;
+use extinction::Extinction;
+use hipparcos::CatalogFormat;
+use limits::MagLimits;
 use log::LevelFilter;
 use log4rs::append::console::ConsoleAppender;
 use log4rs::append::file::FileAppender;
 use log4rs::config::Config as LogConfig;
 use log4rs::config::{Appender, Root};
 use log4rs::encode::pattern::PatternEncoder;
+use names::Names;
+use photometry::ExtLaw;
 use regex::Regex;
+use secondary::CatalogSpec;
 use std::fs;
 use std::time::Instant;
 
@@ -25,15 +33,32 @@ mod color;
 mod constants;
 mod coord;
 mod data;
+mod distance;
+mod ecsv;
+mod extinction;
+mod filter;
+mod fits;
+mod hipparcos;
+mod limits;
 mod load;
 mod lod;
 mod math;
 mod mem;
+mod merge;
+mod names;
 mod parse;
+mod photometry;
+mod region;
+mod rejected;
+mod secondary;
+mod spill;
+mod table;
 mod tests;
 mod util;
+mod votable;
 mod write;
 mod xmatch;
+mod zeropoint;
 
 /**
  * The main function parses the arguments, loads the Gaia and
@@ -48,12 +73,34 @@ fn main() {
         max_part: 100000,
         ruwe_cap: f32::NAN,
         distpc_cap: 1.0e6,
+        mag_bright: f64::NAN,
+        mag_faint: f64::NAN,
+        absmag_bright: f64::NAN,
+        absmag_faint: f64::NAN,
+        volume_dist: f64::NAN,
         photdist: false,
         plx_err_faint: 10.0,
         plx_err_bright: 10.0,
         plx_zeropoint: 0.0,
         mag_corrections: 2,
+        extinction: "analytical".to_string(),
+        ext_coeffs: "".to_string(),
+        ext_law: "constant".to_string(),
+        g_corrections: false,
+        excess_nsigma: f64::NAN,
+        color_model: "helland".to_string(),
+        color_space: "srgb".to_string(),
+        white_point: "d65".to_string(),
+        teff_cal: "".to_string(),
         allow_negative_plx: false,
+        dist_estimator: "plx".to_string(),
+        dist_length_scale: 1350.0,
+        target_epoch: f64::NAN,
+        xmatch_radius: 1.0,
+        xmatch_max_dmag: f32::INFINITY,
+        merge_policy: "".to_string(),
+        names: "".to_string(),
+        name_priority: "".to_string(),
         centre_origin: false,
         postprocess: false,
         dry_run: false,
@@ -63,9 +110,22 @@ fn main() {
         file_num_cap: -1,
         star_num_cap: -1,
         hip: "".to_string(),
+        hip_format: "csv".to_string(),
+        hip_columns: "".to_string(),
+        hip_plx_err: 1000.0,
+        hip_plx_err_cap: 1000.0,
+        hip_teff_cal: "".to_string(),
         additional: "".to_string(),
+        filter: "".to_string(),
+        regions: Vec::new(),
+        dump_rejected: false,
+        rejected_fraction: 1.0,
         xmatch: "".to_string(),
-        columns: "source_id,ra,dec,plx,ra_err,dec_err,plx_err,pmra,pmdec,radvel,gmag,bpmag,rpmag,ruwe,ref_epoch".to_string(),
+        secondary: Vec::new(),
+        columns: load::DEFAULT_COLUMNS.to_string(),
+        header_columns: false,
+        threads: 1,
+        mem_budget: 0,
     };
     // Parse CLI arguments
     {
@@ -105,18 +165,78 @@ fn main() {
         ap.refer(&mut args.plx_zeropoint).add_option(
             &["--plxzeropoint"],
             Store,
-            "Parallax zero point, so that plx_effective = plx_catalog - plx_zeropoint. Defaults to 0.",
+            "Parallax zero point, so that plx_effective = plx_catalog - plx_zeropoint. Defaults to 0. Stars with nu_eff_used_in_astrometry or pseudocolour columns get the per-star zero point of Lindegren et al. (2021) instead.",
         );
         ap.refer(&mut args.mag_corrections).add_option(
             &["-c", "--magcorrections"],
             Store,
-            "Set the level of magnitude and color corrections (extinction and reddening). 0 for no corrections at all, 1 for corrections only if values are in catalog, 2 to use analytical methods.",
+            "Set the level of magnitude and color corrections (extinction and reddening). 0 for no corrections at all, 1 for corrections only if values are in catalog, 2 to also use the --extinction model for the stars without values in the catalog.",
+        );
+        ap.refer(&mut args.extinction).add_option(
+            &["--extinction"],
+            Store,
+            "Extinction model used with --magcorrections 2: 'analytical' (default) for the slab law A_G = min(3.2, d * 5.9e-4), E(BP-RP) = min(1.6, d * 2.9e-4), with d = min(dist_pc, 150 / |sin b|), or the path of an optionally gzipped 3D dust map file. Dust maps start with a header line, either 'grid nx ny nz x0 y0 z0 step' for a galactic Cartesian grid of extinction densities per pc (x varying fastest), or 'healpix nside nested|ring d1 ... dn' for a galactic HEALPix map of the cumulative extinction up to the distances d1..dn in pc (one line of n values per pixel).",
+        );
+        ap.refer(&mut args.ext_coeffs).add_option(
+            &["--extcoeffs"],
+            Store,
+            "Coefficients to convert the values of the --extinction dust map to A_G and E(BP-RP), like 'ag=2.740,ebr=1.339'. Defaults to the coefficients of E(B-V) maps, 2.740 and 1.339 (Casagrande & VandenBerg 2018).",
+        );
+        ap.refer(&mut args.ext_law).add_option(
+            &["--extlaw"],
+            Store,
+            "Law to compute the extinction A_G and the reddening E(BP-RP) of the stars with BP and RP magnitudes: 'constant' (default) to use the values of the catalog, or else the ones of the --extinction model, as they are, or 'riello2021' to solve the extinction A_0 and the intrinsic colour from the catalog A_G, else the catalog E(BP-RP), else the model A_G, and derive both values from them with the colour- and extinction-dependent coefficients of the Gaia EDR3 passbands (Riello et al. 2021). Stars with both values in the catalog use them as they are. The dereddened colour is used to compute T_eff.",
+        );
+        ap.refer(&mut args.g_corrections).add_option(
+            &["--gcorrections"],
+            StoreTrue,
+            "Apply the corrections of the G magnitudes of Gaia DR3 (Riello et al. 2021): the magnitude term of the bright stars with 6 < G < 13, and the colour-dependent correction of the stars with G >= 13 without a five-parameter astrometric solution (needs the astrometric_params_solved and BP and RP columns). Only G magnitudes from the catalog are corrected.",
+        );
+        ap.refer(&mut args.excess_nsigma).add_option(
+            &["--excesscut"],
+            Store,
+            "Reject the Gaia stars whose corrected BP/RP flux excess factor C* (Riello et al. 2021) is larger, in absolute value, than this number of times its expected scatter at their G magnitude, like 3. Needs the phot_bp_rp_excess_factor and BP and RP columns. Stars without them pass. Disabled by default.",
+        );
+        ap.refer(&mut args.color_model).add_option(
+            &["--colormodel"],
+            Store,
+            "Model to convert effective temperatures to star colours: 'helland' (default) for the curve fit of Tanner Helland, valid between 1000 and 40000 K, or 'planck' to integrate the Planck spectrum against the CIE 1931 colour-matching functions.",
+        );
+        ap.refer(&mut args.color_space).add_option(
+            &["--colorspace"],
+            Store,
+            "Colour space of the 'planck' colour model: 'srgb' (default) for gamma-encoded sRGB, or 'linear' for linear sRGB.",
+        );
+        ap.refer(&mut args.white_point).add_option(
+            &["--whitepoint"],
+            Store,
+            "White point of the 'planck' colour model: 'd65' (default), or the temperature in Kelvin of the blackbody that is rendered white, like 5772 for the Sun.",
+        );
+        ap.refer(&mut args.teff_cal).add_option(
+            &["--teffcal"],
+            Store,
+            "Relations to compute T_eff, and thus the colour, of Gaia stars from their colour indices, like 'bprp=mucciarelli2020,bv=casagrande2010'. Relations for BP-RP are 'jordi2010' (default), 'mucciarelli2020' and 'pecaut2013' (main-sequence table of Pecaut & Mamajek). Relations for B-V are 'ballesteros2012' (default), 'casagrande2010' and 'pecaut2013'. Stars without colour index get the B-V given as 'nocolor=<B-V>' (defaults to the solar 0.656) with the B-V relation, and are counted in the log.",
         );
         ap.refer(&mut args.allow_negative_plx).add_option(
             &["--allownegativeplx"],
             StoreTrue,
             "Allow negative parallaxes (and set them to 0.04 mas, or 25 Kpc) for Gaia stars.",
         );
+        ap.refer(&mut args.dist_estimator).add_option(
+            &["--distestimator"],
+            Store,
+            "Estimator used to compute distances from parallaxes: 'plx' for the inverse parallax, 'edsd' for the mode of the posterior with an exponentially decreasing space density prior (which also works for negative parallaxes), or 'lk' for the Lutz-Kelker corrected estimate. Stars for which the estimator gives no distance are rejected. Defaults to 'plx'.",
+        );
+        ap.refer(&mut args.dist_length_scale).add_option(
+            &["--distlengthscale"],
+            Store,
+            "Length scale in parsecs of the prior of the 'edsd' distance estimator. Defaults to 1350.",
+        );
+        ap.refer(&mut args.target_epoch).add_option(
+            &["--targetepoch"],
+            Store,
+            "Epoch (Julian year, e.g. 2016.0) to propagate the positions, parallaxes, proper motions and radial velocities of all stars to, using rigorous linear space motion. Gaia stars are propagated from their ref_epoch column (2016.0 if missing) and Hipparcos stars from 1991.25. By default, stars are kept at their reference epochs. The epoch of the positions is written to catalog.json in the output directory when all stars share it, and marked as \"mixed\" otherwise (e.g. Hipparcos stars merged without --targetepoch, or stars without proper motions).",
+        );
         ap.refer(&mut args.postprocess).add_option(
             &["-p", "--postprocess"],
             StoreTrue,
@@ -140,13 +260,63 @@ fn main() {
         ap.refer(&mut args.hip).add_option(
             &["--hip"],
             Store,
-            "Absolute or relative location of the Hipparcos catalog (only csv supported).",
+            "Absolute or relative location of the Hipparcos catalog.",
+        );
+        ap.refer(&mut args.hip_format).add_option(
+            &["--hipformat"],
+            Store,
+            "Format of the Hipparcos catalog: csv (comma-separated with a header row and the columns hip,names,ra,dec,plx,plx_err,pmra,pmdec,gmag,col_idx), esa1997 (hip_main.dat of the original ESA 1997 catalogue) or vanleeuwen2007 (hip2.dat of the van Leeuwen 2007 reduction). Defaults to csv.",
+        );
+        ap.refer(&mut args.hip_columns).add_option(
+            &["--hipcolumns"],
+            Store,
+            "Comma-separated list of column names, in order, of the Hipparcos catalog. Overrides the columns of --hipformat. Names may have a unit in brackets, like ra[rad], and unused columns are named 'empty'.",
+        );
+        ap.refer(&mut args.hip_plx_err).add_option(
+            &["--hipplxerr"],
+            Store,
+            "Parallax error threshold for Hipparcos stars, where filter [plx_err/plx < hipplxerr] is enforced. Defaults to 1000 (no filter).",
+        );
+        ap.refer(&mut args.hip_plx_err_cap).add_option(
+            &["--hipplxerrcap"],
+            Store,
+            "Maximum parallax error in mas of Hipparcos stars. Defaults to 1000 (no filter).",
+        );
+        ap.refer(&mut args.hip_teff_cal).add_option(
+            &["--hipteffcal"],
+            Store,
+            "Relations to compute T_eff from the colour indices of Hipparcos stars, as in --teffcal.",
         );
         ap.refer(&mut args.distpc_cap).add_option(
             &["--distcap"],
             Store,
             "Maximum distance in parsecs. Stars beyond this limit are ignored.",
         );
+        ap.refer(&mut args.mag_bright).add_option(
+            &["--magbright"],
+            Store,
+            "Bright apparent magnitude limit. Stars brighter than this are ignored, in all catalogs.",
+        );
+        ap.refer(&mut args.mag_faint).add_option(
+            &["--magfaint"],
+            Store,
+            "Faint apparent magnitude limit. Stars fainter than this are ignored, in all catalogs.",
+        );
+        ap.refer(&mut args.absmag_bright).add_option(
+            &["--absmagbright"],
+            Store,
+            "Bright absolute magnitude limit, computed from the apparent magnitude and the distance without extinction. Stars brighter than this are ignored, in all catalogs.",
+        );
+        ap.refer(&mut args.absmag_faint).add_option(
+            &["--absmagfaint"],
+            Store,
+            "Faint absolute magnitude limit, computed from the apparent magnitude and the distance without extinction. Stars fainter than this are ignored, in all catalogs.",
+        );
+        ap.refer(&mut args.volume_dist).add_option(
+            &["--volcomplete"],
+            Store,
+            "Volume-complete mode: distance in parsecs up to which the catalog must be complete given the --magfaint limit. Only stars within this distance that would still be brighter than --magfaint at it are kept.",
+        );
         ap.refer(&mut args.photdist).add_option(
             &["--photdist"],
             StoreTrue,
@@ -155,12 +325,62 @@ fn main() {
         ap.refer(&mut args.additional).add_option(
             &["--additional"],
             Store,
-            "Comma-separated list of files or folders with optionally gzipped csv files containing additional columns (matched by id) of the main catalog. The first column must contain the Gaia source_id.",
+            "Comma-separated list of files or folders with optionally gzipped csv or FITS files containing additional columns (matched by id) of the main catalog. The first column of csv files must contain the Gaia source_id.",
+        );
+        ap.refer(&mut args.filter).add_option(
+            &["--filter"],
+            Store,
+            "Expression to filter the stars of the main catalog with, like 'ruwe < 1.4 && (plx/plx_err > 10 || gmag < 12)'. It may use any loaded or additional column, the operators + - * / < <= > >= == != && || ! and the functions abs, sqrt, log10 and isfinite. Rejections are reported for each clause of the top-level &&.",
+        );
+        ap.refer(&mut args.regions).add_option(
+            &["--region"],
+            Collect,
+            "Sky region to restrict all catalogs to, as kind:values with angles in degrees: cone:ra,dec,radius, box:ra_min,ra_max,dec_min,dec_max (ra_min > ra_max wraps around RA=0), polygon:ra1,dec1,ra2,dec2,ra3,dec3,... (smaller than a hemisphere) or healpix:nside,nested|ring,pixel,... (pixels may be ranges like 100-120). May be given several times, in which case stars in any of the regions are loaded. Regions apply to the positions at --targetepoch, if given.",
+        );
+        ap.refer(&mut args.dump_rejected).add_option(
+            &["--dumprejected"],
+            StoreTrue,
+            "Write the stars of the main catalog rejected by the loader to rejected.csv.gz in the output directory, with their source_id, the rejection reason and their plx, plx_err, ruwe, appmag and dist_pc values (empty if not computed yet when rejected).",
+        );
+        ap.refer(&mut args.rejected_fraction).add_option(
+            &["--rejectedfraction"],
+            Store,
+            "Fraction of the rejected stars to write with --dumprejected, in [0, 1]. The sample depends only on the source_id, so it is the same in every run. Defaults to 1 (all).",
         );
         ap.refer(&mut args.xmatch).add_option(
             &["--xmatchfile"],
             Store,
-            "Crossmatch file between Gaia and Hipparcos, containing two columns: source_id and hip.",
+            "Crossmatch file between Gaia and Hipparcos, containing two columns: source_id and hip. If not given, Gaia and Hipparcos stars are crossmatched by position (see --xmatchradius and --xmatchmaxdmag).",
+        );
+        ap.refer(&mut args.xmatch_radius).add_option(
+            &["--xmatchradius"],
+            Store,
+            "Search radius in arcseconds of the positional crossmatch between Gaia and Hipparcos, used when there is no --xmatchfile. Hipparcos positions are propagated to the Gaia epoch before matching. Defaults to 1.",
+        );
+        ap.refer(&mut args.xmatch_max_dmag).add_option(
+            &["--xmatchmaxdmag"],
+            Store,
+            "Maximum magnitude difference between Gaia and Hipparcos stars in the positional crossmatch. Hipparcos Hp and V magnitudes differ from Gaia G depending on the colour, so there is no magnitude check by default. Only use it with catalogs whose magnitudes are in G.",
+        );
+        ap.refer(&mut args.merge_policy).add_option(
+            &["--mergepolicy"],
+            Store,
+            "Comma-separated list of field=rule pairs that set how Gaia and Hipparcos stars are merged. Fields are position, distance, photometry, kinematics and names. Rules are gaia, secondary (the Hipparcos or secondary catalog star), best-error (smallest parallax error) and weighted-mean (inverse-variance weighted with the parallax errors; for names, keep both; magnitudes are in different bands (G, and Hp or V), so photometry is not averaged and comes from the star with the smallest parallax error). All fields default to best-error, except names, which default to secondary.",
+        );
+        ap.refer(&mut args.secondary).add_option(
+            &["--secondary"],
+            Collect,
+            "Secondary catalog to merge with Gaia, like Tycho-2 or a list of nearby stars, as a ';'-separated list of key=value pairs: name and path (required; a file, or a directory whose csv, txt and dat files are all loaded, including parts like tyc2.dat.00), format (a --hipformat, defaults to csv), columns (as in --columns; csv files without columns use their header row), header (true or false), epoch (defaults to 2000), plxerr and plxerrcap (as in --hipplxerr and --hipplxerrcap), xmatchfile, xmatchradius, xmatchmaxdmag and mergepolicy (as in the Hipparcos options), and teffcal (as in --teffcal, with ',' between the relations). Stars are identified by their source_id column, or their hip column. May be given several times. Catalogs are merged in precedence order: Hipparcos first, then the secondary catalogs in the given order. A Gaia star is merged with its counterpart in the first catalog that has one.",
+        );
+        ap.refer(&mut args.names).add_option(
+            &["--names"],
+            Store,
+            "Comma-separated list of kind=file pairs with star names to add, like 'proper=iau.csv,hd=hd.csv.gz'. Kinds are proper (IAU WGSN names), bayer, flamsteed, hr, hd, gliese, hip, gaia and other. Files are optionally gzipped csv or tsv files with a header row containing a source_id or hip column and a name column. Several names in a row are separated by '|'.",
+        );
+        ap.refer(&mut args.name_priority).add_option(
+            &["--namepriority"],
+            Store,
+            "Comma-separated list of name kinds (see --names) in the order the names of each star are sorted. Names already in the catalogs are classified by their prefix, and duplicates are removed. Kinds not in the list go last. Defaults to proper,other,bayer,flamsteed,hr,hd,gliese,hip,gaia.",
         );
         ap.refer(&mut args.ruwe_cap).add_option(
             &["--ruwe"],
@@ -170,7 +390,22 @@ fn main() {
         ap.refer(&mut args.columns).add_option(
             &["--columns"],
             Store,
-            "Comma-separated list of column names, in order, of the Gaia catalog.",
+            "Comma-separated list of column names, in order, of the Gaia catalog. Parquet, FITS, VOTable and ECSV files ignore this list, as their columns are matched by name (and converted using the units in the header, if any).",
+        );
+        ap.refer(&mut args.header_columns).add_option(
+            &["--headercolumns"],
+            StoreTrue,
+            "Build the column list of each csv file from its header row, instead of using --columns. Unrecognised column names are reported and ignored.",
+        );
+        ap.refer(&mut args.threads).add_option(
+            &["--threads"],
+            Store,
+            "Number of threads used to load the input files. Use 0 to use all available cores. Defaults to 1.",
+        );
+        ap.refer(&mut args.mem_budget).add_option(
+            &["--membudget"],
+            Store,
+            "Memory budget in MB for the loaded stars. If set, stars are spilled to sorted runs in the output directory whenever the budget is exceeded, and the octree is generated from a merge of these runs. Defaults to 0 (everything in memory).",
         );
         ap.refer(&mut args.file_num_cap).add_option(
             &["--filescap"],
@@ -246,297 +481,462 @@ fn main() {
     assert!(input_path.exists(), "Input directory does not exist.");
     assert!(input_path.is_dir(), "Input directory is not a directory.");
 
+    // Distance estimator for Gaia parallaxes
+    let dist_estimator = DistEstimator::from_str(&args.dist_estimator, args.dist_length_scale)
+        .unwrap_or_else(|| panic!("Error: unknown distance estimator: {}", args.dist_estimator));
+    log::info!("Distance estimator: {}", dist_estimator);
+    let mag_limits = MagLimits::new(
+        args.mag_bright,
+        args.mag_faint,
+        args.absmag_bright,
+        args.absmag_faint,
+        args.volume_dist,
+    )
+    .unwrap_or_else(|e| panic!("Error: invalid magnitude limits: {}", e));
+    log::info!("Magnitude limits: {}", mag_limits);
+    let extinction = Arc::new(
+        Extinction::from_str(&args.extinction, &args.ext_coeffs)
+            .unwrap_or_else(|e| panic!("Error loading extinction model: {}", e)),
+    );
+    if args.mag_corrections == 2 {
+        log::info!("Extinction model: {}", extinction);
+    }
+    let ext_law = ExtLaw::from_str(&args.ext_law)
+        .unwrap_or_else(|| panic!("Error: unknown extinction law: {}", args.ext_law));
+    log::info!("Extinction law: {:?}", ext_law);
+    let color_model = ColorModel::from_str(&args.color_model, &args.color_space, &args.white_point)
+        .unwrap_or_else(|e| panic!("Error: invalid colour model: {}", e));
+    if color_model == ColorModel::Helland {
+        log::info!("Colour model: {}", args.color_model);
+    } else {
+        log::info!(
+            "Colour model: {} ({}, white point {})",
+            args.color_model,
+            args.color_space,
+            args.white_point
+        );
+    }
+    let teff_cal = TeffCalibration::from_str(&args.teff_cal)
+        .unwrap_or_else(|e| panic!("Error: invalid T_eff calibration: {}", e));
+    log::info!(
+        "T_eff relations: BP-RP {:?}, B-V {:?}",
+        teff_cal.bp_rp,
+        teff_cal.b_v
+    );
+    if args.g_corrections {
+        log::info!("Applying the Gaia G magnitude corrections");
+    }
+    if !args.excess_nsigma.is_nan() {
+        log::info!(
+            "BP/RP flux excess factor cut at {} sigma",
+            args.excess_nsigma
+        );
+    }
+    if args.target_epoch.is_finite() {
+        log::info!("Propagating stars to epoch J{}", args.target_epoch);
+    }
+
+    // Policy to merge Gaia and Hipparcos stars
+    let merge_policy = merge::MergePolicy::from_str(&args.merge_policy)
+        .unwrap_or_else(|e| panic!("Error: invalid merge policy: {}", e));
+
+    // Names from the lookup files, ordered by priority
+    let mut names = if args.names.is_empty() && args.name_priority.is_empty() {
+        None
+    } else {
+        let mut names = Names::new(&args.name_priority)
+            .unwrap_or_else(|e| panic!("Error: invalid name priority: {}", e));
+        names
+            .load_files(&args.names)
+            .unwrap_or_else(|e| panic!("Error loading name files: {}", e));
+        Some(names)
+    };
+
     if args.input.len() > 0 {
         let start = Instant::now();
 
         //
-        // HIP - For hipparcos we only support the columns in that order
+        // Secondary catalogs, in precedence order: Hipparcos first,
+        // then the ones given with --secondary
         //
-        let mut loader_hip = load::Loader::new(
-            Regex::new(r",").unwrap(),
-            1,
-            50000000,
-            0.0,
-            args.ruwe_cap,
-            1e9,
-            1000.0,
-            1000.0,
-            1000.0,
-            false,
-            args.mag_corrections,
-            true,
-            None,
-            "",
-            "hip,names,ra,dec,plx,plx_err,pmra,pmdec,gmag,col_idx",
-        );
-        // Actually load hipparcos
-        let mut list_hip = Vec::new();
+        let mut specs = Vec::new();
         if args.hip.len() > 0 {
-            println!("Load hip: {}", &args.hip);
-            let start_hip = Instant::now();
-            list_hip = loader_hip
-                .load_dir(&args.hip)
-                .expect("Error loading HIP data.");
-            let time_hip = start_hip.elapsed();
-            log::info!(
-                "{} particles loaded form HIP in {:?}",
-                list_hip.len(),
-                time_hip
+            let mut format = CatalogFormat::from_str(&args.hip_format).unwrap_or_else(|| {
+                panic!(
+                    "Error: unknown Hipparcos format '{}', must be one of {:?}",
+                    args.hip_format,
+                    CatalogFormat::NAMES
+                )
+            });
+            if !args.hip_columns.is_empty() {
+                format.columns = args.hip_columns.clone();
+            }
+            specs.push(CatalogSpec {
+                name: "hip".to_string(),
+                path: args.hip.clone(),
+                format,
+                epoch: constants::HIP_EPOCH,
+                plx_err: args.hip_plx_err,
+                plx_err_cap: args.hip_plx_err_cap,
+                xmatch_file: args.xmatch.clone(),
+                xmatch_radius: args.xmatch_radius,
+                xmatch_max_dmag: args.xmatch_max_dmag,
+                header_columns: false,
+                policy: merge_policy,
+                teff_cal: TeffCalibration::from_str(&args.hip_teff_cal).unwrap_or_else(|e| {
+                    panic!("Error: invalid Hipparcos T_eff calibration: {}", e)
+                }),
+            });
+        }
+        for spec in &args.secondary {
+            specs.push(
+                CatalogSpec::from_str(spec)
+                    .unwrap_or_else(|e| panic!("Error: invalid secondary catalog: {}", e)),
             );
         }
-        mem::log_mem();
 
-        // Load Hip-Gaia cross-match file
-        // All stars with Hip counter-part are added to the
+        // Load the secondary catalogs and their cross-match files.
+        // All stars with a counterpart are added to the
         // must_load list, which is later passed into the loader
+        let mut loaders = Vec::new();
+        let mut lists = Vec::new();
+        let mut xmatch_maps = Vec::new();
         let mut must_load = HashSet::new();
-        let mut xmatch_map = HashMap::new();
-        if args.hip.len() > 0 && args.xmatch.len() > 0 {
-            xmatch::load_xmatch(&args.xmatch, &mut xmatch_map);
-            if !xmatch_map.is_empty() {
-                let keys = xmatch_map.keys();
-                for key in keys {
-                    must_load.insert(*key);
+        for spec in &specs {
+            let mut loader = load::Loader::new(load::LoaderConfig {
+                sep: Regex::new(spec.format.sep).unwrap(),
+                max_records: 50000000,
+                ruwe_cap: args.ruwe_cap,
+                distpc_cap: 1e9,
+                plx_err_faint: spec.plx_err,
+                plx_err_bright: spec.plx_err,
+                plx_err_cap: spec.plx_err_cap,
+                mag_corrections: args.mag_corrections,
+                extinction: extinction.clone(),
+                ext_law,
+                color_model,
+                teff_cal: spec.teff_cal,
+                allow_negative_plx: true,
+                mag_limits,
+                ref_epoch: spec.epoch,
+                target_epoch: args.target_epoch,
+                regions: args.regions.clone(),
+                columns: spec.format.columns.clone(),
+                has_header: spec.format.header,
+                header_columns: spec.header_columns,
+                ..Default::default()
+            });
+            println!("Load {}: {}", spec.name, spec.path);
+            log::info!(
+                "Format of {}: {}, columns: {}",
+                spec.name,
+                spec.format.name,
+                if spec.header_columns {
+                    "from header"
+                } else {
+                    &spec.format.columns
                 }
+            );
+            let start_catalog = Instant::now();
+            let list = loader
+                .load_dir(&spec.path)
+                .expect("Error loading secondary catalog.");
+            log::info!(
+                "{} particles loaded form {} in {:?}",
+                list.len(),
+                spec.name,
+                start_catalog.elapsed()
+            );
+            mem::log_mem();
+
+            let xmatch_map = load_catalog_xmatch(spec, &args.output);
+            if let Some(map) = &xmatch_map {
+                must_load.extend(map.keys());
             }
+            xmatch_maps.push(xmatch_map);
+            loaders.push(loader);
+            lists.push(list);
         }
         mem::log_mem();
 
+        // Without cross-match file, match the loaded Gaia stars by position.
+        // If stars are propagated to a target epoch, all catalogs are already
+        // at the same epoch.
+        let catalogs = specs
+            .iter()
+            .zip(&lists)
+            .zip(xmatch_maps)
+            .map(|((spec, list), xmatch_map)| {
+                let xmatch = match xmatch_map {
+                    Some(map) => xmatch::Xmatch::Table(map),
+                    None => {
+                        let dt = if args.target_epoch.is_finite() {
+                            0.0
+                        } else {
+                            constants::GAIA_EPOCH - spec.epoch
+                        };
+                        xmatch::Xmatch::Positional(xmatch::PositionalXmatch::new(
+                            list,
+                            spec.xmatch_radius,
+                            spec.xmatch_max_dmag,
+                            dt,
+                        ))
+                    }
+                };
+                xmatch::CatalogMerger::new(&spec.name, list, xmatch, spec.policy)
+            })
+            .collect();
+        let mut merger = xmatch::Merger::new(catalogs);
+
         //
         // GAIA - Load Gaia DRx catalog, the columns come from CLI arguments
         //
-        let mut loader_gaia = load::Loader::new(
-            Regex::new(r"\s+|,").unwrap(),
-            args.file_num_cap,
-            args.star_num_cap,
-            args.plx_zeropoint,
-            args.ruwe_cap,
+        let n_threads = if args.threads == 0 {
+            thread::available_parallelism().map_or(1, |n| n.get())
+        } else {
+            args.threads
+        };
+        let rejected_dump = if args.dump_rejected {
+            let dump = rejected::RejectedDump::new(
+                &format!("{}/rejected.csv.gz", args.output),
+                args.rejected_fraction,
+            )
+            .unwrap_or_else(|e| panic!("Error creating rejected stars file: {}", e));
+            Some(dump)
+        } else {
+            None
+        };
+        let mut loader_gaia = load::Loader::new(load::LoaderConfig {
+            max_files: args.file_num_cap,
+            max_records: args.star_num_cap,
+            plx_zeropoint: args.plx_zeropoint,
+            ruwe_cap: args.ruwe_cap,
+            distpc_cap: args.distpc_cap,
+            plx_err_faint: args.plx_err_faint,
+            plx_err_bright: args.plx_err_bright,
+            use_phot_dist: args.photdist,
+            mag_corrections: args.mag_corrections,
+            extinction: extinction.clone(),
+            ext_law,
+            g_corrections: args.g_corrections,
+            excess_nsigma: args.excess_nsigma,
+            color_model,
+            teff_cal,
+            allow_negative_plx: args.allow_negative_plx,
+            dist_estimator,
+            mag_limits,
+            target_epoch: args.target_epoch,
+            must_load: Some(must_load),
+            additional: args.additional.clone(),
+            filter: args.filter.clone(),
+            regions: args.regions.clone(),
+            rejected_dump,
+            columns: args.columns.clone(),
+            header_columns: args.header_columns,
+            n_threads,
+            ..Default::default()
+        });
+
+        let octree = lod::Octree::from_params(
+            args.max_part,
+            args.postprocess,
+            args.child_count,
+            args.parent_count,
             args.distpc_cap,
-            args.plx_err_faint,
-            args.plx_err_bright,
-            1.0,
-            args.photdist,
-            args.mag_corrections,
-            args.allow_negative_plx,
-            Some(must_load),
-            &args.additional,
-            &args.columns,
-        );
-
-        // Actually load the catalog
-        let start_gaia = Instant::now();
-        let list_gaia = loader_gaia
-            .load_dir(&args.input)
-            .expect("Error loading Gaia data");
-        loader_gaia.report_rejected();
-        let time_gaia = start_gaia.elapsed();
-        log::info!(
-            "{} particles loaded form Gaia in {:?}",
-            list_gaia.len(),
-            time_gaia,
+            args.centre_origin,
         );
-        mem::log_mem();
+        let (num_octants, num_stars, depth);
+        let (time_load, time_gen, time_write);
+        let main_list_len;
 
-        //
-        // Merge Gaia and Hipparcos
-        //
-        let mut hip_map: HashMap<i32, &data::Particle> = HashMap::new();
-        let mut main_list = Vec::new();
-        let mut hip_added = HashSet::new();
-        for hip_star in &list_hip {
-            hip_map.insert(hip_star.hip, hip_star);
-        }
-        log::info!("{} stars added to hip_map", hip_map.len());
-        let mut no_hit = 0;
-        let mut hit = 0;
-        let mut gaia_wins = 0;
-        let mut hip_wins = 0;
-
-        for gaia_star in list_gaia {
-            if !xmatch_map.contains_key(&gaia_star.id) {
-                // No hit, add directly to main list
-                main_list.push(gaia_star);
-                no_hit += 1;
-            } else {
-                // Hit, merge
-                let hip_id = xmatch_map.get(&gaia_star.id).unwrap();
-                if hip_map.contains_key(hip_id) {
-                    hip_added.insert(hip_id);
-
-                    let hip_star = hip_map.get(&hip_id).unwrap();
-                    let gaia_plx_e = gaia_star.get_extra(load::ColId::plx_err);
-                    let hip_plx_e = hip_star.get_extra(load::ColId::plx_err);
-
-                    if gaia_plx_e <= hip_plx_e {
-                        //log::info!("Gaia wins: {} <= {}", gaia_plx_e, hip_plx_e);
-                        gaia_wins += 1;
-
-                        let mut size = gaia_star.size;
-                        let mut pos_gaia = data::Vec3::new(gaia_star.x, gaia_star.y, gaia_star.z);
-                        let negative_dist = f64::abs(pos_gaia.len() - NEGATIVE_DIST) < 1e-10;
-                        if negative_dist {
-                            // Negative distance in gaia star
-                            // use gaia 2D position, HIP distance and name
-
-                            // Fetch Gaia RA/DEC
-                            let gaia_sph =
-                                util::cartesian_to_spherical(pos_gaia.x, pos_gaia.y, pos_gaia.z);
-                            let gaia_ra = gaia_sph.x;
-                            let gaia_dec = gaia_sph.y;
-
-                            // Fetch HIP distance
-                            let pos_hip = data::Vec3::new(hip_star.x, hip_star.y, hip_star.z);
-                            let hip_sph =
-                                util::cartesian_to_spherical(pos_hip.x, pos_hip.y, pos_hip.z);
-                            let hip_dist = hip_sph.z;
-
-                            // Compute new cartesian position
-                            pos_gaia.set_from(&util::spherical_to_cartesian(
-                                gaia_ra, gaia_dec, hip_dist,
-                            ));
-
-                            size = hip_star.size;
-                        }
-
-                        // Merged star
-                        let mut star = hip_star.copy();
-                        star.id = gaia_star.id;
-                        // Pos
-                        star.x = pos_gaia.x;
-                        star.y = pos_gaia.y;
-                        star.z = pos_gaia.z;
-                        // Vel vector
-                        star.pmx = gaia_star.pmx;
-                        star.pmy = gaia_star.pmy;
-                        star.pmz = gaia_star.pmz;
-                        // Pm
-                        star.mualpha = gaia_star.mualpha;
-                        star.mudelta = gaia_star.mudelta;
-                        star.radvel = gaia_star.radvel;
-                        // Mag
-                        star.appmag = gaia_star.appmag;
-                        star.absmag = gaia_star.absmag;
-                        // Col
-                        star.col = gaia_star.col;
-                        // Size
-                        star.size = size;
-                        // Teff
-                        star.teff = gaia_star.teff;
-
-                        main_list.push(star);
-                    } else {
-                        //log::info!("Hip wins: {} <= {}", hip_plx_e, gaia_plx_e);
-                        main_list.push(hip_star.copy());
-                        hip_wins += 1;
-                    }
-                }
-                hit += 1;
+        if args.mem_budget == 0 {
+            // Actually load the catalog
+            let start_gaia = Instant::now();
+            let list_gaia = loader_gaia
+                .load_dir(&args.input)
+                .expect("Error loading Gaia data");
+            loader_gaia.report_rejected();
+            let time_gaia = start_gaia.elapsed();
+            log::info!(
+                "{} particles loaded form Gaia in {:?}",
+                list_gaia.len(),
+                time_gaia,
+            );
+            mem::log_mem();
+
+            //
+            // Merge Gaia and the secondary catalogs
+            //
+            let mut main_list = data::StarStore::new();
+            merger.merge(&list_gaia, &mut main_list);
+            merger.add_rest(&mut main_list);
+            merger.log_stats();
+            std::mem::drop(merger);
+            if let Some(names) = names.as_mut() {
+                names.apply(&mut main_list);
+                names.log_stats();
             }
-        }
+            std::mem::drop(names);
+
+            // Drop gaia and secondary lists
+            std::mem::drop(list_gaia);
+            std::mem::drop(lists);
+            mem::log_mem();
+
+            log::info!("{} stars in the final list.", main_list.len());
+            time_load = start.elapsed();
 
-        // Add rest of hip
-        for hip_star in &list_hip {
-            if !hip_added.contains(&hip_star.hip) {
-                main_list.push(hip_star.copy());
+            if main_list.is_empty() {
+                log::info!("No stars were loaded, aborting.");
+                std::process::exit(1);
             }
-        }
-        log::info!(
-            "{} hits ({} gaia wins, {} hip wins), {} no-hits.",
-            hit,
-            gaia_wins,
-            hip_wins,
-            no_hit
-        );
 
-        // Drop hip lists
-        std::mem::drop(list_hip);
-        mem::log_mem();
+            //
+            // Actually generate LOD octree
+            //
+            let start_gen = Instant::now();
+            // Remove stars with distance > dist_cap
+            let (n_too_far, n_close_stars, n_gmag_nan) =
+                filter_distance(&mut main_list, args.distpc_cap);
+            log_distance_filter(n_too_far, n_close_stars, n_gmag_nan, args.distpc_cap);
 
-        log::info!("{} stars in the final list.", main_list.len());
-        let time_load = start.elapsed();
+            mem::log_mem();
 
-        if main_list.is_empty() {
-            log::info!("No stars were loaded, aborting.");
-            std::process::exit(1);
-        }
+            log::info!(
+                "Sorting list by magnitude with {} objects.",
+                main_list.len()
+            );
+            main_list.sort_by_absmag();
+            log::info!("List sorted in {:?}.", start_gen.elapsed());
+            mem::log_mem();
 
-        //
-        // Actually generate LOD octree
-        //
-        let start_gen = Instant::now();
-        let len_before = main_list.len();
-        let mut n_close_stars: u64 = 0;
-        let mut n_gmag_nan: u64 = 0;
-        // Remove stars with distance > dist_cap
-        main_list.retain(|s| {
-            let dist_pc: f64 = (s.x * s.x + s.y * s.y + s.z * s.z).sqrt() * constants::U_TO_PC;
-            if dist_pc <= 5.0 {
-                n_close_stars += 1;
-            }
-            if !s.appmag.is_finite() {
-                n_gmag_nan += 1;
-            }
+            time_gen = start_gen.elapsed();
 
-            dist_pc <= args.distpc_cap
-        });
-        log::info!(
-            "Rejected {} stars due to being too far (cap = {} pc).",
-            len_before - main_list.len(),
-            args.distpc_cap
-        );
-        log::info!("   - Found {} close stars (dist <= 5 pc).", n_close_stars,);
-        log::info!("   - Found {} with non-finite Gmag.", n_gmag_nan,);
+            (num_octants, num_stars, depth) = octree.generate_octree(&main_list);
+            log::info!(
+                "Octree generated with {} octants and {} stars ({} skipped) in {:?}.",
+                num_octants,
+                num_stars,
+                main_list.len() - num_stars,
+                start_gen.elapsed()
+            );
+            octree.print();
+            mem::log_mem();
 
-        mem::log_mem();
+            //
+            // Write tree and particles
+            //
+            let start_write = Instant::now();
 
-        log::info!(
-            "Sorting list by magnitude with {} objects.",
-            main_list.len()
-        );
-        main_list.sort_by(|a, b| {
-            let order = a.absmag.partial_cmp(&b.absmag);
-            match order {
-                Some(val) => val,
-                None => Ordering::Equal,
+            // Write
+            main_list_len = main_list.len() as f32;
+            if !args.dry_run {
+                // Write only if not dry_run
+                write::write_metadata(&octree, &args.output);
+                write::write_catalog_info(catalog_epochs(&loader_gaia, &loaders), &args.output);
+                write::write_particles_mmap(&octree, main_list, &args.output);
             }
-        });
-        log::info!("List sorted in {:?}.", start_gen.elapsed());
-        mem::log_mem();
+            time_write = start_write.elapsed();
+        } else {
+            //
+            // Out-of-core mode: load the catalog in chunks, merge them with
+            // Hipparcos, and spill them to disk as sorted runs
+            //
+            let max_stars = args.mem_budget * 1_000_000 / data::STAR_SIZE;
+            log::info!(
+                "Memory budget of {} MB, spilling every {} stars",
+                args.mem_budget,
+                max_stars
+            );
+            let mut spiller = spill::Spiller::new(&format!("{}/spill", args.output));
+            let (mut n_gaia, mut n_too_far, mut n_close_stars, mut n_gmag_nan) = (0, 0, 0, 0);
+            let mut spill_merged = |mut merged: data::StarStore| {
+                if let Some(names) = names.as_mut() {
+                    names.apply(&mut merged);
+                }
+                let (far, close, nan) = filter_distance(&mut merged, args.distpc_cap);
+                n_too_far += far;
+                n_close_stars += close;
+                n_gmag_nan += nan;
+                spiller.spill(merged);
+                mem::log_mem();
+            };
 
-        let time_gen = start_gen.elapsed();
+            let start_gaia = Instant::now();
+            loader_gaia.load_dir_chunked(&args.input, max_stars, &mut |chunk| {
+                n_gaia += chunk.len();
+                let mut merged = data::StarStore::new();
+                merger.merge(&chunk, &mut merged);
+                std::mem::drop(chunk);
+                spill_merged(merged);
+            });
+            loader_gaia.report_rejected();
+            log::info!(
+                "{} particles loaded form Gaia in {:?}",
+                n_gaia,
+                start_gaia.elapsed(),
+            );
 
-        let octree = lod::Octree::from_params(
-            args.max_part,
-            args.postprocess,
-            args.child_count,
-            args.parent_count,
-            args.distpc_cap,
-            args.centre_origin,
-        );
-        let (num_octants, num_stars, depth) = octree.generate_octree(&main_list);
-        log::info!(
-            "Octree generated with {} octants and {} stars ({} skipped) in {:?}.",
-            num_octants,
-            num_stars,
-            main_list.len() - num_stars,
-            start_gen.elapsed()
-        );
-        octree.print();
-        mem::log_mem();
+            // The secondary stars without match go in their own run
+            let mut rest = data::StarStore::new();
+            merger.add_rest(&mut rest);
+            merger.log_stats();
+            spill_merged(rest);
+            std::mem::drop(merger);
+            std::mem::drop(lists);
+            if let Some(names) = names.as_ref() {
+                names.log_stats();
+            }
+            std::mem::drop(names);
+            log_distance_filter(n_too_far, n_close_stars, n_gmag_nan, args.distpc_cap);
 
-        //
-        // Write tree and particles
-        //
-        let start_write = Instant::now();
-
-        // Write
-        let main_list_len = main_list.len() as f32;
-        if !args.dry_run {
-            // Write only if not dry_run
-            write::write_metadata(&octree, &args.output);
-            write::write_particles_mmap(&octree, main_list, &args.output);
+            log::info!("{} stars in the final list.", spiller.len);
+            time_load = start.elapsed();
+
+            if spiller.len == 0 {
+                log::info!("No stars were loaded, aborting.");
+                spiller.clean();
+                std::process::exit(1);
+            }
+
+            //
+            // Generate the LOD octree from the merged runs, which are
+            // written to a single sorted file at the same time
+            //
+            let start_gen = Instant::now();
+            let mut sorted = spill::StarFileWriter::create(&spiller.path("sorted"));
+            let positions = spiller.merge().map(|p| {
+                sorted.write(&p);
+                (p.x, p.y, p.z)
+            });
+            (num_octants, num_stars, depth) =
+                octree.generate_octree_from(&spiller.min, &spiller.max, spiller.len, positions);
+            log::info!(
+                "Octree generated with {} octants and {} stars ({} skipped) in {:?}.",
+                num_octants,
+                num_stars,
+                spiller.len - num_stars,
+                start_gen.elapsed()
+            );
+            octree.print();
+            mem::log_mem();
+            time_gen = start_gen.elapsed();
+
+            //
+            // Write tree and particles, reading them from the sorted file
+            //
+            let start_write = Instant::now();
+            main_list_len = spiller.len as f32;
+            let (sorted_base, sorted_len) = sorted.finish();
+            if !args.dry_run {
+                write::write_metadata(&octree, &args.output);
+                write::write_catalog_info(catalog_epochs(&loader_gaia, &loaders), &args.output);
+                let stars = spill::MappedStars::open(&sorted_base, sorted_len);
+                write::write_particles_mmap(&octree, stars, &args.output);
+            }
+            spiller.clean();
+            time_write = start_write.elapsed();
         }
-        let time_write = start_write.elapsed();
 
         mem::log_mem();
         // Star counts per magnitude
@@ -545,8 +945,11 @@ fn main() {
         log::info!("STAR COUNTS PER MAGNITUDE");
         log::info!("=========================");
         for i in 0..=21 {
-            let count =
-                loader_hip.counts_per_mag.borrow()[i] + loader_gaia.counts_per_mag.borrow()[i];
+            let count = loaders
+                .iter()
+                .map(|loader| loader.counters.counts_per_mag[i])
+                .sum::<u32>()
+                + loader_gaia.counters.counts_per_mag[i];
             log::info!(
                 "Magnitude {}: {} stars ({:.3}%)",
                 i,
@@ -615,3 +1018,77 @@ fn main() {
         std::process::exit(1);
     }
 }
+
+/// Loads the cross-match file of a secondary catalog, and writes its conflicts
+/// to the output directory. Returns None if there is no file or it can't be
+/// loaded, so that the positional cross-match is used instead.
+fn load_catalog_xmatch(spec: &CatalogSpec, output: &str) -> Option<HashMap<i64, i64>> {
+    if spec.xmatch_file.is_empty() {
+        return None;
+    }
+    let mut map = HashMap::new();
+    match xmatch::load_xmatch(&spec.xmatch_file, &mut map) {
+        Ok(conflicts) => {
+            if !conflicts.is_empty() {
+                fs::create_dir_all(output).expect("Error creating output directory");
+                xmatch::write_conflicts(
+                    &conflicts,
+                    &format!("{}/xmatch_conflicts_{}.csv", output, spec.name),
+                );
+            }
+            Some(map)
+        }
+        Err(e) => {
+            log::error!(
+                "Error loading cross-match file of {}, using positional cross-match: {}",
+                spec.name,
+                e
+            );
+            None
+        }
+    }
+}
+
+/// Removes the stars farther than distpc_cap parsecs from the list. Returns the number
+/// of removed stars, the number of close stars (<= 5 pc) and the number of stars
+/// with non-finite magnitude.
+/// Epochs of the positions of the stars loaded from Gaia and the secondary catalogs.
+fn catalog_epochs(loader_gaia: &load::Loader, loaders: &[load::Loader]) -> load::Epochs {
+    let mut epochs = loader_gaia.counters.epochs;
+    for loader in loaders {
+        epochs += loader.counters.epochs;
+    }
+    epochs
+}
+
+fn filter_distance(list: &mut data::StarStore, distpc_cap: f64) -> (usize, u64, u64) {
+    let len_before = list.len();
+    let mut n_close_stars: u64 = 0;
+    let mut n_gmag_nan: u64 = 0;
+    let keep: Vec<bool> = (0..list.len())
+        .map(|i| {
+            let (x, y, z) = (list.x[i], list.y[i], list.z[i]);
+            let dist_pc: f64 = (x * x + y * y + z * z).sqrt() * constants::U_TO_PC;
+            if dist_pc <= 5.0 {
+                n_close_stars += 1;
+            }
+            if !list.appmag[i].is_finite() {
+                n_gmag_nan += 1;
+            }
+
+            dist_pc <= distpc_cap
+        })
+        .collect();
+    list.retain_mask(&keep);
+    (len_before - list.len(), n_close_stars, n_gmag_nan)
+}
+
+fn log_distance_filter(n_too_far: usize, n_close_stars: u64, n_gmag_nan: u64, distpc_cap: f64) {
+    log::info!(
+        "Rejected {} stars due to being too far (cap = {} pc).",
+        n_too_far,
+        distpc_cap
+    );
+    log::info!("   - Found {} close stars (dist <= 5 pc).", n_close_stars,);
+    log::info!("   - Found {} with non-finite Gmag.", n_gmag_nan,);
+}
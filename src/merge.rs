@@ -0,0 +1,336 @@
+use std::fmt;
+
+use crate::constants;
+use crate::data::{Particle, Vec3};
+use crate::util;
+
+/// Groups of fields of a star that are merged together.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Field {
+    // Sky position (RA/DEC)
+    Position,
+    // Distance and parallax error
+    Distance,
+    // Magnitudes, colour, effective temperature and size
+    Photometry,
+    // Proper motions and radial velocity
+    Kinematics,
+    Names,
+}
+
+impl Field {
+    pub const ALL: [Field; 5] = [
+        Field::Position,
+        Field::Distance,
+        Field::Photometry,
+        Field::Kinematics,
+        Field::Names,
+    ];
+
+    pub fn to_str(self) -> &'static str {
+        match self {
+            Field::Position => "position",
+            Field::Distance => "distance",
+            Field::Photometry => "photometry",
+            Field::Kinematics => "kinematics",
+            Field::Names => "names",
+        }
+    }
+
+    pub fn from_str(input: &str) -> Option<Field> {
+        Field::ALL.into_iter().find(|f| f.to_str() == input)
+    }
+}
+
+/// How a field is selected when merging a Gaia star and a star of a
+/// secondary catalog, like Hipparcos.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MergeRule {
+    Gaia,
+    Secondary,
+    // The star with the smallest parallax error, as it is the only
+    // error available for both catalogs
+    BestError,
+    // Mean weighted with the inverse of the parallax variance. Names
+    // from both stars are kept. The magnitudes are in different bands
+    // (G, and Hp or V), so the photometry is not averaged and comes from
+    // the star with the smallest parallax error.
+    WeightedMean,
+}
+
+impl MergeRule {
+    pub fn to_str(self) -> &'static str {
+        match self {
+            MergeRule::Gaia => "gaia",
+            MergeRule::Secondary => "secondary",
+            MergeRule::BestError => "best-error",
+            MergeRule::WeightedMean => "weighted-mean",
+        }
+    }
+
+    pub fn from_str(input: &str) -> Option<MergeRule> {
+        match input {
+            "gaia" => Some(MergeRule::Gaia),
+            "secondary" => Some(MergeRule::Secondary),
+            "best-error" => Some(MergeRule::BestError),
+            "weighted-mean" => Some(MergeRule::WeightedMean),
+            _ => None,
+        }
+    }
+}
+
+/// Where a merged field comes from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Source {
+    Gaia,
+    Secondary,
+    Mean,
+}
+
+/**
+ * The rule of each field used to merge Gaia and secondary catalog stars.
+ * All fields default to best-error, except for the names, which
+ * default to the secondary catalog ones.
+ **/
+#[derive(Clone, Copy, Debug)]
+pub struct MergePolicy {
+    rules: [MergeRule; 5],
+}
+
+impl Default for MergePolicy {
+    fn default() -> Self {
+        MergePolicy {
+            rules: [
+                MergeRule::BestError,
+                MergeRule::BestError,
+                MergeRule::BestError,
+                MergeRule::BestError,
+                MergeRule::Secondary,
+            ],
+        }
+    }
+}
+
+impl MergePolicy {
+    /// Parses a comma-separated list of field=rule pairs, like
+    /// 'position=gaia,photometry=weighted-mean'. Fields not in the
+    /// list get their default rule.
+    pub fn from_str(input: &str) -> Result<Self, String> {
+        let mut policy = MergePolicy::default();
+        for token in input.split(',').map(|t| t.trim()).filter(|t| !t.is_empty()) {
+            let (field, rule) = token
+                .split_once('=')
+                .ok_or(format!("expected field=rule, got '{}'", token))?;
+            let field =
+                Field::from_str(field.trim()).ok_or(format!("unknown field '{}'", field.trim()))?;
+            let rule = MergeRule::from_str(rule.trim())
+                .ok_or(format!("unknown rule '{}'", rule.trim()))?;
+            policy.rules[field as usize] = rule;
+        }
+        Ok(policy)
+    }
+
+    pub fn rule(&self, field: Field) -> MergeRule {
+        self.rules[field as usize]
+    }
+
+    /// Merges a Gaia star and its secondary catalog counterpart, counting
+    /// where each field comes from.
+    pub fn merge(&self, gaia: &Particle, sec: &Particle, counts: &mut MergeCounts) -> Particle {
+        let gaia_best = gaia.plx_err <= sec.plx_err;
+        let source = |field: Field| match self.rule(field) {
+            MergeRule::Gaia => Source::Gaia,
+            MergeRule::Secondary => Source::Secondary,
+            MergeRule::BestError if gaia_best => Source::Gaia,
+            MergeRule::BestError => Source::Secondary,
+            MergeRule::WeightedMean => Source::Mean,
+        };
+        let (wg, ws) = weights(gaia.plx_err, sec.plx_err);
+        let mean = |g: f64, h: f64| {
+            if !g.is_finite() {
+                h
+            } else if !h.is_finite() {
+                g
+            } else {
+                wg * g + ws * h
+            }
+        };
+        let mean32 = |g: f32, h: f32| mean(g as f64, h as f64) as f32;
+
+        let pos_source = source(Field::Position);
+        let mut dist_source = source(Field::Distance);
+        let phot_source = match source(Field::Photometry) {
+            Source::Mean if gaia_best => Source::Gaia,
+            Source::Mean => Source::Secondary,
+            src => src,
+        };
+        let kin_source = source(Field::Kinematics);
+        let names_source = source(Field::Names);
+        // Gaia stars with negative parallaxes have no distance,
+        // so the secondary catalog one is used
+        let gaia_sph = util::cartesian_to_spherical(gaia.x, gaia.y, gaia.z);
+        let sec_sph = util::cartesian_to_spherical(sec.x, sec.y, sec.z);
+        let gaia_no_dist =
+            f64::abs(Vec3::new(gaia.x, gaia.y, gaia.z).len() - constants::NEGATIVE_DIST) < 1e-10;
+        let mut size_from_secondary = false;
+        if gaia_no_dist && dist_source != Source::Secondary {
+            dist_source = Source::Secondary;
+            size_from_secondary = true;
+        }
+        for (field, src) in [
+            (Field::Position, pos_source),
+            (Field::Distance, dist_source),
+            (Field::Photometry, phot_source),
+            (Field::Kinematics, kin_source),
+            (Field::Names, names_source),
+        ] {
+            counts.add(field, src);
+        }
+
+        let mut star = match pos_source {
+            Source::Secondary => sec.clone(),
+            _ => gaia.clone(),
+        };
+        if sec.hip > 0 {
+            star.hip = sec.hip;
+        }
+
+        // Position and distance
+        let (dist, plx_err) = match dist_source {
+            Source::Gaia => (gaia_sph.z, gaia.plx_err),
+            Source::Secondary => (sec_sph.z, sec.plx_err),
+            Source::Mean => {
+                // Mean of parallaxes
+                let plx = mean(1.0 / gaia_sph.z, 1.0 / sec_sph.z);
+                let plx_err = if gaia.plx_err > 0.0 && sec.plx_err > 0.0 {
+                    (1.0 / (1.0 / gaia.plx_err.powi(2) + 1.0 / sec.plx_err.powi(2))).sqrt()
+                } else {
+                    f32::NAN
+                };
+                (1.0 / plx, plx_err)
+            }
+        };
+        star.plx_err = plx_err;
+        let same_source = pos_source == dist_source && pos_source != Source::Mean;
+        if !same_source {
+            let (ra, dec) = match pos_source {
+                Source::Gaia => (gaia_sph.x, gaia_sph.y),
+                Source::Secondary => (sec_sph.x, sec_sph.y),
+                Source::Mean => {
+                    let g = util::spherical_to_cartesian(gaia_sph.x, gaia_sph.y, 1.0);
+                    let h = util::spherical_to_cartesian(sec_sph.x, sec_sph.y, 1.0);
+                    let sph = util::cartesian_to_spherical(
+                        wg * g.x + ws * h.x,
+                        wg * g.y + ws * h.y,
+                        wg * g.z + ws * h.z,
+                    );
+                    (sph.x, sph.y)
+                }
+            };
+            let pos = util::spherical_to_cartesian(ra, dec, dist);
+            (star.x, star.y, star.z) = (pos.x, pos.y, pos.z);
+        }
+
+        // Kinematics
+        let kin = match kin_source {
+            Source::Gaia => Some(gaia),
+            Source::Secondary => Some(sec),
+            Source::Mean => None,
+        };
+        match kin {
+            Some(k) => {
+                (star.pmx, star.pmy, star.pmz) = (k.pmx, k.pmy, k.pmz);
+                (star.mualpha, star.mudelta, star.radvel) = (k.mualpha, k.mudelta, k.radvel);
+            }
+            None => {
+                star.pmx = mean32(gaia.pmx, sec.pmx);
+                star.pmy = mean32(gaia.pmy, sec.pmy);
+                star.pmz = mean32(gaia.pmz, sec.pmz);
+                star.mualpha = mean32(gaia.mualpha, sec.mualpha);
+                star.mudelta = mean32(gaia.mudelta, sec.mudelta);
+                star.radvel = mean32(gaia.radvel, sec.radvel);
+            }
+        }
+
+        // Photometry. The absolute magnitude and the size are kept if
+        // they come from the same star as the distance.
+        let phot = match phot_source {
+            Source::Secondary => sec,
+            _ => gaia,
+        };
+        (star.appmag, star.absmag, star.size) = (phot.appmag, phot.absmag, phot.size);
+        (star.col, star.teff) = (phot.col, phot.teff);
+        if size_from_secondary {
+            star.size = sec.size;
+        } else if phot_source != dist_source {
+            let dist_pc = dist * constants::U_TO_PC;
+            let absmag = star.appmag as f64 - 5.0 * f64::log10(dist_pc) + 5.0;
+            star.absmag = absmag as f32;
+            star.size = util::absmag_to_size(absmag);
+        }
+
+        // Names
+        star.names = match names_source {
+            Source::Gaia => gaia.names.clone(),
+            Source::Secondary => sec.names.clone(),
+            Source::Mean => {
+                let mut names = sec.names.clone();
+                for name in &gaia.names {
+                    if !names.contains(name) {
+                        names.push(name.clone());
+                    }
+                }
+                names
+            }
+        };
+
+        star
+    }
+}
+
+impl fmt::Display for MergePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rules: Vec<String> = Field::ALL
+            .iter()
+            .map(|field| format!("{}={}", field.to_str(), self.rule(*field).to_str()))
+            .collect();
+        write!(f, "{}", rules.join(","))
+    }
+}
+
+/// Normalized weights of the Gaia and secondary catalog values, from the inverse
+/// of the parallax variances. If only one of the errors is valid, that star
+/// gets all the weight.
+fn weights(gaia_plx_err: f32, sec_plx_err: f32) -> (f64, f64) {
+    let valid = |e: f32| e.is_finite() && e > 0.0;
+    match (valid(gaia_plx_err), valid(sec_plx_err)) {
+        (true, true) => {
+            let wg = 1.0 / (gaia_plx_err as f64).powi(2);
+            let ws = 1.0 / (sec_plx_err as f64).powi(2);
+            (wg / (wg + ws), ws / (wg + ws))
+        }
+        (true, false) => (1.0, 0.0),
+        (false, true) => (0.0, 1.0),
+        (false, false) => (0.5, 0.5),
+    }
+}
+
+/**
+ * Number of merged stars whose fields come from Gaia, from
+ * the secondary catalog, or from both, for each field.
+ **/
+#[derive(Default)]
+pub struct MergeCounts {
+    counts: [[usize; 3]; 5],
+}
+
+impl MergeCounts {
+    fn add(&mut self, field: Field, source: Source) {
+        self.counts[field as usize][source as usize] += 1;
+    }
+
+    pub fn get(&self, field: Field) -> (usize, usize, usize) {
+        let c = self.counts[field as usize];
+        (c[0], c[1], c[2])
+    }
+}
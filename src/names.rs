@@ -0,0 +1,272 @@
+//! Star names from external designation catalogs (IAU WGSN proper names,
+//! Bayer and Flamsteed designations, HD, HR and Gliese numbers, Gaia DR3
+//! designations...), which are merged with the names already in the
+//! catalogs, de-duplicated and ordered by priority.
+
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::io::BufRead;
+
+use flate2::read::GzDecoder;
+
+use crate::data::StarStore;
+
+// Greek letters of Bayer designations, abbreviated and in full
+const GREEK: [&str; 41] = [
+    "alf", "alp", "bet", "gam", "del", "eps", "zet", "eta", "tet", "the", "iot", "kap", "lam",
+    "mu", "nu", "ksi", "omi", "pi", "rho", "sig", "tau", "ups", "phi", "chi", "psi", "ome",
+    "alpha", "beta", "gamma", "delta", "epsilon", "zeta", "theta", "iota", "kappa", "lambda", "xi",
+    "omicron", "sigma", "upsilon", "omega",
+];
+
+/// Kind of a star name, which sets its priority.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum NameKind {
+    // IAU WGSN proper names
+    Proper,
+    Bayer,
+    Flamsteed,
+    Hr,
+    Hd,
+    Gliese,
+    Hip,
+    Gaia,
+    // Names of the catalogs that are none of the above
+    Other,
+}
+
+impl NameKind {
+    pub const ALL: [NameKind; 9] = [
+        NameKind::Proper,
+        NameKind::Other,
+        NameKind::Bayer,
+        NameKind::Flamsteed,
+        NameKind::Hr,
+        NameKind::Hd,
+        NameKind::Gliese,
+        NameKind::Hip,
+        NameKind::Gaia,
+    ];
+
+    pub fn to_str(self) -> &'static str {
+        match self {
+            NameKind::Proper => "proper",
+            NameKind::Bayer => "bayer",
+            NameKind::Flamsteed => "flamsteed",
+            NameKind::Hr => "hr",
+            NameKind::Hd => "hd",
+            NameKind::Gliese => "gliese",
+            NameKind::Hip => "hip",
+            NameKind::Gaia => "gaia",
+            NameKind::Other => "other",
+        }
+    }
+
+    pub fn from_str(input: &str) -> Option<NameKind> {
+        match input.trim().to_lowercase().as_str() {
+            "wgsn" | "iau" => Some(NameKind::Proper),
+            name => NameKind::ALL.into_iter().find(|k| k.to_str() == name),
+        }
+    }
+
+    /// Guesses the kind of a name from the catalogs by its prefix.
+    pub fn classify(name: &str) -> NameKind {
+        let words: Vec<&str> = name.split_whitespace().collect();
+        let first = words.first().map_or(String::new(), |w| w.to_lowercase());
+        let second = words.get(1).copied().unwrap_or("");
+        // Abbreviation (CMa) or genitive (Canis Majoris) of a constellation
+        let is_constellation = |w: &str| {
+            w.starts_with(|c: char| c.is_ascii_uppercase()) && w.chars().all(|c| c.is_alphabetic())
+        };
+        match first.as_str() {
+            "hip" => NameKind::Hip,
+            "hd" => NameKind::Hd,
+            "hr" => NameKind::Hr,
+            "gj" | "gl" | "gliese" => NameKind::Gliese,
+            "gaia" => NameKind::Gaia,
+            _ if words.len() >= 2 && is_constellation(second) => {
+                // Bayer letters may have a superscript number, like pi01 or alf2
+                let letter = first.trim_end_matches(|c: char| c.is_ascii_digit());
+                if GREEK.contains(&letter)
+                    || letter.chars().count() == 1
+                        && ('α'..='ω').contains(&letter.chars().next().unwrap())
+                {
+                    NameKind::Bayer
+                } else if first.chars().all(|c| c.is_ascii_digit()) {
+                    NameKind::Flamsteed
+                } else {
+                    NameKind::Other
+                }
+            }
+            _ => NameKind::Other,
+        }
+    }
+}
+
+/**
+ * Names of the lookup files, by source_id and by HIP number, and the
+ * priority used to order the names of each star.
+ **/
+pub struct Names {
+    by_source_id: HashMap<i64, Vec<(NameKind, String)>>,
+    by_hip: HashMap<i32, Vec<(NameKind, String)>>,
+    // Kinds in priority order
+    priority: Vec<NameKind>,
+    pub n_named: usize,
+    pub n_added: usize,
+}
+
+impl Names {
+    /// Creates an empty names lookup with the given comma-separated priority of
+    /// kinds, like 'proper,bayer,hd'. Kinds not in the list go after the listed
+    /// ones, in the default order.
+    pub fn new(priority: &str) -> Result<Self, String> {
+        let mut kinds = Vec::new();
+        for token in priority
+            .split(',')
+            .map(|t| t.trim())
+            .filter(|t| !t.is_empty())
+        {
+            let kind = NameKind::from_str(token).ok_or(format!("unknown name kind '{}'", token))?;
+            if !kinds.contains(&kind) {
+                kinds.push(kind);
+            }
+        }
+        for kind in NameKind::ALL {
+            if !kinds.contains(&kind) {
+                kinds.push(kind);
+            }
+        }
+        Ok(Names {
+            by_source_id: HashMap::new(),
+            by_hip: HashMap::new(),
+            priority: kinds,
+            n_named: 0,
+            n_added: 0,
+        })
+    }
+
+    /// Loads a comma-separated list of kind=file pairs, like
+    /// 'proper=iau.csv,hd=hd.csv.gz'.
+    pub fn load_files(&mut self, files: &str) -> Result<(), String> {
+        for token in files.split(',').map(|t| t.trim()).filter(|t| !t.is_empty()) {
+            let (kind, file) = token
+                .split_once('=')
+                .ok_or(format!("expected kind=file, got '{}'", token))?;
+            let kind = NameKind::from_str(kind).ok_or(format!("unknown name kind '{}'", kind))?;
+            let f = File::open(file.trim()).map_err(|e| format!("{}: {}", file, e))?;
+            let reader: Box<dyn BufRead> = if file.ends_with(".gz") {
+                Box::new(io::BufReader::new(GzDecoder::new(f)))
+            } else {
+                Box::new(io::BufReader::new(f))
+            };
+            let n = self
+                .parse(kind, reader)
+                .map_err(|e| format!("{}: {}", file, e))?;
+            log::info!("{} {} names loaded from {}", n, kind.to_str(), file);
+        }
+        Ok(())
+    }
+
+    /// Parses a lookup file with the names of the given kind. Values are separated
+    /// by commas or tabs, and the header row must have a source_id or hip column, and
+    /// a name column. Several names in the same row are separated by '|'. Returns
+    /// the number of names.
+    pub fn parse(&mut self, kind: NameKind, reader: Box<dyn BufRead>) -> Result<usize, String> {
+        let mut lines = reader.lines();
+        let header = match lines.next() {
+            Some(line) => line.map_err(|e| e.to_string())?,
+            None => return Ok(0),
+        };
+        let columns: Vec<String> = header
+            .split([',', '\t'])
+            .map(|c| c.trim().to_lowercase())
+            .collect();
+        let find = |names: &[&str]| columns.iter().position(|c| names.contains(&c.as_str()));
+        let name_col = find(&["name", "names", "designation"]).ok_or("no name column")?;
+        let (key_col, by_hip) = match (find(&["source_id", "sourceid"]), find(&["hip"])) {
+            (Some(col), _) => (col, false),
+            (None, Some(col)) => (col, true),
+            _ => return Err("no source_id or hip column".to_string()),
+        };
+
+        let mut n = 0;
+        for line in lines {
+            let line = line.map_err(|e| e.to_string())?;
+            let tokens: Vec<&str> = line.split([',', '\t']).map(|t| t.trim()).collect();
+            let key = match tokens.get(key_col).and_then(|t| t.parse::<i64>().ok()) {
+                Some(key) if key > 0 => key,
+                _ => continue,
+            };
+            let names = tokens.get(name_col).copied().unwrap_or("");
+            for name in names.split('|').map(|n| n.trim()).filter(|n| !n.is_empty()) {
+                let entry = if by_hip {
+                    self.by_hip.entry(key as i32).or_default()
+                } else {
+                    self.by_source_id.entry(key).or_default()
+                };
+                entry.push((kind, name.to_string()));
+                n += 1;
+            }
+        }
+        Ok(n)
+    }
+
+    /// Adds the names of the lookup files to the stars of the list, and orders
+    /// the names of each star by priority, removing duplicates.
+    pub fn apply(&mut self, list: &mut StarStore) {
+        for i in 0..list.len() {
+            let current = list.names(i);
+            let from_source_id = self.by_source_id.get(&list.id[i]);
+            let from_hip = if list.hip[i] > 0 {
+                self.by_hip.get(&list.hip[i])
+            } else {
+                None
+            };
+            if current.is_empty() && from_source_id.is_none() && from_hip.is_none() {
+                continue;
+            }
+
+            let mut names: Vec<(NameKind, String)> = current
+                .split('|')
+                .filter(|n| !n.is_empty())
+                .map(|n| (NameKind::classify(n), n.to_string()))
+                .collect();
+            let n_current = names.len();
+            names.extend(from_source_id.into_iter().flatten().cloned());
+            names.extend(from_hip.into_iter().flatten().cloned());
+            let ordered = self.order(names);
+            if ordered.len() > n_current {
+                self.n_named += 1;
+                self.n_added += ordered.len() - n_current;
+            }
+            list.set_names(i, &ordered);
+        }
+    }
+
+    /// Orders the names by priority, keeping the original order within each kind,
+    /// and removes the duplicates, ignoring case and whitespace.
+    fn order(&self, mut names: Vec<(NameKind, String)>) -> Vec<String> {
+        names.sort_by_key(|(kind, _)| self.priority.iter().position(|k| k == kind));
+        let mut seen = HashSet::new();
+        names
+            .into_iter()
+            .filter(|(_, name)| {
+                let key: Vec<String> = name.split_whitespace().map(|w| w.to_lowercase()).collect();
+                seen.insert(key.join(" "))
+            })
+            .map(|(_, name)| name)
+            .collect()
+    }
+
+    pub fn log_stats(&self) {
+        let priority: Vec<&str> = self.priority.iter().map(|k| k.to_str()).collect();
+        log::info!("Name priority: {}", priority.join(","));
+        log::info!(
+            "{} names added to {} stars from the name lookup files",
+            self.n_added,
+            self.n_named
+        );
+    }
+}
@@ -0,0 +1,209 @@
+//! Photometric relations of the Gaia passbands.
+
+/// Law to compute the extinction A_G and the reddening E(BP-RP) of the stars
+/// that lack one of them in the catalog, from their BP and RP magnitudes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExtLaw {
+    // A_G and E(BP-RP) from the catalog or the extinction model, independent of the colour
+    Constant,
+    // Colour- and extinction-dependent coefficients of the Gaia EDR3 passbands
+    Riello2021,
+}
+
+impl ExtLaw {
+    pub fn from_str(name: &str) -> Option<ExtLaw> {
+        match name.to_lowercase().as_str() {
+            "constant" => Some(ExtLaw::Constant),
+            "riello2021" | "edr3" => Some(ExtLaw::Riello2021),
+            _ => None,
+        }
+    }
+}
+
+// Coefficients of k_X = A_X / A_0 as a function of X = (BP-RP)_0 and A_0, in
+// the order 1, X, X^2, X^3, A, A^2, A^3, XA, AX^2, XA^2. Gaia EDR3 extinction
+// law (Riello et al. 2021), with the Fitzpatrick et al. (2019) extinction curve.
+const K_G: [f64; 10] = [
+    0.995969721536602,
+    -0.159726460302015,
+    0.0122380738156057,
+    0.00090726555099859,
+    -0.0377160263914123,
+    0.00151347495244888,
+    -2.52364537395142e-05,
+    0.0114522658102451,
+    -0.000936914989014318,
+    -0.000260296774134201,
+];
+const K_BP: [f64; 10] = [
+    1.15363197483424,
+    -0.0814012991657388,
+    -0.036013023976704,
+    0.0192143585568966,
+    -0.022397548243016,
+    0.000840562680547171,
+    -1.31018008013547e-05,
+    0.00660124080271006,
+    -0.000882247501989453,
+    -0.000111215755291684,
+];
+const K_RP: [f64; 10] = [
+    0.66320787941067,
+    -0.0179847164933981,
+    0.000493769449961458,
+    -0.00267994405695751,
+    -0.00651422146709376,
+    3.30179903473159e-05,
+    1.57894227641527e-06,
+    -7.9800898337247e-05,
+    0.000255679812110045,
+    1.10476584967393e-05,
+];
+// Range of (BP-RP)_0 where the polynomials are valid
+const X_MIN: f64 = -0.06;
+const X_MAX: f64 = 2.5;
+
+fn k(c: &[f64; 10], x: f64, a0: f64) -> f64 {
+    let x = x.clamp(X_MIN, X_MAX);
+    c[0] + c[1] * x
+        + c[2] * x * x
+        + c[3] * x * x * x
+        + c[4] * a0
+        + c[5] * a0 * a0
+        + c[6] * a0 * a0 * a0
+        + c[7] * x * a0
+        + c[8] * a0 * x * x
+        + c[9] * x * a0 * a0
+}
+
+/// A_G / A_0 for the intrinsic colour (BP-RP)_0 and the extinction A_0.
+pub fn k_g(bp_rp0: f64, a0: f64) -> f64 {
+    k(&K_G, bp_rp0, a0)
+}
+
+/// A_BP / A_0 for the intrinsic colour (BP-RP)_0 and the extinction A_0.
+pub fn k_bp(bp_rp0: f64, a0: f64) -> f64 {
+    k(&K_BP, bp_rp0, a0)
+}
+
+/// A_RP / A_0 for the intrinsic colour (BP-RP)_0 and the extinction A_0.
+pub fn k_rp(bp_rp0: f64, a0: f64) -> f64 {
+    k(&K_RP, bp_rp0, a0)
+}
+
+/// Finds the extinction A_0 and the intrinsic colour (BP-RP)_0 of a star
+/// with the given observed BP-RP and extinction A_G, by fixed-point iteration.
+/// Returns None if the values are not finite.
+pub fn solve_a0(bp_rp: f64, ag: f64) -> Option<(f64, f64)> {
+    if !bp_rp.is_finite() || !ag.is_finite() {
+        return None;
+    }
+    if ag <= 0.0 {
+        return Some((0.0, bp_rp));
+    }
+    let mut a0 = ag;
+    let mut bp_rp0 = bp_rp;
+    for _ in 0..20 {
+        bp_rp0 = bp_rp - (k_bp(bp_rp0, a0) - k_rp(bp_rp0, a0)) * a0;
+        let next = ag / k_g(bp_rp0, a0);
+        if (next - a0).abs() < 1e-6 {
+            a0 = next;
+            break;
+        }
+        a0 = next;
+    }
+    Some((a0, bp_rp0))
+}
+
+/// Finds the extinction A_0 and the intrinsic colour (BP-RP)_0 of a star
+/// with the given observed BP-RP and reddening E(BP-RP), by fixed-point
+/// iteration. Returns None if the values are not finite.
+pub fn solve_a0_from_reddening(bp_rp: f64, ebr: f64) -> Option<(f64, f64)> {
+    if !bp_rp.is_finite() || !ebr.is_finite() {
+        return None;
+    }
+    if ebr <= 0.0 {
+        return Some((0.0, bp_rp));
+    }
+    let bp_rp0 = bp_rp - ebr;
+    let mut a0 = ebr / (k_bp(bp_rp0, 0.0) - k_rp(bp_rp0, 0.0));
+    for _ in 0..20 {
+        let next = ebr / (k_bp(bp_rp0, a0) - k_rp(bp_rp0, a0));
+        if (next - a0).abs() < 1e-6 {
+            a0 = next;
+            break;
+        }
+        a0 = next;
+    }
+    Some((a0, bp_rp0))
+}
+
+/// Gets the extinctions A_G, A_BP and A_RP of a star with the given
+/// extinction A_0 and intrinsic colour (BP-RP)_0.
+pub fn extinctions(a0: f64, bp_rp0: f64) -> (f64, f64, f64) {
+    (
+        k_g(bp_rp0, a0) * a0,
+        k_bp(bp_rp0, a0) * a0,
+        k_rp(bp_rp0, a0) * a0,
+    )
+}
+
+/// Corrects the G magnitude of the sources without a five-parameter
+/// astrometric solution, whose G fluxes are biased (Riello et al. 2021,
+/// Sect. 8.3), and the magnitude term of the bright sources with
+/// 6 < G < 13 (Riello et al. 2021, Sect. 8.3). The solution type is the
+/// astrometric_params_solved value (31 for five parameters), or None if
+/// unknown, in which case only the bright correction is applied.
+pub fn correct_gmag(gmag: f64, bp_rp: f64, params_solved: Option<i32>) -> f64 {
+    if !gmag.is_finite() {
+        return gmag;
+    }
+    if gmag < 13.0 {
+        if gmag > 6.0 {
+            return gmag - 0.0032 * (gmag - 6.0);
+        }
+        return gmag;
+    }
+    match params_solved {
+        Some(params) if params != 31 && bp_rp.is_finite() => {
+            let x = bp_rp.clamp(0.25, 3.0);
+            let factor = if gmag <= 16.0 {
+                1.00876 - 0.02540 * x + 0.01747 * x * x - 0.00277 * x * x * x
+            } else {
+                1.00525 - 0.02323 * x + 0.01740 * x * x - 0.00253 * x * x * x
+            };
+            gmag - 2.5 * factor.log10()
+        }
+        _ => gmag,
+    }
+}
+
+/// Gets the corrected BP/RP flux excess factor C* of Riello et al. (2021),
+/// which is zero on average for well-behaved sources of any colour.
+pub fn excess_factor_corrected(excess_factor: f64, bp_rp: f64) -> f64 {
+    let x = bp_rp;
+    let expected = if x < 0.5 {
+        1.154360 + 0.033772 * x + 0.032277 * x * x
+    } else if x < 4.0 {
+        1.162004 + 0.011464 * x + 0.049255 * x * x - 0.005879 * x * x * x
+    } else {
+        1.057572 + 0.140537 * x
+    };
+    excess_factor - expected
+}
+
+/// Gets the scatter of C* of well-behaved sources with the given G
+/// magnitude (Riello et al. 2021, Eq. 18).
+pub fn excess_sigma(gmag: f64) -> f64 {
+    0.0059898 + 8.817481e-12 * gmag.powf(7.618399)
+}
+
+/// Whether the corrected BP/RP flux excess factor of the source is within
+/// n_sigma times its expected scatter. Sources without excess factor or
+/// colour pass.
+pub fn accept_excess(excess_factor: f64, bp_rp: f64, gmag: f64, n_sigma: f64) -> bool {
+    if !excess_factor.is_finite() || !bp_rp.is_finite() || !gmag.is_finite() {
+        return true;
+    }
+    excess_factor_corrected(excess_factor, bp_rp).abs() <= n_sigma * excess_sigma(gmag)
+}
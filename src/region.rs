@@ -0,0 +1,251 @@
+//! Sky regions to restrict the catalogs to: cones, RA/Dec boxes, spherical
+//! polygons and lists of HEALPix pixels. A star is loaded if it is in any
+//! of the given regions.
+
+extern crate nalgebra as na;
+
+use std::collections::HashSet;
+use std::f64::consts::{FRAC_PI_2, PI};
+
+use na::Vector3;
+
+/// A region of the sky, with angles in radians.
+#[derive(Clone, Debug)]
+pub enum Region {
+    // Centre and cosine of the radius
+    Cone(Vector3<f64>, f64),
+    // Minimum and maximum RA (the minimum is larger if the box
+    // contains RA=0), and minimum and maximum Dec
+    Box(f64, f64, f64, f64),
+    // Vertices, in order
+    Polygon(Vec<Vector3<f64>>),
+    // Nside, whether the scheme is nested, and pixels
+    Healpix(u64, bool, HashSet<u64>),
+}
+
+impl Region {
+    /// Parses a region given as kind:values, with angles in degrees:
+    ///  - cone:ra,dec,radius
+    ///  - box:ra_min,ra_max,dec_min,dec_max, where ra_min > ra_max wraps
+    ///    around RA=0.
+    ///  - polygon:ra1,dec1,ra2,dec2,ra3,dec3[,...], whose edges are great
+    ///    circle arcs. Polygons must be smaller than a hemisphere.
+    ///  - healpix:nside,nested|ring,pixel[,...], where pixels may be ranges
+    ///    like 100-120.
+    pub fn from_str(input: &str) -> Result<Region, String> {
+        let (kind, values) = input
+            .split_once(':')
+            .ok_or(format!("expected kind:values, got '{}'", input))?;
+        let tokens: Vec<&str> = values.split(',').map(|t| t.trim()).collect();
+        let numbers = |tokens: &[&str]| -> Result<Vec<f64>, String> {
+            tokens
+                .iter()
+                .map(|t| {
+                    t.parse::<f64>()
+                        .map_err(|_| format!("invalid value '{}' in '{}'", t, input))
+                })
+                .collect()
+        };
+        match kind.trim().to_lowercase().as_str() {
+            "cone" => match numbers(&tokens)?[..] {
+                [ra, dec, radius] => Ok(Region::Cone(
+                    unit_vector(ra.to_radians(), dec.to_radians()),
+                    radius.to_radians().cos(),
+                )),
+                _ => Err(format!("cone needs ra,dec,radius, got '{}'", values)),
+            },
+            "box" => match numbers(&tokens)?[..] {
+                [ra_min, ra_max, dec_min, dec_max] => Ok(Region::Box(
+                    ra_min.rem_euclid(360.0).to_radians(),
+                    ra_max.rem_euclid(360.0).to_radians(),
+                    dec_min.to_radians(),
+                    dec_max.to_radians(),
+                )),
+                _ => Err(format!(
+                    "box needs ra_min,ra_max,dec_min,dec_max, got '{}'",
+                    values
+                )),
+            },
+            "polygon" => {
+                let numbers = numbers(&tokens)?;
+                if numbers.len() < 6 || numbers.len() % 2 != 0 {
+                    return Err(format!(
+                        "polygon needs at least three ra,dec vertices, got '{}'",
+                        values
+                    ));
+                }
+                Ok(Region::Polygon(
+                    numbers
+                        .chunks(2)
+                        .map(|v| unit_vector(v[0].to_radians(), v[1].to_radians()))
+                        .collect(),
+                ))
+            }
+            "healpix" => {
+                if tokens.len() < 3 {
+                    return Err(format!(
+                        "healpix needs nside,nested|ring,pixel[,...], got '{}'",
+                        values
+                    ));
+                }
+                let nside = tokens[0]
+                    .parse::<u64>()
+                    .map_err(|_| format!("invalid nside '{}'", tokens[0]))?;
+                let nested = match tokens[1].to_lowercase().as_str() {
+                    "nested" | "nest" => true,
+                    "ring" => false,
+                    s => return Err(format!("unknown HEALPix scheme '{}'", s)),
+                };
+                if nside == 0 || (nested && !nside.is_power_of_two()) {
+                    return Err(format!("invalid nside {}", nside));
+                }
+                let mut pixels = HashSet::new();
+                for token in &tokens[2..] {
+                    let (first, last) = token.split_once('-').unwrap_or((token, token));
+                    let parse = |t: &str| {
+                        t.trim()
+                            .parse::<u64>()
+                            .ok()
+                            .filter(|p| *p < 12 * nside * nside)
+                            .ok_or(format!("invalid pixel '{}' for nside {}", token, nside))
+                    };
+                    pixels.extend(parse(first)?..=parse(last)?);
+                }
+                Ok(Region::Healpix(nside, nested, pixels))
+            }
+            k => Err(format!("unknown region kind '{}'", k)),
+        }
+    }
+
+    /// Whether the position with the given RA and Dec (radians) is in this region.
+    pub fn contains(&self, ra: f64, dec: f64) -> bool {
+        match self {
+            Region::Cone(centre, cos_radius) => unit_vector(ra, dec).dot(centre) >= *cos_radius,
+            Region::Box(ra_min, ra_max, dec_min, dec_max) => {
+                let ra = ra.rem_euclid(2.0 * PI);
+                let in_ra = if ra_min <= ra_max {
+                    ra >= *ra_min && ra <= *ra_max
+                } else {
+                    ra >= *ra_min || ra <= *ra_max
+                };
+                in_ra && dec >= *dec_min && dec <= *dec_max
+            }
+            Region::Polygon(vertices) => polygon_contains(vertices, &unit_vector(ra, dec)),
+            Region::Healpix(nside, nested, pixels) => {
+                pixels.contains(&ang2pix(*nside, *nested, ra, dec))
+            }
+        }
+    }
+}
+
+fn unit_vector(ra: f64, dec: f64) -> Vector3<f64> {
+    Vector3::new(dec.cos() * ra.cos(), dec.cos() * ra.sin(), dec.sin())
+}
+
+/// Whether the point is in the spherical polygon, by adding up the angles
+/// the edges subtend around the point: about ±2π if it is inside, and zero
+/// otherwise.
+fn polygon_contains(vertices: &[Vector3<f64>], p: &Vector3<f64>) -> bool {
+    // Basis of the plane tangent to the sphere at p
+    let axis = if p.z.abs() < 0.9 {
+        Vector3::z()
+    } else {
+        Vector3::x()
+    };
+    let e1 = axis.cross(p).normalize();
+    let e2 = p.cross(&e1);
+    let bearing = |v: &Vector3<f64>| v.dot(&e2).atan2(v.dot(&e1));
+
+    let mut winding = 0.0;
+    for (i, v) in vertices.iter().enumerate() {
+        let next = &vertices[(i + 1) % vertices.len()];
+        let mut delta = bearing(next) - bearing(v);
+        if delta > PI {
+            delta -= 2.0 * PI;
+        } else if delta < -PI {
+            delta += 2.0 * PI;
+        }
+        winding += delta;
+    }
+    winding.abs() > PI
+}
+
+/// Gets the HEALPix pixel of the given RA and Dec (radians), in the nested
+/// or ring scheme, following Górski et al. (2005).
+pub fn ang2pix(nside: u64, nested: bool, ra: f64, dec: f64) -> u64 {
+    let n = nside as i64;
+    let z = dec.sin();
+    let za = z.abs();
+    let tt = (ra / FRAC_PI_2).rem_euclid(4.0);
+
+    if nested {
+        let (face, ix, iy) = if za <= 2.0 / 3.0 {
+            // Equatorial region
+            let temp1 = n as f64 * (0.5 + tt);
+            let temp2 = n as f64 * z * 0.75;
+            let jp = (temp1 - temp2) as i64;
+            let jm = (temp1 + temp2) as i64;
+            let (ifp, ifm) = (jp / n, jm / n);
+            let face = if ifp == ifm {
+                ifp | 4
+            } else if ifp < ifm {
+                ifp
+            } else {
+                ifm + 8
+            };
+            (face, jm & (n - 1), n - (jp & (n - 1)) - 1)
+        } else {
+            // Polar caps
+            let ntt = (tt as i64).min(3);
+            let tp = tt - ntt as f64;
+            let tmp = n as f64 * (3.0 * (1.0 - za)).sqrt();
+            let jp = ((tp * tmp) as i64).min(n - 1);
+            let jm = (((1.0 - tp) * tmp) as i64).min(n - 1);
+            if z >= 0.0 {
+                (ntt, n - jm - 1, n - jp - 1)
+            } else {
+                (ntt + 8, jp, jm)
+            }
+        };
+        face as u64 * nside * nside + spread_bits(ix as u64) + (spread_bits(iy as u64) << 1)
+    } else {
+        let nl4 = 4 * n;
+        let ncap = 2 * n * (n - 1);
+        let pixel = if za <= 2.0 / 3.0 {
+            // Equatorial region
+            let temp1 = n as f64 * (0.5 + tt);
+            let temp2 = n as f64 * z * 0.75;
+            let jp = (temp1 - temp2) as i64;
+            let jm = (temp1 + temp2) as i64;
+            // Ring number counted from z=2/3, and whether it is shifted
+            let ir = n + 1 + jp - jm;
+            let kshift = 1 - (ir & 1);
+            let ip = ((jp + jm - n + kshift + 1 + 2 * nl4) / 2) % nl4;
+            ncap + (ir - 1) * nl4 + ip
+        } else {
+            // Polar caps
+            let tp = tt - tt.floor();
+            let tmp = n as f64 * (3.0 * (1.0 - za)).sqrt();
+            let jp = (tp * tmp) as i64;
+            let jm = ((1.0 - tp) * tmp) as i64;
+            // Ring number counted from the closest pole
+            let ir = jp + jm + 1;
+            let ip = ((tt * ir as f64) as i64).min(4 * ir - 1);
+            if z > 0.0 {
+                2 * ir * (ir - 1) + ip
+            } else {
+                12 * n * n - 2 * ir * (ir + 1) + ip
+            }
+        };
+        pixel as u64
+    }
+}
+
+/// Interleaves the bits of x with zeros.
+fn spread_bits(x: u64) -> u64 {
+    let mut result = 0;
+    for bit in 0..32 {
+        result |= ((x >> bit) & 1) << (2 * bit);
+    }
+    result
+}
@@ -0,0 +1,98 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::Mutex;
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+/// A rejected star, with the values that may have triggered its rejection.
+/// Values that were not computed yet when the star was rejected are NaN.
+pub struct Rejection {
+    pub source_id: i64,
+    pub reason: String,
+    // plx, plx_err, ruwe, appmag and dist_pc
+    pub values: [f64; 5],
+}
+
+/**
+ * Gzipped csv file with the stars rejected by a loader, or a sample of
+ * them, to find out why a star is missing from the catalog. Rows are
+ * written in the order the loader threads reject them.
+ **/
+pub struct RejectedDump {
+    pub path: String,
+    // Fraction of the rejected stars to write, in [0, 1]
+    pub fraction: f64,
+    writer: Mutex<Option<GzEncoder<BufWriter<File>>>>,
+    pub n_written: Mutex<u64>,
+}
+
+impl RejectedDump {
+    pub fn new(path: &str, fraction: f64) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+        let mut writer = GzEncoder::new(BufWriter::new(file), Compression::default());
+        writeln!(writer, "source_id,reason,plx,plx_err,ruwe,appmag,dist_pc")
+            .map_err(|e| format!("{}: {}", path, e))?;
+        Ok(RejectedDump {
+            path: path.to_string(),
+            fraction,
+            writer: Mutex::new(Some(writer)),
+            n_written: Mutex::new(0),
+        })
+    }
+
+    /// Whether the star with the given id is in the sample. The sample
+    /// depends only on the id, so that it is the same in every run.
+    pub fn sampled(&self, source_id: i64) -> bool {
+        if self.fraction >= 1.0 {
+            return true;
+        }
+        // SplitMix64 finalizer, to spread consecutive ids
+        let mut h = source_id as u64;
+        h = (h ^ (h >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        h = (h ^ (h >> 27)).wrapping_mul(0x94d049bb133111eb);
+        h ^= h >> 31;
+        ((h >> 11) as f64 / (1u64 << 53) as f64) < self.fraction
+    }
+
+    pub fn write(&self, rows: &[Rejection]) {
+        let mut writer = self.writer.lock().unwrap();
+        let writer = match writer.as_mut() {
+            Some(writer) => writer,
+            None => return,
+        };
+        for row in rows {
+            let values: Vec<String> = row
+                .values
+                .iter()
+                .map(|v| {
+                    // Single precision is enough, and prints ruwe without noise
+                    if v.is_finite() {
+                        (*v as f32).to_string()
+                    } else {
+                        String::new()
+                    }
+                })
+                .collect();
+            writeln!(
+                writer,
+                "{},\"{}\",{}",
+                row.source_id,
+                row.reason.replace('"', "\"\""),
+                values.join(",")
+            )
+            .expect("Error writing rejected stars");
+        }
+        *self.n_written.lock().unwrap() += rows.len() as u64;
+    }
+
+    /// Finishes the gzip stream. Later writes are ignored.
+    pub fn finish(&self) {
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            writer
+                .finish()
+                .and_then(|mut w| w.flush())
+                .expect("Error writing rejected stars");
+        }
+    }
+}
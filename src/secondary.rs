@@ -0,0 +1,107 @@
+use crate::color::TeffCalibration;
+use crate::constants;
+use crate::hipparcos::CatalogFormat;
+use crate::merge::MergePolicy;
+
+/**
+ * Configuration of a secondary catalog to merge into the Gaia catalog,
+ * like Hipparcos, Tycho-2 or a hand-curated list of nearby stars. Stars
+ * are identified by their source_id column, or by their hip column if
+ * there is no source_id, and need a parallax to be loaded.
+ **/
+pub struct CatalogSpec {
+    pub name: String,
+    pub path: String,
+    // Columns, separator and header of the files
+    pub format: CatalogFormat,
+    // Whether to take the columns from the header row of the files
+    pub header_columns: bool,
+    // Reference epoch of the positions (Julian year)
+    pub epoch: f64,
+    // Relative and absolute parallax error thresholds
+    pub plx_err: f64,
+    pub plx_err_cap: f64,
+    // Crossmatch file, or radius (arcsec) and maximum magnitude
+    // difference of the positional crossmatch if there is none. The
+    // magnitudes are only compared if the difference is finite
+    pub xmatch_file: String,
+    pub xmatch_radius: f64,
+    pub xmatch_max_dmag: f32,
+    pub policy: MergePolicy,
+    // Relations to compute T_eff from the colour indices
+    pub teff_cal: TeffCalibration,
+}
+
+impl CatalogSpec {
+    /// Parses a ';'-separated list of key=value pairs, like
+    /// 'name=nearby;path=nearby.csv;columns=source_id,ra,dec,plx,gmag;epoch=2000'.
+    /// The keys are name and path (required), format (a Hipparcos format, csv
+    /// by default), columns, header, epoch (J2000 by default), plxerr, plxerrcap,
+    /// xmatchfile, xmatchradius, xmatchmaxdmag, mergepolicy and teffcal. If a csv
+    /// catalog has no columns, they are taken from its header row.
+    pub fn from_str(input: &str) -> Result<CatalogSpec, String> {
+        let mut spec = CatalogSpec {
+            name: String::new(),
+            path: String::new(),
+            format: CatalogFormat::from_str("csv").unwrap(),
+            header_columns: false,
+            epoch: constants::J2000_EPOCH,
+            plx_err: 1000.0,
+            plx_err_cap: 1000.0,
+            xmatch_file: String::new(),
+            xmatch_radius: 1.0,
+            xmatch_max_dmag: f32::INFINITY,
+            policy: MergePolicy::default(),
+            teff_cal: TeffCalibration::default(),
+        };
+        let mut columns = None;
+        let mut header = None;
+        for token in input.split(';').map(|t| t.trim()).filter(|t| !t.is_empty()) {
+            let (key, value) = token
+                .split_once('=')
+                .ok_or(format!("expected key=value, got '{}'", token))?;
+            let value = value.trim();
+            let number = |v: &str| {
+                v.parse::<f64>()
+                    .map_err(|_| format!("invalid value of {}: '{}'", key, v))
+            };
+            match key.trim() {
+                "name" => spec.name = value.to_string(),
+                "path" => spec.path = value.to_string(),
+                "format" => {
+                    spec.format = CatalogFormat::from_str(value)
+                        .ok_or(format!("unknown format '{}'", value))?
+                }
+                "columns" => columns = Some(value.to_string()),
+                "header" => {
+                    header = Some(
+                        value
+                            .parse::<bool>()
+                            .map_err(|_| format!("invalid value of header: '{}'", value))?,
+                    )
+                }
+                "epoch" => spec.epoch = number(value)?,
+                "plxerr" => spec.plx_err = number(value)?,
+                "plxerrcap" => spec.plx_err_cap = number(value)?,
+                "xmatchfile" => spec.xmatch_file = value.to_string(),
+                "xmatchradius" => spec.xmatch_radius = number(value)?,
+                "xmatchmaxdmag" => spec.xmatch_max_dmag = number(value)? as f32,
+                "mergepolicy" => spec.policy = MergePolicy::from_str(value)?,
+                "teffcal" => spec.teff_cal = TeffCalibration::from_str(value)?,
+                k => return Err(format!("unknown key '{}'", k)),
+            }
+        }
+        if spec.name.is_empty() || spec.path.is_empty() {
+            return Err(format!("name and path are required in '{}'", input));
+        }
+        if let Some(header) = header {
+            spec.format.header = header;
+        }
+        match columns {
+            Some(columns) => spec.format.columns = columns,
+            // Plain csv files without columns use their header row
+            None => spec.header_columns = spec.format.name == "csv" && spec.format.header,
+        }
+        Ok(spec)
+    }
+}
@@ -0,0 +1,344 @@
+use crate::data;
+
+use memmap::Mmap;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use data::{Particle, StarSource, StarStore, Vec3};
+
+// Size of a star record: x, y, z (f64), 13 f32 attributes,
+// hip (i32), id (i64), names offset (u64) and names length (u32).
+const RECORD_SIZE: usize = 3 * 8 + 13 * 4 + 4 + 8 + 8 + 4;
+
+/**
+ * Writes stars to a star file, which consists of a file with
+ * fixed-size records (.bin) and a file with the names (.names).
+ * Values are written in native byte order, as the files are only
+ * read by this same process.
+ **/
+pub struct StarFileWriter {
+    base: PathBuf,
+    records: BufWriter<File>,
+    names: BufWriter<File>,
+    names_len: u64,
+    len: usize,
+}
+
+impl StarFileWriter {
+    pub fn create(base: &Path) -> Self {
+        let open = |ext: &str| {
+            let file = base.with_extension(ext);
+            BufWriter::new(
+                File::create(&file)
+                    .unwrap_or_else(|e| panic!("Error creating {}: {}", file.display(), e)),
+            )
+        };
+        StarFileWriter {
+            base: base.to_path_buf(),
+            records: open("bin"),
+            names: open("names"),
+            names_len: 0,
+            len: 0,
+        }
+    }
+
+    pub fn write(&mut self, p: &Particle) {
+        let names = p.names.join("|");
+        let mut rec = Vec::with_capacity(RECORD_SIZE);
+        for v in [p.x, p.y, p.z] {
+            rec.extend(v.to_ne_bytes());
+        }
+        for v in [
+            p.pmx, p.pmy, p.pmz, p.mualpha, p.mudelta, p.radvel, p.appmag, p.absmag, p.col, p.teff,
+            p.size, p.plx_err, p.ruwe,
+        ] {
+            rec.extend(v.to_ne_bytes());
+        }
+        rec.extend(p.hip.to_ne_bytes());
+        rec.extend(p.id.to_ne_bytes());
+        rec.extend(self.names_len.to_ne_bytes());
+        rec.extend((names.len() as u32).to_ne_bytes());
+        self.records
+            .write_all(&rec)
+            .expect("Error writing star file");
+        self.names
+            .write_all(names.as_bytes())
+            .expect("Error writing star file");
+        self.names_len += names.len() as u64;
+        self.len += 1;
+    }
+
+    /// Flushes the files and returns the base path and the number of stars.
+    pub fn finish(mut self) -> (PathBuf, usize) {
+        self.records.flush().expect("Error writing star file");
+        self.names.flush().expect("Error writing star file");
+        (self.base, self.len)
+    }
+}
+
+/// Decodes a star record. The names are given separately.
+fn decode(rec: &[u8], names: &str) -> Particle {
+    let f64_at = |i: usize| f64::from_ne_bytes(rec[i..i + 8].try_into().unwrap());
+    let f32_at = |i: usize| f32::from_ne_bytes(rec[i..i + 4].try_into().unwrap());
+    Particle {
+        x: f64_at(0),
+        y: f64_at(8),
+        z: f64_at(16),
+        pmx: f32_at(24),
+        pmy: f32_at(28),
+        pmz: f32_at(32),
+        mualpha: f32_at(36),
+        mudelta: f32_at(40),
+        radvel: f32_at(44),
+        appmag: f32_at(48),
+        absmag: f32_at(52),
+        col: f32_at(56),
+        teff: f32_at(60),
+        size: f32_at(64),
+        plx_err: f32_at(68),
+        ruwe: f32_at(72),
+        hip: i32::from_ne_bytes(rec[76..80].try_into().unwrap()),
+        id: i64::from_ne_bytes(rec[80..88].try_into().unwrap()),
+        names: if names.is_empty() {
+            Vec::new()
+        } else {
+            names.split('|').map(String::from).collect()
+        },
+    }
+}
+
+/// Gets the names offset and length of a star record.
+fn names_range(rec: &[u8]) -> (usize, usize) {
+    let offset = u64::from_ne_bytes(rec[88..96].try_into().unwrap()) as usize;
+    let len = u32::from_ne_bytes(rec[96..100].try_into().unwrap()) as usize;
+    (offset, len)
+}
+
+/**
+ * Reads the stars of a star file sequentially.
+ **/
+struct StarFileReader {
+    records: BufReader<File>,
+    names: BufReader<File>,
+}
+
+impl StarFileReader {
+    fn open(base: &Path) -> Self {
+        let open = |ext: &str| {
+            let file = base.with_extension(ext);
+            BufReader::new(
+                File::open(&file)
+                    .unwrap_or_else(|e| panic!("Error opening {}: {}", file.display(), e)),
+            )
+        };
+        StarFileReader {
+            records: open("bin"),
+            names: open("names"),
+        }
+    }
+}
+
+impl Iterator for StarFileReader {
+    type Item = Particle;
+
+    fn next(&mut self) -> Option<Particle> {
+        let mut rec = [0_u8; RECORD_SIZE];
+        if self.records.read_exact(&mut rec).is_err() {
+            return None;
+        }
+        let (_, len) = names_range(&rec);
+        let mut names = vec![0_u8; len];
+        self.names
+            .read_exact(&mut names)
+            .expect("Error reading star file");
+        Some(decode(&rec, &String::from_utf8_lossy(&names)))
+    }
+}
+
+/**
+ * A star file mapped into memory, with random access to the stars.
+ * This is what the particle writer reads from in out-of-core mode.
+ **/
+pub struct MappedStars {
+    records: Option<Mmap>,
+    names: Option<Mmap>,
+    len: usize,
+}
+
+impl MappedStars {
+    pub fn open(base: &Path, len: usize) -> Self {
+        // Empty files can't be mapped
+        let map = |ext: &str| {
+            let file = base.with_extension(ext);
+            let f = File::open(&file)
+                .unwrap_or_else(|e| panic!("Error opening {}: {}", file.display(), e));
+            if f.metadata().map(|m| m.len()).unwrap_or(0) == 0 {
+                None
+            } else {
+                Some(unsafe { Mmap::map(&f).expect("Error mapping star file") })
+            }
+        };
+        MappedStars {
+            records: map("bin"),
+            names: map("names"),
+            len,
+        }
+    }
+}
+
+impl StarSource for MappedStars {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn get(&self, i: usize) -> Particle {
+        let records = self.records.as_ref().expect("Star file is empty");
+        let rec = &records[i * RECORD_SIZE..(i + 1) * RECORD_SIZE];
+        let (offset, len) = names_range(rec);
+        let names = match &self.names {
+            Some(names) => String::from_utf8_lossy(&names[offset..offset + len]),
+            None => "".into(),
+        };
+        decode(rec, &names)
+    }
+}
+
+/**
+ * Spills lists of stars to disk as runs sorted by absolute magnitude,
+ * keeping track of the number of stars and their bounds. The runs are
+ * then merged into a single sorted stream.
+ **/
+pub struct Spiller {
+    dir: PathBuf,
+    runs: Vec<PathBuf>,
+    pub len: usize,
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Spiller {
+    pub fn new(dir: &str) -> Self {
+        fs::create_dir_all(dir).unwrap_or_else(|e| panic!("Error creating {}: {}", dir, e));
+        Spiller {
+            dir: PathBuf::from(dir),
+            runs: Vec::new(),
+            len: 0,
+            min: Vec3::with(1.0e50),
+            max: Vec3::with(-1.0e50),
+        }
+    }
+
+    /// Sorts the given stars and writes them to a new run.
+    pub fn spill(&mut self, mut list: StarStore) {
+        if list.is_empty() {
+            return;
+        }
+        list.sort_by_absmag();
+        let (min, max) = list.bounds();
+        self.min.set(
+            f64::min(self.min.x, min.x),
+            f64::min(self.min.y, min.y),
+            f64::min(self.min.z, min.z),
+        );
+        self.max.set(
+            f64::max(self.max.x, max.x),
+            f64::max(self.max.y, max.y),
+            f64::max(self.max.z, max.z),
+        );
+
+        let base = self.dir.join(format!("run_{:05}", self.runs.len()));
+        let mut writer = StarFileWriter::create(&base);
+        for i in 0..list.len() {
+            writer.write(&list.get(i));
+        }
+        let (base, len) = writer.finish();
+        log::info!("Spilled {} stars to {}", len, base.display());
+        self.runs.push(base);
+        self.len += len;
+    }
+
+    /// Gets the path of a file in the spill directory.
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    /// Merges the runs into a single stream sorted by absolute magnitude.
+    /// Stars with the same magnitude come in run order, so that the result
+    /// is the same as sorting all the stars at once.
+    pub fn merge(&self) -> Merge {
+        let mut readers: Vec<StarFileReader> = self
+            .runs
+            .iter()
+            .map(|run| StarFileReader::open(run))
+            .collect();
+        let mut heads = Vec::with_capacity(readers.len());
+        let mut heap = BinaryHeap::new();
+        for (i, reader) in readers.iter_mut().enumerate() {
+            let head = reader.next();
+            if let Some(p) = &head {
+                heap.push(Reverse(Key(p.absmag, i)));
+            }
+            heads.push(head);
+        }
+        Merge {
+            readers,
+            heads,
+            heap,
+        }
+    }
+
+    /// Removes the spill directory.
+    pub fn clean(&self) {
+        if let Err(e) = fs::remove_dir_all(&self.dir) {
+            log::warn!("Error removing {}: {}", self.dir.display(), e);
+        }
+    }
+}
+
+/// Merge key: absolute magnitude and run index.
+struct Key(f32, usize);
+
+impl Ord for Key {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+impl PartialOrd for Key {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Key {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Key {}
+
+/**
+ * K-way merge of the spilled runs.
+ **/
+pub struct Merge {
+    readers: Vec<StarFileReader>,
+    heads: Vec<Option<Particle>>,
+    heap: BinaryHeap<Reverse<Key>>,
+}
+
+impl Iterator for Merge {
+    type Item = Particle;
+
+    fn next(&mut self) -> Option<Particle> {
+        let Reverse(Key(_, i)) = self.heap.pop()?;
+        let next = self.readers[i].next();
+        if let Some(p) = &next {
+            self.heap.push(Reverse(Key(p.absmag, i)));
+        }
+        std::mem::replace(&mut self.heads[i], next)
+    }
+}
@@ -0,0 +1,92 @@
+use crate::load::ColId;
+
+/**
+ * A column of a self-describing table (VOTable, ECSV),
+ * with its name and unit as given in the file header.
+ **/
+pub struct TableColumn {
+    pub name: String,
+    pub unit: String,
+}
+
+/**
+ * A table read fully into memory. Values are kept
+ * as strings, and nulls are represented by None.
+ **/
+pub struct Table {
+    pub columns: Vec<TableColumn>,
+    pub rows: Vec<Vec<Option<String>>>,
+}
+
+impl Table {
+    pub fn names(&self) -> Vec<&str> {
+        self.columns.iter().map(|c| c.name.as_str()).collect()
+    }
+}
+
+/// Gets the factor to convert values of the given column in the given unit
+/// to the units expected by the loader: degrees for positions, mas for parallaxes,
+/// mas/yr for proper motions, km/s for radial velocities and pc for distances.
+/// An empty unit is assumed to be correct. Returns None if the unit is not recognised.
+pub fn unit_factor(col_id: ColId, unit: &str) -> Option<f64> {
+    let unit = normalize_unit(unit);
+    if unit.is_empty() {
+        return Some(1.0);
+    }
+    match col_id {
+        ColId::ra | ColId::dec | ColId::ecl_lat => angle_to_mas(&unit).map(|f| f / 3.6e6),
+        ColId::plx | ColId::plx_err => angle_to_mas(&unit),
+        ColId::pmra | ColId::pmdec | ColId::pmra_err | ColId::pmdec_err => {
+            match unit.strip_suffix("/yr") {
+                Some(angle) => angle_to_mas(angle),
+                None => None,
+            }
+        }
+        ColId::radvel | ColId::radvel_err => match unit.as_str() {
+            "km/s" => Some(1.0),
+            "m/s" => Some(1.0e-3),
+            _ => None,
+        },
+        ColId::geodist | ColId::phot_dist => match unit.as_str() {
+            "pc" => Some(1.0),
+            "kpc" => Some(1.0e3),
+            _ => None,
+        },
+        // The rest of the columns are used as they are
+        _ => Some(1.0),
+    }
+}
+
+/// Normalizes the different unit notations (VOUnit, astropy) so that
+/// 'mas.yr**-1', 'mas / yr' and 'mas/a' all become 'mas/yr'.
+fn normalize_unit(unit: &str) -> String {
+    let mut unit: String = unit.chars().filter(|c| !c.is_whitespace()).collect();
+    for (from, to) in [
+        ("**-1", "-1"),
+        ("^-1", "-1"),
+        ("\u{b5}", "u"),
+        ("\u{3bc}", "u"),
+        ("degrees", "deg"),
+        ("degree", "deg"),
+        ("year", "yr"),
+        (".yr-1", "/yr"),
+        (".a-1", "/yr"),
+        ("/a", "/yr"),
+        (".s-1", "/s"),
+    ] {
+        unit = unit.replace(from, to);
+    }
+    unit
+}
+
+fn angle_to_mas(unit: &str) -> Option<f64> {
+    match unit {
+        "mas" => Some(1.0),
+        "uas" => Some(1.0e-3),
+        "arcsec" | "\"" => Some(1.0e3),
+        "arcmin" | "'" => Some(6.0e4),
+        "deg" => Some(3.6e6),
+        "rad" => Some(3.6e6 * 180.0 / std::f64::consts::PI),
+        _ => None,
+    }
+}
@@ -16,3 +16,1482 @@ fn test_large_long_map() {
     assert_eq!(155, *map.get(155).unwrap());
     assert_eq!(28, *map.get(39482).unwrap());
 }
+
+#[cfg(test)]
+use parquet::data_type::{ByteArray, ByteArrayType, DoubleType, FloatType, Int64Type};
+#[cfg(test)]
+use parquet::file::writer::SerializedFileWriter;
+
+#[test]
+fn test_parquet() {
+    // Columns matched by name, in any order, with an unknown one, typed
+    // values (FLOAT parallax, INT64 ids) and nulls
+    let schema = parquet::schema::parser::parse_message_type(
+        "message gaia {
+            OPTIONAL DOUBLE dec;
+            REQUIRED INT64 source_id;
+            OPTIONAL BYTE_ARRAY foo (UTF8);
+            OPTIONAL DOUBLE ra;
+            OPTIONAL FLOAT parallax;
+            OPTIONAL DOUBLE parallax_error;
+            OPTIONAL DOUBLE phot_g_mean_mag;
+            OPTIONAL BYTE_ARRAY names (UTF8);
+        }",
+    )
+    .unwrap();
+    let dir = std::env::temp_dir().join(format!("catgen_parquet_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("gaia.parquet");
+    let mut writer = SerializedFileWriter::new(
+        std::fs::File::create(&file).unwrap(),
+        std::sync::Arc::new(schema),
+        Default::default(),
+    )
+    .unwrap();
+    let mut rg = writer.next_row_group().unwrap();
+    let doubles =
+        |rg: &mut parquet::file::writer::SerializedRowGroupWriter<_>, v: &[f64], d: &[i16]| {
+            let mut col = rg.next_column().unwrap().unwrap();
+            col.typed::<DoubleType>()
+                .write_batch(v, Some(d), None)
+                .unwrap();
+            col.close().unwrap();
+        };
+    doubles(&mut rg, &[-16.7, 38.8, 10.0], &[1, 1, 1]);
+    let mut col = rg.next_column().unwrap().unwrap();
+    col.typed::<Int64Type>()
+        .write_batch(&[5937173300407375616, 2, 3], None, None)
+        .unwrap();
+    col.close().unwrap();
+    let mut col = rg.next_column().unwrap().unwrap();
+    col.typed::<ByteArrayType>()
+        .write_batch(&[ByteArray::from("bar")], Some(&[0, 1, 0]), None)
+        .unwrap();
+    col.close().unwrap();
+    doubles(&mut rg, &[101.3, 279.2, 20.0], &[1, 1, 1]);
+    let mut col = rg.next_column().unwrap().unwrap();
+    col.typed::<FloatType>()
+        .write_batch(&[379.2, 130.2], Some(&[1, 0, 1]), None)
+        .unwrap();
+    col.close().unwrap();
+    doubles(&mut rg, &[0.1, 0.1, 0.1], &[1, 1, 1]);
+    doubles(&mut rg, &[-1.1, 5.0], &[1, 0, 1]);
+    let mut col = rg.next_column().unwrap().unwrap();
+    col.typed::<ByteArrayType>()
+        .write_batch(&[ByteArray::from("Sirius|alf CMa")], Some(&[1, 0, 0]), None)
+        .unwrap();
+    col.close().unwrap();
+    rg.close().unwrap();
+    writer.close().unwrap();
+
+    let new_loader = |max_records: i32| {
+        crate::load::Loader::new(crate::load::LoaderConfig {
+            max_records,
+            plx_err_faint: 1000.0,
+            plx_err_bright: 1000.0,
+            plx_err_cap: 1000.0,
+            mag_corrections: 0,
+            ..Default::default()
+        })
+    };
+    let mut loader = new_loader(-1);
+    let list = loader.load_dir(file.to_str().unwrap()).unwrap();
+    // The second star has a null parallax
+    assert_eq!(2, list.len());
+    let star = list.get(0);
+    assert_eq!(5937173300407375616, star.id);
+    assert_eq!(vec!["Sirius", "alf CMa"], star.names);
+    let sph = crate::util::cartesian_to_spherical(star.x, star.y, star.z);
+    assert!((sph.x - 101.3_f64.to_radians()).abs() < 1e-9);
+    assert!((sph.y + 16.7_f64.to_radians()).abs() < 1e-9);
+    assert!((sph.z * crate::constants::U_TO_PC - 1000.0 / 379.2_f32 as f64).abs() < 1e-6);
+    assert!((star.appmag + 1.1).abs() < 1e-6);
+    let star = list.get(1);
+    assert_eq!(3, star.id);
+    assert!(star.names.is_empty());
+    assert!((star.appmag - 5.0).abs() < 1e-6);
+
+    let mut loader = new_loader(1);
+    let list = loader.load_dir(file.to_str().unwrap()).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(1, list.len());
+    assert_eq!(5937173300407375616, list.get(0).id);
+}
+
+#[cfg(test)]
+use crate::fits::FitsTable;
+
+#[cfg(test)]
+fn fits_block(cards: &[String], fill: u8) -> Vec<u8> {
+    let mut bytes: Vec<u8> = cards
+        .iter()
+        .flat_map(|c| format!("{:80}", c).into_bytes())
+        .collect();
+    bytes.resize(bytes.len().div_ceil(2880) * 2880, fill);
+    bytes
+}
+
+#[test]
+fn test_fits_table() {
+    let primary = [
+        "SIMPLE  =                    T",
+        "BITPIX  =                    8",
+        "NAXIS   =                    0",
+        "END",
+    ];
+    let table = [
+        "XTENSION= 'BINTABLE'",
+        "BITPIX  =                    8",
+        "NAXIS   =                    2",
+        "NAXIS1  =                   20",
+        "NAXIS2  =                    2",
+        "PCOUNT  =                    0",
+        "GCOUNT  =                    1",
+        "TFIELDS =                    3",
+        "TTYPE1  = 'source_id'",
+        "TFORM1  = 'K       '",
+        "TTYPE2  = 'parallax'           / [mas]",
+        "TFORM2  = '1D      '",
+        "TTYPE3  = 'flag    '",
+        "TFORM3  = 'J       '",
+        "TNULL3  =                   -1",
+        "END",
+    ];
+    let mut bytes = fits_block(&primary.map(String::from), b' ');
+    bytes.extend(fits_block(&table.map(String::from), b' '));
+    let mut data = Vec::new();
+    data.extend(5853498713190525696_i64.to_be_bytes());
+    data.extend(1.5_f64.to_be_bytes());
+    data.extend(3_i32.to_be_bytes());
+    data.extend(42_i64.to_be_bytes());
+    data.extend(f64::NAN.to_be_bytes());
+    data.extend((-1_i32).to_be_bytes());
+    data.resize(2880, 0);
+    bytes.extend(data);
+
+    let table = FitsTable::parse(bytes).unwrap();
+    assert_eq!(vec!["source_id", "parallax", "flag"], table.names());
+    assert_eq!(2, table.n_rows);
+    assert_eq!(Some(5853498713190525696), table.get_i64(0, 0));
+    assert_eq!(Some(1.5), table.get_f64(0, 1));
+    assert_eq!(Some(3.0), table.get_f64(0, 2));
+    assert_eq!(None, table.get_f64(1, 1));
+    assert_eq!(None, table.get_i64(1, 2));
+    assert_eq!(None, table.get_str(0, 0));
+}
+
+#[cfg(test)]
+use crate::{ecsv, load::ColId, table, votable};
+
+#[test]
+fn test_ecsv_table() {
+    let text = "# %ECSV 1.0
+# ---
+# delimiter: ','
+# datatype:
+# - {name: source_id, datatype: int64}
+# - {name: ra, unit: deg, datatype: float64,
+#   description: 'Right ascension, ICRS'}
+# - name: parallax
+#   unit: mas
+#   datatype: float64
+source_id,ra,parallax
+42,10.5,
+43,\"11.5\",null
+";
+    let table = ecsv::parse(Box::new(text.as_bytes())).unwrap();
+    assert_eq!(vec!["source_id", "ra", "parallax"], table.names());
+    assert_eq!("deg", table.columns[1].unit);
+    assert_eq!("mas", table.columns[2].unit);
+    assert_eq!(2, table.rows.len());
+    assert_eq!(Some("11.5".to_string()), table.rows[1][1]);
+    assert_eq!(None, table.rows[0][2]);
+    assert_eq!(None, table.rows[1][2]);
+}
+
+#[test]
+fn test_votable_table() {
+    let text = r#"<VOTABLE><RESOURCE><TABLE>
+<FIELD name="source_id" datatype="long"/>
+<FIELD name="parallax" datatype="double" unit="mas"><VALUES null="-999"/></FIELD>
+<DATA><TABLEDATA>
+<TR><TD>42</TD><TD>1.5</TD></TR>
+<TR><TD>43</TD><TD>-999</TD></TR>
+</TABLEDATA></DATA></TABLE></RESOURCE></VOTABLE>"#;
+    let table = votable::parse(Box::new(text.as_bytes())).unwrap();
+    assert_eq!(vec!["source_id", "parallax"], table.names());
+    assert_eq!("mas", table.columns[1].unit);
+    assert_eq!(Some("1.5".to_string()), table.rows[0][1]);
+    assert_eq!(None, table.rows[1][1]);
+}
+
+#[test]
+fn test_unit_factor() {
+    assert_eq!(Some(1.0), table::unit_factor(ColId::ra, "deg"));
+    assert_eq!(Some(1.0), table::unit_factor(ColId::plx, ""));
+    assert_eq!(Some(1.0e3), table::unit_factor(ColId::plx, "arcsec"));
+    assert_eq!(Some(1.0), table::unit_factor(ColId::pmra, "mas.yr**-1"));
+    assert_eq!(Some(1.0e-3), table::unit_factor(ColId::radvel, "m / s"));
+    assert_eq!(None, table::unit_factor(ColId::plx, "furlong"));
+}
+
+#[test]
+fn test_header_columns() {
+    // Two files with their columns in different orders, with names and
+    // unrecognised columns that are ignored
+    let (indices, unknown) =
+        crate::load::Loader::indices_from_names(&["source_id", "foo", "parallax", "plx", "ra"]);
+    assert_eq!(vec!["foo"], unknown);
+    assert_eq!(Some(&2), indices.get(&ColId::plx));
+    assert_eq!(Some(&4), indices.get(&ColId::ra));
+
+    let dir = std::env::temp_dir().join(format!("catgen_header_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("a.csv"),
+        "source_id,ra,dec,plx,plx_err,gmag,foo\n1,10,20,5.0,0.1,10.0,abc\n",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("b.csv"),
+        "phot_g_mean_mag,parallax_error,bar,parallax,dec,ra,source_id\n11.0,0.1,xyz,2.0,-30,200,2\n",
+    )
+    .unwrap();
+    // File without parallaxes, which is skipped
+    std::fs::write(
+        dir.join("c.csv"),
+        "source_id,ra,dec,phot_g_mean_mag\n3,10,20,12.0\n",
+    )
+    .unwrap();
+    // The units of --columns are positional, so they do not apply to the headers
+    let mut loader = crate::load::Loader::new(crate::load::LoaderConfig {
+        mag_corrections: 0,
+        columns: "source_id,ra[rad],dec[rad],plx,plx_err,gmag".to_string(),
+        header_columns: true,
+        ..Default::default()
+    });
+    assert!(!loader.settings.factors.is_empty());
+    let list = loader.load_dir(dir.to_str().unwrap()).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(2, list.len());
+    let expected = [(1, 10.0, 20.0, 5.0, 10.0), (2, 200.0, -30.0, 2.0, 11.0)];
+    for (i, (id, ra, dec, plx, gmag)) in expected.iter().enumerate() {
+        let star = list.get(i);
+        assert_eq!(*id, star.id);
+        let sph = crate::util::cartesian_to_spherical(star.x, star.y, star.z);
+        let ra_star = sph.x.to_degrees().rem_euclid(360.0);
+        assert!((ra_star - ra).abs() < 1e-9);
+        assert!((sph.y.to_degrees() - dec).abs() < 1e-9);
+        assert!((sph.z * crate::constants::U_TO_PC - 1000.0 / plx).abs() < 1e-6);
+        assert!((star.appmag as f64 - gmag).abs() < 1e-6);
+    }
+    // The loader's own indices are restored after each file
+    assert_eq!(Some(&1), loader.indices.get(&ColId::ra));
+}
+
+#[test]
+fn test_typed_tables() {
+    // A FITS table read from its typed values, with a 64-bit ID and a
+    // null parallax, and an ECSV table with the parallax in arcsec
+    let dir = std::env::temp_dir().join(format!("catgen_typed_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let table = [
+        "XTENSION= 'BINTABLE'",
+        "BITPIX  =                    8",
+        "NAXIS   =                    2",
+        "NAXIS1  =                   36",
+        "NAXIS2  =                    2",
+        "PCOUNT  =                    0",
+        "GCOUNT  =                    1",
+        "TFIELDS =                    6",
+        "TTYPE1  = 'source_id'",
+        "TFORM1  = 'K       '",
+        "TTYPE2  = 'ra      '",
+        "TFORM2  = 'D       '",
+        "TTYPE3  = 'dec     '",
+        "TFORM3  = 'D       '",
+        "TTYPE4  = 'parallax'",
+        "TFORM4  = 'E       '",
+        "TTYPE5  = 'phot_g_mean_mag'",
+        "TFORM5  = 'E       '",
+        "TTYPE6  = 'parallax_error'",
+        "TFORM6  = 'E       '",
+        "END",
+    ];
+    let primary = [
+        "SIMPLE  =                    T",
+        "NAXIS   =                    0",
+        "END",
+    ];
+    let mut bytes = fits_block(&primary.map(String::from), b' ');
+    bytes.extend(fits_block(&table.map(String::from), b' '));
+    let mut data = Vec::new();
+    for (id, plx) in [(5853498713190525697_i64, 5.0_f32), (2, f32::NAN)] {
+        data.extend(id.to_be_bytes());
+        data.extend(10.0_f64.to_be_bytes());
+        data.extend(20.0_f64.to_be_bytes());
+        data.extend(plx.to_be_bytes());
+        data.extend(12.0_f32.to_be_bytes());
+        data.extend(0.01_f32.to_be_bytes());
+    }
+    data.resize(2880, 0);
+    bytes.extend(data);
+    std::fs::write(dir.join("a.fits"), bytes).unwrap();
+    std::fs::write(
+        dir.join("b.ecsv"),
+        "# %ECSV 1.0
+# ---
+# datatype:
+# - {name: source_id, datatype: int64}
+# - {name: ra, unit: deg, datatype: float64}
+# - {name: dec, unit: deg, datatype: float64}
+# - {name: parallax, unit: arcsec, datatype: float64}
+# - {name: phot_g_mean_mag, datatype: float64}
+# - {name: parallax_error, unit: arcsec, datatype: float64}
+source_id ra dec parallax phot_g_mean_mag parallax_error
+3 200.0 -30.0 0.002 11.0 0.00001
+",
+    )
+    .unwrap();
+    let mut loader = crate::load::Loader::new(crate::load::LoaderConfig {
+        mag_corrections: 0,
+        ..Default::default()
+    });
+    let list = loader.load_dir(dir.to_str().unwrap()).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(2, list.len());
+    let mut ids: Vec<i64> = (0..list.len()).map(|i| list.get(i).id).collect();
+    ids.sort();
+    assert_eq!(vec![3, 5853498713190525697], ids);
+    for i in 0..list.len() {
+        let star = list.get(i);
+        let plx = if star.id == 3 { 2.0 } else { 5.0 };
+        let dist = crate::util::cartesian_to_spherical(star.x, star.y, star.z).z;
+        assert!((dist * crate::constants::U_TO_PC - 1000.0 / plx).abs() < 1e-6);
+    }
+}
+
+#[test]
+fn test_parallel_load() {
+    // Several files loaded with 1 and 3 threads give the same stars, in
+    // the same order, and the same counters
+    let dir = std::env::temp_dir().join(format!("catgen_parallel_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    for f in 0..7 {
+        let mut content = String::from("source_id,ra,dec,plx,plx_err,gmag,bpmag,rpmag\n");
+        for i in 0..60 {
+            let k = i % 6;
+            let plx = if k == 3 { -1.0 } else { 1.0 + k as f64 };
+            let plx_err = if k == 4 { 5.0 } else { 0.1 };
+            let (bp, rp) = if k == 5 {
+                (String::new(), String::new())
+            } else {
+                ("9.3".to_string(), "8.1".to_string())
+            };
+            content.push_str(&format!(
+                "{},{},{},{},{},{},{},{}\n",
+                f * 10000 + i,
+                (10 * f + i) % 360,
+                5 * k - 10,
+                plx,
+                plx_err,
+                5 + f + k,
+                bp,
+                rp
+            ));
+        }
+        std::fs::write(dir.join(format!("gaia_{:02}.csv", f)), content).unwrap();
+    }
+
+    let load = |n_threads: usize| {
+        let dump = std::env::temp_dir().join(format!(
+            "catgen_parallel_{}_{}.csv.gz",
+            std::process::id(),
+            n_threads
+        ));
+        let mut loader = crate::load::Loader::new(crate::load::LoaderConfig {
+            columns: "source_id,ra,dec,plx,plx_err,gmag,bpmag,rpmag".to_string(),
+            filter: "gmag < 14".to_string(),
+            rejected_dump: Some(RejectedDump::new(dump.to_str().unwrap(), 1.0).unwrap()),
+            n_threads,
+            ..Default::default()
+        });
+        let list = loader.load_dir(dir.to_str().unwrap()).unwrap();
+        let mut rejected = String::new();
+        std::io::Read::read_to_string(
+            &mut flate2::read::GzDecoder::new(std::fs::File::open(&dump).unwrap()),
+            &mut rejected,
+        )
+        .unwrap();
+        std::fs::remove_file(&dump).unwrap();
+        (loader, list, rejected)
+    };
+    let (seq, seq_list, seq_rejected) = load(1);
+    let (par, par_list, par_rejected) = load(3);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // The rejected stars are written in file order too
+    assert!(seq_rejected.lines().count() > 2);
+    assert_eq!(seq_rejected, par_rejected);
+
+    let counters = &seq.counters;
+    assert!(counters.total_loaded > 0 && counters.rejected_plx_neg > 0);
+    assert!(counters.rejected_filter[0] > 0);
+    assert_eq!(seq_list.len(), par_list.len());
+    for i in 0..seq_list.len() {
+        let (a, b) = (seq_list.get(i), par_list.get(i));
+        assert_eq!((a.id, a.hip, &a.names), (b.id, b.hip, &b.names));
+        assert_eq!(
+            [a.x, a.y, a.z].map(f64::to_bits),
+            [b.x, b.y, b.z].map(f64::to_bits)
+        );
+        assert_eq!(
+            [a.appmag, a.absmag, a.col, a.teff, a.size, a.radvel, a.plx_err, a.ruwe]
+                .map(f32::to_bits),
+            [b.appmag, b.absmag, b.col, b.teff, b.size, b.radvel, b.plx_err, b.ruwe]
+                .map(f32::to_bits)
+        );
+    }
+    assert_eq!(seq.counters, par.counters);
+}
+
+#[cfg(test)]
+use crate::data::{Particle, StarStore};
+
+#[cfg(test)]
+fn star(id: i64, absmag: f32, names: &[&str]) -> Particle {
+    Particle {
+        x: id as f64,
+        y: 0.0,
+        z: 0.0,
+        pmx: 0.0,
+        pmy: 0.0,
+        pmz: 0.0,
+        mualpha: 0.0,
+        mudelta: 0.0,
+        radvel: 0.0,
+        appmag: absmag,
+        absmag,
+        col: 0.0,
+        teff: 0.0,
+        size: 0.0,
+        hip: 0,
+        id,
+        plx_err: f32::NAN,
+        ruwe: f32::NAN,
+        names: names.iter().map(|n| n.to_string()).collect(),
+    }
+}
+
+#[test]
+fn test_star_store() {
+    let mut store = StarStore::new();
+    store.push(star(1, 5.0, &["Sirius", "HIP 32349"]));
+    store.push(star(2, 3.0, &[]));
+    let mut other = StarStore::new();
+    other.push(star(3, 1.0, &["Vega"]));
+    other.push(star(4, 5.0, &["Sirius", "HIP 32349"]));
+    store.append(other);
+    assert_eq!(4, store.len());
+    assert_eq!("Vega", store.names(2));
+
+    store.sort_by_absmag();
+    assert_eq!(vec![3, 2, 1, 4], store.id);
+    assert_eq!(vec![3.0, 2.0, 1.0, 4.0], store.x);
+    assert_eq!("Sirius|HIP 32349", store.names(3));
+    assert_eq!(vec!["Sirius", "HIP 32349"], store.get(2).names);
+    assert!(store.get(1).names.is_empty());
+
+    store.retain_mask(&[true, false, false, true]);
+    assert_eq!(vec![3, 4], store.id);
+    assert_eq!("Vega", store.names(0));
+    assert!(store.get(1).plx_err.is_nan());
+}
+
+#[cfg(test)]
+use crate::data::StarSource;
+#[cfg(test)]
+use crate::spill::{MappedStars, Spiller, StarFileWriter};
+
+#[test]
+fn test_spill_merge() {
+    let dir = std::env::temp_dir().join(format!("catgen_spill_{}", std::process::id()));
+    let mut spiller = Spiller::new(dir.to_str().unwrap());
+    let mut run = StarStore::new();
+    run.push(star(1, 5.0, &["Sirius", "HIP 32349"]));
+    run.push(star(2, 3.0, &[]));
+    spiller.spill(run);
+    let mut run = StarStore::new();
+    run.push(star(3, 1.0, &["Vega"]));
+    run.push(star(4, 5.0, &[]));
+    spiller.spill(run);
+    assert_eq!(4, spiller.len);
+    assert_eq!(1.0, spiller.min.x);
+    assert_eq!(4.0, spiller.max.x);
+
+    // Same order as sorting all stars at once
+    let mut writer = StarFileWriter::create(&spiller.path("sorted"));
+    let ids: Vec<i64> = spiller
+        .merge()
+        .map(|p| {
+            writer.write(&p);
+            p.id
+        })
+        .collect();
+    assert_eq!(vec![3, 2, 1, 4], ids);
+
+    let (base, len) = writer.finish();
+    let stars = MappedStars::open(&base, len);
+    assert_eq!(4, stars.len());
+    assert_eq!(vec!["Vega"], stars.get(0).names);
+    assert_eq!(vec!["Sirius", "HIP 32349"], stars.get(2).names);
+    assert!(stars.get(3).names.is_empty());
+    assert!(stars.get(3).ruwe.is_nan());
+
+    spiller.clean();
+    assert!(!dir.exists());
+}
+
+#[cfg(test)]
+use crate::distance::DistEstimator;
+
+#[test]
+fn test_dist_estimators() {
+    let inv = DistEstimator::from_str("plx", 1350.0).unwrap();
+    let edsd = DistEstimator::from_str("edsd", 1350.0).unwrap();
+    let lk = DistEstimator::from_str("lk", 1350.0).unwrap();
+    assert!(DistEstimator::from_str("foo", 1350.0).is_none());
+
+    // Precise parallaxes: all close to 1/plx
+    assert_eq!(Some(100.0), inv.distance(10.0, 0.01));
+    assert!((edsd.distance(10.0, 0.01).unwrap() - 100.0).abs() < 0.1);
+    assert!((lk.distance(10.0, 0.01).unwrap() - 100.0).abs() < 0.1);
+
+    // Noisy parallaxes: the uniform prior pushes stars farther away
+    let d_lk = lk.distance(1.0, 0.3).unwrap();
+    assert!(d_lk > 1000.0);
+    assert!((d_lk - 2000.0 / (1.0 + 0.28_f64.sqrt())).abs() < 1e-9);
+    assert_eq!(None, lk.distance(1.0, 0.4));
+    assert_eq!(None, lk.distance(-1.0, 0.1));
+
+    // EDSD gives distances for negative parallaxes, beyond the length scale
+    let d_neg = edsd.distance(-0.5, 0.5).unwrap();
+    assert!(d_neg > 1350.0 && d_neg.is_finite());
+    assert_eq!(None, edsd.distance(1.0, f64::NAN));
+    // The mode solves the cubic r^3/L - 2r^2 + (plx/s^2)r - 1/s^2 = 0
+    let r = edsd.distance(0.2, 0.1).unwrap() / 1000.0;
+    assert!((r * r * r / 1.35 - 2.0 * r * r + 20.0 * r - 100.0).abs() < 1e-6);
+}
+
+#[cfg(test)]
+use crate::zeropoint::{self, Solution};
+
+#[test]
+fn test_lindegren_zeropoint() {
+    // North ecliptic pole
+    assert!((zeropoint::ecliptic_latitude(270.0, 66.560_708_9) - 90.0).abs() < 1e-6);
+    assert!(zeropoint::ecliptic_latitude(0.0, 0.0).abs() < 1e-9);
+
+    // At a magnitude node, with nu_eff = 1.48 and beta = 0 only q00 and q02 remain
+    let zpt = zeropoint::zero_point(17.5, 1.48, 0.0, Solution::FiveParam).unwrap();
+    assert!((zpt.value - (-28.37 - 10.77 / 3.0) / 1000.0).abs() < 1e-12);
+    assert!(zpt.calibrated);
+    // Halfway between nodes the coefficients are interpolated
+    let zpt = zeropoint::zero_point(16.8, 1.48, 0.0, Solution::FiveParam).unwrap();
+    let expected = (-36.83 - 28.37) / 2.0 - (14.67 + 10.77) / 6.0;
+    assert!((zpt.value - expected / 1000.0).abs() < 1e-12);
+    // Magnitudes are clamped to the table range, and flagged
+    let zpt = zeropoint::zero_point(3.0, 1.5, 20.0, Solution::SixParam).unwrap();
+    assert!(!zpt.calibrated);
+    assert_eq!(
+        zpt.value,
+        zeropoint::zero_point(6.0, 1.5, 20.0, Solution::SixParam)
+            .unwrap()
+            .value
+    );
+    // And so are colours
+    let zpt = zeropoint::zero_point(15.0, 2.5, 20.0, Solution::FiveParam).unwrap();
+    assert!(!zpt.calibrated);
+    assert_eq!(
+        zpt.value,
+        zeropoint::zero_point(15.0, 1.9, 20.0, Solution::FiveParam)
+            .unwrap()
+            .value
+    );
+    assert!(
+        !zeropoint::zero_point(15.0, 1.8, 20.0, Solution::SixParam)
+            .unwrap()
+            .calibrated
+    );
+    assert_eq!(
+        None,
+        zeropoint::zero_point(f64::NAN, 1.5, 20.0, Solution::FiveParam)
+    );
+    assert_eq!(None, Solution::from_params_solved(3));
+}
+
+#[test]
+fn test_zeropoint_counts() {
+    // Only the Gaia parallaxes that are corrected are counted, and not
+    // the ones coming from an additional catalog
+    use std::io::Write;
+    let dir = std::env::temp_dir().join(format!("catgen_zpt_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let additional = dir.join("additional.csv.gz");
+    let mut gz = flate2::write::GzEncoder::new(
+        std::fs::File::create(&additional).unwrap(),
+        flate2::Compression::default(),
+    );
+    gz.write_all(b"source_id,pllx\n1,4.0\n").unwrap();
+    gz.finish().unwrap();
+    let input = dir.join("input");
+    std::fs::create_dir_all(&input).unwrap();
+    // Star 3 has a colour outside the calibrated range, and star 4 has no colour
+    std::fs::write(
+        input.join("a.csv"),
+        "1,10,20,5.0,0.01,12.0,1.5,20\n2,10,20,5.0,0.01,12.0,1.5,20\n3,10,20,5.0,0.01,12.0,2.5,20\n4,10,20,5.0,0.01,12.0,,20\n",
+    )
+    .unwrap();
+    let mut loader = crate::load::Loader::new(crate::load::LoaderConfig {
+        mag_corrections: 0,
+        columns: "source_id,ra,dec,plx,plx_err,gmag,nu_eff,ecl_lat".to_string(),
+        has_header: false,
+        additional: additional.to_str().unwrap().to_string(),
+        ..Default::default()
+    });
+    let list = loader.load_dir(input.to_str().unwrap()).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(4, list.len());
+    assert_eq!(2, loader.counters.total_zeropoint);
+    assert_eq!(1, loader.counters.total_zeropoint_uncalibrated);
+}
+
+#[cfg(test)]
+use crate::util;
+
+#[test]
+fn test_propagate_epoch() {
+    // Barnard's star, which has the largest proper motion
+    let (ra, dec) = (269.448_502_f64.to_radians(), 4.739_420_f64.to_radians());
+    let (plx, pmra, pmdec, rv) = (546.976, -801.551, 10362.394, -110.353);
+
+    let same = util::propagate_epoch(ra, dec, plx, pmra, pmdec, rv, 0.0);
+    assert!((same.0 - ra).abs() < 1e-12 && (same.1 - dec).abs() < 1e-12);
+    assert!((same.3 - pmra).abs() < 1e-9 && (same.5 - rv).abs() < 1e-9);
+
+    // About 104 arcsec north in ten years, and getting closer
+    let fwd = util::propagate_epoch(ra, dec, plx, pmra, pmdec, rv, 10.0);
+    let ddec_arcsec = (fwd.1 - dec).to_degrees() * 3600.0;
+    assert!((ddec_arcsec - 103.62).abs() < 0.1);
+    assert!(fwd.2 > plx);
+
+    // Going back gets the original parameters
+    let back = util::propagate_epoch(fwd.0, fwd.1, fwd.2, fwd.3, fwd.4, fwd.5, -10.0);
+    assert!((back.0 - ra).abs() < 1e-12 && (back.1 - dec).abs() < 1e-12);
+    assert!((back.2 - plx).abs() < 1e-9);
+    assert!((back.3 - pmra).abs() < 1e-6 && (back.4 - pmdec).abs() < 1e-6);
+    assert!((back.5 - rv).abs() < 1e-6);
+}
+
+#[cfg(test)]
+use crate::load::Epochs;
+
+#[test]
+fn test_epochs() {
+    let mut epochs = Epochs::None;
+    epochs.add(2016.0);
+    epochs.add(2016.0);
+    assert_eq!(Epochs::Single(2016.0), epochs);
+    // Gaia stars merged with Hipparcos stars not propagated
+    let mut hip = Epochs::None;
+    hip.add(1991.25);
+    epochs += hip;
+    assert_eq!(Epochs::Mixed, epochs);
+    epochs += Epochs::Single(2016.0);
+    assert_eq!(Epochs::Mixed, epochs);
+    // Empty worker counters leave the epoch unchanged
+    let mut single = Epochs::Single(2000.0);
+    single += Epochs::None;
+    assert_eq!(Epochs::Single(2000.0), single);
+}
+
+#[test]
+fn test_catalog_info() {
+    let dir = std::env::temp_dir().join(format!("catgen_info_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("catalog.json");
+    crate::write::write_catalog_info(Epochs::Single(2000.0), dir.to_str().unwrap());
+    assert_eq!(
+        "{\n  \"epoch\": 2000.0\n}\n",
+        std::fs::read_to_string(&path).unwrap()
+    );
+    // Stars at different epochs
+    crate::write::write_catalog_info(Epochs::Mixed, dir.to_str().unwrap());
+    assert_eq!(
+        "{\n  \"epoch\": \"mixed\"\n}\n",
+        std::fs::read_to_string(&path).unwrap()
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(test)]
+use crate::xmatch::PositionalXmatch;
+
+#[test]
+fn test_positional_xmatch() {
+    // Star at (ra, dec) in degrees, with the given magnitude
+    let at = |id: i64, ra: f64, dec: f64, mag: f32| {
+        let pos = util::spherical_to_cartesian(ra.to_radians(), dec.to_radians(), 1.0e3);
+        let mut s = star(id, mag, &[]);
+        (s.x, s.y, s.z) = (pos.x, pos.y, pos.z);
+        s.hip = id as i32;
+        s
+    };
+    let arcsec = 1.0 / 3600.0;
+    let mut list_hip = StarStore::new();
+    list_hip.push(at(1, 10.0, 20.0, 5.0));
+    list_hip.push(at(2, 10.0, 20.0 + 1.3 * arcsec, 5.2));
+    list_hip.push(at(3, 200.0, -30.0, 8.0));
+
+    let mut list_gaia = StarStore::new();
+    // Between HIP 1 and 2, closer to 1
+    list_gaia.push(at(100, 10.0, 20.0 + 0.5 * arcsec, 5.1));
+    // Close to HIP 3, but much fainter
+    list_gaia.push(at(101, 200.0, -30.0 + 0.2 * arcsec, 12.0));
+    // Far from everything
+    list_gaia.push(at(102, 100.0, 0.0, 8.0));
+
+    let mut xmatch = PositionalXmatch::new(&list_hip, 1.0, 1.5, 0.0);
+    let map = xmatch.match_stars(&list_gaia);
+    assert_eq!(1, map.len());
+    assert_eq!(Some(&1), map.get(&100));
+    assert_eq!(1, xmatch.n_ambiguous);
+    assert_eq!(1, xmatch.n_rejected_mag);
+
+    // HIP 1 is not matched again in later chunks
+    let mut chunk = StarStore::new();
+    chunk.push(at(103, 10.0, 20.0 - 0.2 * arcsec, 5.0));
+    assert!(xmatch.match_stars(&chunk).is_empty());
+
+    // Without magnitude check, the faint Gaia star matches HIP 3
+    let mut xmatch = PositionalXmatch::new(&list_hip, 1.0, f32::INFINITY, 0.0);
+    let map = xmatch.match_stars(&list_gaia);
+    assert_eq!(Some(&3), map.get(&101));
+    assert_eq!(0, xmatch.n_rejected_mag);
+}
+
+#[cfg(test)]
+use crate::merge::{Field, MergeCounts, MergePolicy, MergeRule};
+
+#[test]
+fn test_merge_policy() {
+    assert!(MergePolicy::from_str("position=foo").is_err());
+    assert!(MergePolicy::from_str("colour=gaia").is_err());
+    let policy = MergePolicy::from_str("distance=weighted-mean, photometry=secondary").unwrap();
+    assert_eq!(MergeRule::BestError, policy.rule(Field::Position));
+    assert_eq!(MergeRule::Secondary, policy.rule(Field::Names));
+    assert_eq!(
+        "position=best-error,distance=weighted-mean,photometry=secondary,kinematics=best-error,names=secondary",
+        policy.to_string()
+    );
+
+    // Same direction, Gaia at 100 pc with plx_err 0.1 and Hipparcos at
+    // 125 pc with plx_err 0.2, so that the mean parallax is 9.6 mas
+    let pc = crate::constants::PC_TO_U;
+    let mut gaia = star(1, 4.0, &["Gaia 1"]);
+    (gaia.x, gaia.plx_err, gaia.pmx) = (100.0 * pc, 0.1, 1.0);
+    let mut hip = star(2, 5.0, &["HIP 2"]);
+    (hip.x, hip.plx_err, hip.pmx, hip.hip) = (125.0 * pc, 0.2, 2.0, 2);
+
+    let mut counts = MergeCounts::default();
+    let merged = policy.merge(&gaia, &hip, &mut counts);
+    assert_eq!(1, merged.id);
+    assert_eq!(2, merged.hip);
+    assert!((merged.x / pc - 1000.0 / 9.6).abs() < 1e-6);
+    assert_eq!(1.0, merged.pmx);
+    assert_eq!(vec!["HIP 2"], merged.names);
+    // Hipparcos magnitude with the merged distance
+    assert_eq!(5.0, merged.appmag);
+    assert!((merged.absmag as f64 - (5.0 - 5.0 * (1000.0_f64 / 9.6).log10() + 5.0)).abs() < 1e-5);
+    assert_eq!((0, 0, 1), counts.get(Field::Distance));
+    assert_eq!((1, 0, 0), counts.get(Field::Kinematics));
+
+    // Magnitudes in different bands are not averaged, the magnitude comes
+    // with the colour and T_eff of Gaia (best error)
+    let policy = MergePolicy::from_str("photometry=weighted-mean").unwrap();
+    (gaia.col, gaia.teff) = (color::col_to_f32(1.0, 0.8, 0.6, 1.0), 4500.0);
+    (hip.col, hip.teff) = (color::col_to_f32(0.6, 0.8, 1.0, 1.0), 9000.0);
+    let mut counts = MergeCounts::default();
+    let merged = policy.merge(&gaia, &hip, &mut counts);
+    assert_eq!(4.0, merged.appmag);
+    assert_eq!(gaia.col.to_bits(), merged.col.to_bits());
+    assert_eq!(4500.0, merged.teff);
+    assert_eq!((1, 0, 0), counts.get(Field::Photometry));
+}
+
+#[cfg(test)]
+use crate::xmatch::parse_xmatch;
+
+#[test]
+fn test_parse_xmatch() {
+    let data = "# Gaia-Hipparcos crossmatch\n\
+                hip source_id\n\
+                1\t100\n\
+                2   200\n\
+                2 200\n\
+                3 100\n\
+                4 300\n\
+                4 400\n\
+                foo bar\n\
+                \n\
+                5 500\n";
+    let reader: Box<dyn std::io::BufRead> = Box::new(std::io::Cursor::new(data));
+    let mut map = std::collections::HashMap::new();
+    let conflicts = parse_xmatch(reader, &mut map).unwrap();
+    assert_eq!(4, map.len());
+    assert_eq!(Some(&1), map.get(&100));
+    assert_eq!(Some(&4), map.get(&300));
+    assert_eq!(None, map.get(&400));
+    assert_eq!(Some(&5), map.get(&500));
+
+    // Kept and ignored entry of each conflict
+    assert_eq!(4, conflicts.len());
+    let c: Vec<(&str, i64, i64, bool)> = conflicts
+        .iter()
+        .map(|c| (c.kind, c.source_id, c.id, c.kept))
+        .collect();
+    assert_eq!(("one-to-many", 100, 1, true), c[0]);
+    assert_eq!(("one-to-many", 100, 3, false), c[1]);
+    assert_eq!(("many-to-one", 300, 4, true), c[2]);
+    assert_eq!(("many-to-one", 400, 4, false), c[3]);
+}
+
+#[cfg(test)]
+use crate::hipparcos::CatalogFormat;
+
+#[test]
+fn test_hip_formats() {
+    assert!(CatalogFormat::from_str("foo").is_none());
+    let esa = CatalogFormat::from_str("esa1997").unwrap();
+    let cols: Vec<&str> = esa.columns.split(',').collect();
+    assert_eq!(38, cols.len());
+    assert_eq!(("hip", "gmag", "col_idx"), (cols[1], cols[5], cols[37]));
+
+    // HIP 32349 (Sirius) in hip2.dat, no header and positions in radians
+    let format = CatalogFormat::from_str("vanleeuwen2007").unwrap();
+    let dir = std::env::temp_dir().join(format!("catgen_hip_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("hip2.dat");
+    std::fs::write(
+        &file,
+        " 32349 5 0 1 1.7677953563 -0.2917512467 379.21 -546.05 -1223.14   1.40   1.28   1.58   1.33   1.24 119 -0.72 0  0.0 0 -1.0876 0.0020 0.0040 0  0.009 0.006  -0.030\n",
+    )
+    .unwrap();
+    let mut loader = crate::load::Loader::new(crate::load::LoaderConfig {
+        sep: regex::Regex::new(format.sep).unwrap(),
+        max_files: 1,
+        plx_err_faint: 1000.0,
+        plx_err_bright: 1000.0,
+        plx_err_cap: 1000.0,
+        mag_corrections: 0,
+        allow_negative_plx: true,
+        ref_epoch: crate::constants::HIP_EPOCH,
+        columns: format.columns.clone(),
+        has_header: format.header,
+        ..Default::default()
+    });
+    let list = loader.load_dir(file.to_str().unwrap()).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(1, list.len());
+    let star = list.get(0);
+    assert_eq!(32349, star.hip);
+    let sph = util::cartesian_to_spherical(star.x, star.y, star.z);
+    assert!((sph.x - 1.7677953563).abs() < 1e-9);
+    assert!((sph.y + 0.2917512467).abs() < 1e-9);
+    assert!((sph.z * crate::constants::U_TO_PC - 1000.0 / 379.21).abs() < 1e-6);
+    assert!((star.appmag + 1.0876).abs() < 1e-6);
+}
+
+#[cfg(test)]
+use crate::secondary::CatalogSpec;
+#[cfg(test)]
+use crate::xmatch::{CatalogMerger, Merger, Xmatch};
+
+#[test]
+fn test_secondary_catalogs() {
+    assert!(CatalogSpec::from_str("name=tycho2").is_err());
+    assert!(CatalogSpec::from_str("name=tycho2;path=tyc2.csv;foo=bar").is_err());
+    let spec =
+        CatalogSpec::from_str("name=tycho2; path=tyc2.csv; epoch=2000; mergepolicy=position=gaia")
+            .unwrap();
+    assert_eq!("tycho2", spec.name);
+    assert!(spec.header_columns);
+    assert!(spec.xmatch_file.is_empty());
+    assert_eq!(MergeRule::Gaia, spec.policy.rule(Field::Position));
+    let spec = CatalogSpec::from_str("name=hip;path=hip2.dat;format=vanleeuwen2007").unwrap();
+    assert!(!spec.header_columns && !spec.format.header);
+    assert_eq!(TeffRelation::Jordi2010, spec.teff_cal.bp_rp);
+    let spec =
+        CatalogSpec::from_str("name=tycho2;path=tyc2.csv;teffcal=bv=casagrande2010").unwrap();
+    assert_eq!(TeffRelation::Casagrande2010, spec.teff_cal.b_v);
+
+    // Gaia 1 is in both catalogs, Gaia 2 only in the second one
+    let mut list_gaia = StarStore::new();
+    for id in 1..=3 {
+        list_gaia.push(star(id, 5.0, &[]));
+    }
+    let mut first = StarStore::new();
+    first.push(star(10, 5.0, &["First 10"]));
+    first.push(star(11, 5.0, &["First 11"]));
+    let mut second = StarStore::new();
+    second.push(star(20, 5.0, &["Second 20"]));
+    second.push(star(21, 5.0, &["Second 21"]));
+    let table = |pairs: &[(i64, i64)]| Xmatch::Table(pairs.iter().copied().collect());
+    let mut merger = Merger::new(vec![
+        CatalogMerger::new("first", &first, table(&[(1, 10)]), MergePolicy::default()),
+        CatalogMerger::new(
+            "second",
+            &second,
+            table(&[(1, 20), (2, 21)]),
+            MergePolicy::default(),
+        ),
+    ]);
+    let mut main_list = StarStore::new();
+    merger.merge(&list_gaia, &mut main_list);
+    merger.add_rest(&mut main_list);
+    let names: Vec<Vec<String>> = (0..main_list.len())
+        .map(|i| main_list.get(i).names)
+        .collect();
+    assert_eq!(
+        vec![
+            vec!["First 10"],
+            vec!["Second 21"],
+            vec![],
+            vec!["First 11"]
+        ],
+        names
+    );
+    assert_eq!(1, merger.no_hit);
+    assert_eq!(1, merger.catalogs[1].duplicates);
+}
+
+#[test]
+fn test_split_secondary_catalog() {
+    // A catalog split in two parts, with the part number after the extension
+    let dir = std::env::temp_dir().join(format!("catgen_split_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("tyc2.dat.00"),
+        "1,10,20,5.0,0.1,8.0\n2,30,40,4.0,0.1,9.0\n",
+    )
+    .unwrap();
+    std::fs::write(dir.join("tyc2.dat.01"), "3,50,60,3.0,0.1,10.0\n").unwrap();
+    std::fs::write(dir.join("ReadMe"), "Tycho-2 in two parts\n").unwrap();
+    let spec = CatalogSpec::from_str(&format!(
+        "name=tycho2;path={};columns=hip,ra,dec,plx,plx_err,gmag;header=false",
+        dir.to_str().unwrap()
+    ))
+    .unwrap();
+    let mut loader = crate::load::Loader::new(crate::load::LoaderConfig {
+        sep: regex::Regex::new(spec.format.sep).unwrap(),
+        plx_err_faint: spec.plx_err,
+        plx_err_bright: spec.plx_err,
+        plx_err_cap: spec.plx_err_cap,
+        mag_corrections: 0,
+        allow_negative_plx: true,
+        ref_epoch: spec.epoch,
+        columns: spec.format.columns.clone(),
+        has_header: spec.format.header,
+        header_columns: spec.header_columns,
+        ..Default::default()
+    });
+    let list = loader.load_dir(&spec.path).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(
+        vec![1, 2, 3],
+        (0..list.len()).map(|i| list.get(i).hip).collect::<Vec<_>>()
+    );
+}
+
+#[cfg(test)]
+use crate::names::{NameKind, Names};
+
+#[test]
+fn test_names() {
+    assert_eq!(NameKind::Hip, NameKind::classify("HIP 32349"));
+    assert_eq!(NameKind::Bayer, NameKind::classify("alf CMa"));
+    assert_eq!(NameKind::Bayer, NameKind::classify("pi01 Ori"));
+    assert_eq!(NameKind::Bayer, NameKind::classify("Alpha Canis Majoris"));
+    assert_eq!(NameKind::Flamsteed, NameKind::classify("9 CMa"));
+    assert_eq!(NameKind::Gliese, NameKind::classify("GJ 244"));
+    assert_eq!(NameKind::Other, NameKind::classify("Sirius"));
+    assert!(Names::new("proper,foo").is_err());
+
+    let mut names = Names::new("proper,bayer,hd").unwrap();
+    let by_hip = "hip,name\n32349,Sirius\n";
+    let by_source_id = "source_id\tname\n2947050466531873024\tHD 48915|hd  48915\n";
+    let reader =
+        |s: &'static str| -> Box<dyn std::io::BufRead> { Box::new(std::io::Cursor::new(s)) };
+    assert_eq!(1, names.parse(NameKind::Proper, reader(by_hip)).unwrap());
+    assert_eq!(2, names.parse(NameKind::Hd, reader(by_source_id)).unwrap());
+
+    let mut list = StarStore::new();
+    let mut sirius = star(
+        2947050466531873024,
+        -1.4,
+        &["HIP 32349", "9 CMa", "alf CMa"],
+    );
+    sirius.hip = 32349;
+    list.push(sirius);
+    list.push(star(1, 5.0, &["Star1"]));
+    names.apply(&mut list);
+    // Names differing only in case and whitespace are duplicates
+    assert_eq!(
+        vec!["Sirius", "alf CMa", "HD 48915", "9 CMa", "HIP 32349"],
+        list.get(0).names
+    );
+    assert_eq!(vec!["Star1"], list.get(1).names);
+    assert_eq!((1, 2), (names.n_named, names.n_added));
+}
+
+#[cfg(test)]
+use crate::filter::{Filter, Var};
+
+#[test]
+fn test_filter() {
+    let resolve = |name: &str| match name {
+        "dr2_radvel" => Some(Var::Additional(name.to_string())),
+        _ => ColId::from_str(name).map(Var::Column),
+    };
+    let filter =
+        Filter::compile("ruwe < 1.4 && (plx/plx_err > 10 || gmag < 12)", &resolve).unwrap();
+    assert_eq!(
+        vec!["ruwe < 1.4", "(plx/plx_err > 10 || gmag < 12)"],
+        filter.clauses()
+    );
+    assert_eq!(
+        vec![
+            Var::Column(ColId::ruwe),
+            Var::Column(ColId::plx),
+            Var::Column(ColId::plx_err),
+            Var::Column(ColId::gmag)
+        ],
+        filter.vars
+    );
+    // Values of ruwe, plx, plx_err and gmag
+    assert_eq!(None, filter.eval(&[1.0, 5.0, 0.1, 15.0]));
+    assert_eq!(None, filter.eval(&[1.0, 5.0, 1.0, 11.0]));
+    assert_eq!(Some(0), filter.eval(&[2.0, 5.0, 0.1, 15.0]));
+    assert_eq!(Some(1), filter.eval(&[1.0, 5.0, 1.0, 15.0]));
+    // Comparisons with NaN are false
+    assert_eq!(Some(0), filter.eval(&[f64::NAN, 5.0, 0.1, 15.0]));
+
+    let filter = Filter::compile(
+        "-abs(dr2_radvel) >= -2e1 * 2 || !isfinite(dr2_radvel)",
+        &resolve,
+    )
+    .unwrap();
+    assert_eq!(1, filter.clauses().len());
+    assert_eq!(None, filter.eval(&[-30.0]));
+    assert_eq!(Some(0), filter.eval(&[50.0]));
+    assert_eq!(None, filter.eval(&[f64::NAN]));
+
+    assert!(Filter::compile("foo > 1", &resolve).is_err());
+    assert!(Filter::compile("ruwe < 1.4 & gmag < 12", &resolve).is_err());
+    assert!(Filter::compile("(ruwe < 1.4", &resolve).is_err());
+    assert!(Filter::compile("ruwe < ", &resolve).is_err());
+    assert!(Filter::compile("", &resolve).is_err());
+}
+
+#[cfg(test)]
+use crate::region::{self, Region};
+
+#[test]
+fn test_regions() {
+    let contains =
+        |region: &Region, ra: f64, dec: f64| region.contains(ra.to_radians(), dec.to_radians());
+
+    // Pleiades
+    let cone = Region::from_str("cone:56.75,24.12,2").unwrap();
+    assert!(contains(&cone, 56.75, 24.12));
+    assert!(contains(&cone, 58.0, 23.0));
+    assert!(!contains(&cone, 56.75, 27.0));
+
+    // Box around RA=0
+    let box_ = Region::from_str("box:350,10,-5,5").unwrap();
+    assert!(contains(&box_, 355.0, 0.0));
+    assert!(contains(&box_, 5.0, 4.0));
+    assert!(!contains(&box_, 20.0, 0.0));
+    assert!(!contains(&box_, 0.0, 6.0));
+
+    // Non-convex polygon (an L shape)
+    let polygon = Region::from_str("polygon:0,0,20,0,20,5,5,5,5,20,0,20").unwrap();
+    assert!(contains(&polygon, 2.0, 15.0));
+    assert!(contains(&polygon, 15.0, 2.0));
+    assert!(!contains(&polygon, 15.0, 15.0));
+    assert!(!contains(&polygon, 30.0, 2.0));
+
+    // North pole in both schemes
+    assert_eq!(0, region::ang2pix(1, false, 0.0, 1.5));
+    assert_eq!(0, region::ang2pix(1, true, 0.0, 1.5));
+    assert_eq!(4, region::ang2pix(1, true, 0.0, 0.0));
+    let healpix = Region::from_str("healpix:1,nested,4-5").unwrap();
+    assert!(contains(&healpix, 0.0, 0.0));
+    assert!(!contains(&healpix, 0.0, 80.0));
+
+    assert!(Region::from_str("cone:10,20").is_err());
+    assert!(Region::from_str("polygon:0,0,1,1").is_err());
+    assert!(Region::from_str("healpix:3,nested,1").is_err());
+    assert!(Region::from_str("healpix:1,ring,12").is_err());
+    assert!(Region::from_str("circle:1,2,3").is_err());
+}
+
+#[cfg(test)]
+use crate::limits::MagLimits;
+
+#[test]
+fn test_mag_limits() {
+    let none = MagLimits::new(f64::NAN, f64::NAN, f64::NAN, f64::NAN, f64::NAN).unwrap();
+    assert!(none.is_none());
+    assert!(none.accept_apparent(25.0));
+    assert!(none.accept_absolute(f64::NAN));
+    assert!(none.accept_volume(1.0e5, 20.0));
+
+    let limits = MagLimits::new(3.0, 12.0, f64::NAN, 10.0, f64::NAN).unwrap();
+    assert!(!limits.accept_apparent(2.0));
+    assert!(limits.accept_apparent(12.0));
+    assert!(!limits.accept_apparent(12.5));
+    assert!(limits.accept_absolute(-5.0));
+    assert!(!limits.accept_absolute(11.0));
+    assert!(!limits.accept_absolute(f64::NAN));
+
+    // Complete to 100 pc with G <= 15 means absolute magnitudes up to 10
+    let volume = MagLimits::new(f64::NAN, 15.0, f64::NAN, f64::NAN, 100.0).unwrap();
+    assert!((volume.volume_absmag() - 10.0).abs() < 1e-9);
+    assert!(volume.accept_volume(50.0, 9.5));
+    assert!(!volume.accept_volume(50.0, 10.5));
+    assert!(!volume.accept_volume(150.0, 5.0));
+
+    assert!(MagLimits::new(12.0, 3.0, f64::NAN, f64::NAN, f64::NAN).is_err());
+    assert!(MagLimits::new(f64::NAN, f64::NAN, f64::NAN, f64::NAN, 100.0).is_err());
+
+    // Must-load stars without distance skip the absolute magnitude limits
+    let dir = std::env::temp_dir().join(format!("catgen_maglim_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("gaia.csv");
+    std::fs::write(
+        &file,
+        "source_id,ra,dec,plx,plx_err,gmag\n1,10,20,10.0,0.1,8.0\n2,10,20,,,8.0\n3,10,20,,,8.0\n",
+    )
+    .unwrap();
+    let mut loader = crate::load::Loader::new(crate::load::LoaderConfig {
+        mag_corrections: 0,
+        mag_limits: MagLimits::new(f64::NAN, f64::NAN, f64::NAN, 10.0, f64::NAN).unwrap(),
+        must_load: Some([2].into_iter().collect()),
+        columns: "source_id,ra,dec,plx,plx_err,gmag".to_string(),
+        ..Default::default()
+    });
+    let list = loader.load_dir(file.to_str().unwrap()).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(vec![1, 2], list.id);
+    assert_eq!(0, loader.counters.rejected_absmaglim);
+}
+
+#[cfg(test)]
+use crate::rejected::{RejectedDump, Rejection};
+
+#[test]
+fn test_rejected_dump() {
+    let path = std::env::temp_dir().join(format!("catgen_rejected_{}.csv.gz", std::process::id()));
+    let path = path.to_str().unwrap();
+    let dump = RejectedDump::new(path, 0.25).unwrap();
+    // The sample is deterministic, and close to the fraction
+    let n_sampled = (0..10000).filter(|id| dump.sampled(*id)).count();
+    assert!((2300..2700).contains(&n_sampled));
+    assert_eq!(dump.sampled(12345), dump.sampled(12345));
+
+    dump.write(&[Rejection {
+        source_id: 42,
+        reason: "filter: ruwe < 1.4".to_string(),
+        values: [-0.5, 0.2, 1.1, 12.5, f64::NAN],
+    }]);
+    dump.finish();
+    // Writes after finishing are ignored
+    dump.write(&[Rejection {
+        source_id: 43,
+        reason: "ruwe".to_string(),
+        values: [f64::NAN; 5],
+    }]);
+
+    let mut content = String::new();
+    std::io::Read::read_to_string(
+        &mut flate2::read::GzDecoder::new(std::fs::File::open(path).unwrap()),
+        &mut content,
+    )
+    .unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(
+        "source_id,reason,plx,plx_err,ruwe,appmag,dist_pc\n42,\"filter: ruwe < 1.4\",-0.5,0.2,1.1,12.5,\n",
+        content
+    );
+}
+
+#[test]
+fn test_rejected_dump_single_file() {
+    // A single input file, rather than a directory
+    let dir = std::env::temp_dir().join(format!("catgen_rejected_file_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("gaia.csv");
+    std::fs::write(
+        &file,
+        "source_id,ra,dec,plx,plx_err,gmag\n1,10,20,5.0,0.1,10.0\n2,10,20,-1.0,0.1,11.0\n",
+    )
+    .unwrap();
+    let path = dir.join("rejected.csv.gz");
+    let mut loader = crate::load::Loader::new(crate::load::LoaderConfig {
+        mag_corrections: 0,
+        rejected_dump: Some(RejectedDump::new(path.to_str().unwrap(), 1.0).unwrap()),
+        columns: "source_id,ra,dec,plx,plx_err,gmag".to_string(),
+        ..Default::default()
+    });
+    let list = loader.load_dir(file.to_str().unwrap()).unwrap();
+    assert_eq!(1, list.len());
+
+    let mut content = String::new();
+    std::io::Read::read_to_string(
+        &mut flate2::read::GzDecoder::new(std::fs::File::open(&path).unwrap()),
+        &mut content,
+    )
+    .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(
+        "source_id,reason,plx,plx_err,ruwe,appmag,dist_pc\n2,\"negative parallax\",-1,0.1,,11,\n",
+        content
+    );
+}
+
+#[cfg(test)]
+use crate::extinction::{DustMap, Extinction};
+
+#[test]
+fn test_extinction() {
+    let reader = |s: String| -> Box<dyn std::io::BufRead> { Box::new(std::io::Cursor::new(s)) };
+    let close = |a: f64, b: f64| (a - b).abs() < 1e-6;
+
+    // Uniform density of 1 mag/kpc in a 300 pc cube centred on the Sun
+    let grid = DustMap::parse(reader(format!(
+        "# test grid\ngrid 3 3 3 -100 -100 -100 100\n{}",
+        "0.001 ".repeat(27)
+    )))
+    .unwrap();
+    assert!(close(0.1, grid.sample(0.0, 0.0, 100.0)));
+    assert!(close(0.15, grid.sample(0.0, 0.0, 1000.0)));
+    // Towards (1, 1, 0), leaving the cube at x = y = 150 pc
+    assert!(close(
+        0.15 * 2f64.sqrt(),
+        grid.sample(std::f64::consts::FRAC_PI_4, 0.0, 1000.0)
+    ));
+    assert_eq!(0.0, grid.sample(0.0, 0.0, -1.0));
+
+    // Cumulative extinction of 0.1 at 100 pc and 0.3 at 200 pc everywhere
+    let healpix = DustMap::parse(reader(format!(
+        "healpix 1 nested 100 200\n{}",
+        "0.1 0.3\n".repeat(12)
+    )))
+    .unwrap();
+    assert!(close(0.05, healpix.sample(2.0, 0.5, 50.0)));
+    assert!(close(0.2, healpix.sample(2.0, 0.5, 150.0)));
+    assert!(close(0.3, healpix.sample(2.0, 0.5, 500.0)));
+
+    assert!(DustMap::parse(reader("grid 3 3 3 0 0 0 10\n1 2 3".to_string())).is_err());
+    assert!(DustMap::parse(reader("healpix 1 ring 200 100\n".to_string())).is_err());
+    assert!(DustMap::parse(reader("cube 1 2 3".to_string())).is_err());
+
+    // Analytical law towards the galactic pole
+    let (ag, ebr) = Extinction::Analytical.extinction(0.0, std::f64::consts::FRAC_PI_2, 100.0);
+    assert!(close(0.059, ag) && close(0.029, ebr));
+    let map = Extinction::Map {
+        file: "test".to_string(),
+        map: healpix,
+        ag_coeff: Extinction::AG_COEFF,
+        ebr_coeff: Extinction::EBR_COEFF,
+    };
+    let (ag, ebr) = map.extinction(2.0, 0.5, 500.0);
+    assert!(close(0.3 * 2.740, ag) && close(0.3 * 1.339, ebr));
+}
+
+#[cfg(test)]
+use crate::photometry::{self, ExtLaw};
+
+#[test]
+fn test_extinction_law() {
+    assert_eq!(Some(ExtLaw::Riello2021), ExtLaw::from_str("riello2021"));
+    assert_eq!(None, ExtLaw::from_str("foo"));
+
+    // Redder stars have smaller extinction coefficients
+    assert!(photometry::k_g(2.0, 0.1) < photometry::k_g(0.5, 0.1));
+    assert!(photometry::k_bp(1.0, 0.1) > photometry::k_g(1.0, 0.1));
+    assert!(photometry::k_rp(1.0, 0.1) < photometry::k_g(1.0, 0.1));
+
+    // Recover A_0 and (BP-RP)_0 from A_G and the observed colour
+    let (a0, bp_rp0) = (1.5, 0.8);
+    let ag = photometry::k_g(bp_rp0, a0) * a0;
+    let bp_rp = bp_rp0 + (photometry::k_bp(bp_rp0, a0) - photometry::k_rp(bp_rp0, a0)) * a0;
+    let (a0_solved, bp_rp0_solved) = photometry::solve_a0(bp_rp, ag).unwrap();
+    assert!((a0 - a0_solved).abs() < 1e-4);
+    assert!((bp_rp0 - bp_rp0_solved).abs() < 1e-4);
+    let (a_g, a_bp, a_rp) = photometry::extinctions(a0_solved, bp_rp0_solved);
+    assert!((a_g - ag).abs() < 1e-4);
+    assert!((a_bp - a_rp - (bp_rp - bp_rp0)).abs() < 1e-4);
+    // Same from the reddening
+    let (a0_solved, bp_rp0_solved) =
+        photometry::solve_a0_from_reddening(bp_rp, a_bp - a_rp).unwrap();
+    assert!((a0 - a0_solved).abs() < 1e-4);
+    assert!((bp_rp0 - bp_rp0_solved).abs() < 1e-4);
+
+    assert_eq!(Some((0.0, 1.0)), photometry::solve_a0(1.0, 0.0));
+    assert_eq!(None, photometry::solve_a0(f64::NAN, 0.5));
+
+    // The loader derives A_G and E(BP-RP) from the same A_0, with the catalog
+    // A_G first, then the catalog E(BP-RP)
+    let dir = std::env::temp_dir().join(format!("catgen_extlaw_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("gaia.csv");
+    std::fs::write(
+        &file,
+        format!(
+            "source_id,ra,dec,plx,plx_err,gmag,bpmag,rpmag,ag,ebp_min_rp\n\
+             1,10,20,5.0,0.1,12.0,12.5,{rp},{ag},\n\
+             2,10,20,5.0,0.1,12.0,12.5,{rp},,{ebr}\n",
+            rp = 12.5 - bp_rp,
+            ag = ag,
+            ebr = bp_rp - bp_rp0
+        ),
+    )
+    .unwrap();
+    let teff_cal = crate::color::TeffCalibration::default();
+    let mut loader = crate::load::Loader::new(crate::load::LoaderConfig {
+        mag_corrections: 1,
+        ext_law: ExtLaw::Riello2021,
+        teff_cal,
+        columns: "source_id,ra,dec,plx,plx_err,gmag,bpmag,rpmag,ag,ebp_min_rp".to_string(),
+        ..Default::default()
+    });
+    let list = loader.load_dir(file.to_str().unwrap()).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    let teff0 = teff_cal.teff(crate::color::ColorIndex::BpRp, bp_rp0);
+    for i in 0..2 {
+        let star = list.get(i);
+        assert!((star.appmag as f64 - (12.0 - ag)).abs() < 1e-4);
+        assert!((star.teff as f64 - teff0).abs() < 1.0);
+    }
+}
+
+#[test]
+fn test_gaia_photometry() {
+    // Five-parameter solutions are not corrected, six-parameter ones are
+    assert_eq!(17.0, photometry::correct_gmag(17.0, 1.0, Some(31)));
+    assert_eq!(17.0, photometry::correct_gmag(17.0, 1.0, None));
+    let corrected = photometry::correct_gmag(17.0, 1.0, Some(95));
+    assert!((corrected - 17.00338).abs() < 1e-4);
+    assert_eq!(17.0, photometry::correct_gmag(17.0, f64::NAN, Some(95)));
+    // Bright stars
+    assert!((photometry::correct_gmag(10.0, 1.0, Some(31)) - 9.9872).abs() < 1e-9);
+    assert_eq!(5.0, photometry::correct_gmag(5.0, 1.0, Some(31)));
+
+    // Corrected excess factor of about 1.2 sigma at G = 15
+    let c = photometry::excess_factor_corrected(1.2, 1.0);
+    assert!((c + 0.016844).abs() < 1e-6);
+    assert!(photometry::accept_excess(1.2, 1.0, 15.0, 3.0));
+    assert!(!photometry::accept_excess(1.2, 1.0, 15.0, 1.0));
+    assert!(photometry::accept_excess(f64::NAN, 1.0, 15.0, 1.0));
+}
+
+#[cfg(test)]
+use crate::color::{self, ColorModel};
+
+#[test]
+fn test_color_models() {
+    // Chromaticity of the blackbody locus
+    let (x, y, z) = color::planck_to_xyz(6504.0);
+    assert!((x / (x + y + z) - 0.3135).abs() < 1e-3);
+    assert!((y / (x + y + z) - 0.3237).abs() < 1e-3);
+
+    let planck = ColorModel::from_str("planck", "srgb", "d65").unwrap();
+    let (r, g, b) = planck.teff_to_rgb(3000.0);
+    assert!(r == 1.0 && g > b);
+    let (r, g, b) = planck.teff_to_rgb(40000.0);
+    assert!(b == 1.0 && r < g);
+    // The white point is rendered white
+    let planck = ColorModel::from_str("planck", "linear", "5772").unwrap();
+    let (r, g, b) = planck.teff_to_rgb(5772.0);
+    assert!((r - 1.0).abs() < 1e-6 && (g - 1.0).abs() < 1e-6 && (b - 1.0).abs() < 1e-6);
+
+    assert_eq!(
+        color::teff_to_rgb(5000.0),
+        ColorModel::Helland.teff_to_rgb(5000.0)
+    );
+    assert!(ColorModel::from_str("planck", "cmyk", "d65").is_err());
+    assert!(ColorModel::from_str("planck", "srgb", "hot").is_err());
+    assert!(ColorModel::from_str("other", "srgb", "d65").is_err());
+}
+
+#[cfg(test)]
+use crate::color::{ColorIndex, TeffCalibration, TeffRelation};
+
+#[test]
+fn test_teff_calibrations() {
+    // The defaults are the original relations
+    let cal = TeffCalibration::from_str("").unwrap();
+    assert_eq!(color::xp_to_teff(0.8), cal.teff(ColorIndex::BpRp, 0.8));
+    assert_eq!(
+        color::bv_to_teff_ballesteros(0.65),
+        cal.teff(ColorIndex::BV, 0.65)
+    );
+
+    let cal = TeffCalibration::from_str("bprp=pecaut2013, bv=casagrande2010").unwrap();
+    assert_eq!(TeffRelation::PecautMamajek2013, cal.bp_rp);
+    assert_eq!(TeffRelation::Casagrande2010, cal.b_v);
+    // Table nodes, interpolation and clamping
+    assert_eq!(5770.0, cal.teff(ColorIndex::BpRp, 0.82));
+    assert!((cal.teff(ColorIndex::BpRp, 0.785) - 5845.0).abs() < 1e-6);
+    assert_eq!(31400.0, cal.teff(ColorIndex::BpRp, -1.0));
+    assert_eq!(2810.0, cal.teff(ColorIndex::BpRp, 5.0));
+    // Sun-like stars with every relation
+    for (index, c, relation) in [
+        (ColorIndex::BpRp, 0.82, TeffRelation::Jordi2010),
+        (ColorIndex::BpRp, 0.82, TeffRelation::Mucciarelli2020),
+        (ColorIndex::BV, 0.65, TeffRelation::Ballesteros2012),
+        (ColorIndex::BV, 0.65, TeffRelation::Casagrande2010),
+        (ColorIndex::BV, 0.65, TeffRelation::PecautMamajek2013),
+    ] {
+        assert!((relation.teff(index, c) - 5772.0).abs() < 250.0);
+    }
+
+    assert!(TeffCalibration::from_str("bv=jordi2010").is_err());
+    assert!(TeffCalibration::from_str("bprp=other").is_err());
+    assert!(TeffCalibration::from_str("ub=pecaut2013").is_err());
+    assert!(TeffCalibration::from_str("nocolor=red").is_err());
+
+    // Stars without colour index go through the B-V relation
+    let cal = TeffCalibration::from_str("bv=casagrande2010,nocolor=1.2").unwrap();
+    assert_eq!(1.2, cal.no_color_bv);
+    let dir = std::env::temp_dir().join(format!("catgen_nocolor_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("gaia.csv");
+    std::fs::write(
+        &file,
+        "source_id,ra,dec,plx,plx_err,gmag\n1,10,20,5.0,0.1,10.0\n",
+    )
+    .unwrap();
+    let mut loader = crate::load::Loader::new(crate::load::LoaderConfig {
+        mag_corrections: 0,
+        teff_cal: cal,
+        columns: "source_id,ra,dec,plx,plx_err,gmag".to_string(),
+        ..Default::default()
+    });
+    let list = loader.load_dir(file.to_str().unwrap()).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(1, loader.counters.total_no_color);
+    assert_eq!(color::bv_to_teff_casagrande(1.2) as f32, list.get(0).teff);
+}
@@ -1,6 +1,9 @@
+extern crate nalgebra as na;
+
 use std::{f64::consts, time::Duration};
 
 use data::Vec3;
+use na::Vector3;
 
 use crate::constants;
 use crate::data;
@@ -88,6 +91,82 @@ pub fn propermotion_to_cartesian(
     }
 }
 
+/**
+ * Propagates the astrometric parameters of a star by dt years using rigorous
+ * linear space motion (ESA 1997, The Hipparcos and Tycho Catalogues, vol. 1,
+ * sec. 1.5.5). Takes and returns (ra[rad], dec[rad], plx[mas], pmra*[mas/yr],
+ * pmdec[mas/yr], radvel[km/s]). If the parallax is not positive or the radial
+ * velocity is not finite, the radial proper motion is taken as zero.
+ */
+pub fn propagate_epoch(
+    ra: f64,
+    dec: f64,
+    plx: f64,
+    pmra: f64,
+    pmdec: f64,
+    radvel: f64,
+    dt: f64,
+) -> (f64, f64, f64, f64, f64, f64) {
+    // Astronomical unit in km.yr/s
+    const A_V: f64 = 4.740_470_446;
+    let mas_to_rad = (1.0 / 3.6e6_f64).to_radians();
+
+    let (sa, ca) = ra.sin_cos();
+    let (sd, cd) = dec.sin_cos();
+    let p0 = Vector3::new(-sa, ca, 0.0);
+    let q0 = Vector3::new(-sd * ca, -sd * sa, cd);
+    let r0 = Vector3::new(cd * ca, cd * sa, sd);
+
+    // Proper motions in rad/yr
+    let has_radial = plx > 0.0 && radvel.is_finite();
+    let mu_r = if has_radial {
+        radvel * plx / A_V * mas_to_rad
+    } else {
+        0.0
+    };
+    let pm0 = p0 * (pmra * mas_to_rad) + q0 * (pmdec * mas_to_rad);
+    let mu2 = pm0.norm_squared();
+
+    let f = 1.0 / (1.0 + 2.0 * mu_r * dt + (mu2 + mu_r * mu_r) * dt * dt).sqrt();
+    let r = (r0 * (1.0 + mu_r * dt) + pm0 * dt) * f;
+    let pm = (pm0 * (1.0 + mu_r * dt) - r0 * (mu2 * dt)) * f * f * f;
+    let mu_r_t = (mu_r + (mu2 + mu_r * mu_r) * dt) * f * f;
+    let plx_t = plx * f;
+
+    let mut ra_t = r.y.atan2(r.x);
+    if ra_t < 0.0 {
+        ra_t += 2.0 * consts::PI;
+    }
+    let dec_t = r.z.atan2((r.x * r.x + r.y * r.y).sqrt());
+    let (sa, ca) = ra_t.sin_cos();
+    let (sd, cd) = dec_t.sin_cos();
+    let p = Vector3::new(-sa, ca, 0.0);
+    let q = Vector3::new(-sd * ca, -sd * sa, cd);
+    let radvel_t = if has_radial {
+        mu_r_t / mas_to_rad * A_V / plx_t
+    } else {
+        radvel
+    };
+
+    (
+        ra_t,
+        dec_t,
+        plx_t,
+        p.dot(&pm) / mas_to_rad,
+        q.dot(&pm) / mas_to_rad,
+        radvel_t,
+    )
+}
+
+/**
+ * Computes the size of a star in internal units from its absolute magnitude.
+ */
+pub fn absmag_to_size(absmag: f64) -> f32 {
+    let pseudo_l = f64::powf(10.0, -0.4 * absmag);
+    let size_fac = constants::PC_TO_M * constants::M_TO_U * 0.15;
+    f64::min(pseudo_l.powf(0.5) * size_fac, 1e10) as f32
+}
+
 pub fn seconds_to_time(secs: u64) -> (u64, u64, u64) {
     let hours = secs / 3600;
     let mins = (secs - (hours * 3600)) / 60;
@@ -0,0 +1,336 @@
+extern crate base64;
+extern crate flate2;
+extern crate quick_xml;
+
+use base64::Engine;
+use flate2::read::GzDecoder;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::{Reader, XmlVersion};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use crate::table::{Table, TableColumn};
+
+/**
+ * A FIELD of a VOTable, with the attributes needed
+ * to decode its values.
+ **/
+struct Field {
+    name: String,
+    unit: String,
+    datatype: String,
+    // Number of elements for fixed-size fields, or number of elements
+    // per item of the last dimension for variable-size fields
+    count: usize,
+    // Whether the field has a variable size (arraysize ends with '*')
+    variable: bool,
+    // Null value, from the VALUES element
+    null: Option<String>,
+}
+
+impl Field {
+    fn from(e: &BytesStart) -> Self {
+        let mut field = Field {
+            name: String::new(),
+            unit: String::new(),
+            datatype: String::new(),
+            count: 1,
+            variable: false,
+            null: None,
+        };
+        for (key, value) in attributes(e) {
+            match key.as_str() {
+                "name" => field.name = value,
+                "unit" => field.unit = value,
+                "datatype" => field.datatype = value,
+                "arraysize" => {
+                    // For variable sizes, the last dimension comes from the data
+                    let mut dims: Vec<&str> = value.split('x').collect();
+                    field.variable = value.ends_with('*');
+                    if field.variable {
+                        dims.pop();
+                    }
+                    field.count = dims
+                        .iter()
+                        .filter_map(|d| d.parse::<usize>().ok())
+                        .product();
+                }
+                _ => (),
+            }
+        }
+        field
+    }
+
+    /// Size of one element in the binary serialization, in bytes.
+    fn element_size(&self) -> usize {
+        match self.datatype.as_str() {
+            "boolean" | "unsignedByte" | "char" => 1,
+            "short" | "unicodeChar" => 2,
+            "int" | "float" => 4,
+            "long" | "double" | "floatComplex" => 8,
+            "doubleComplex" => 16,
+            _ => 0,
+        }
+    }
+
+    /// Converts the text value of a TD element, returning None for nulls.
+    fn text_value(&self, text: &str) -> Option<String> {
+        let text = text.trim();
+        if text.is_empty() || self.null.as_deref() == Some(text) {
+            None
+        } else {
+            Some(text.to_string())
+        }
+    }
+
+    /// Converts the binary value of a field, returning None for nulls
+    /// and arrays, which are not supported.
+    fn binary_value(&self, bytes: &[u8], count: usize) -> Option<String> {
+        let value = match self.datatype.as_str() {
+            "char" => {
+                let s = String::from_utf8_lossy(bytes);
+                s.trim_end_matches(['\0', ' ']).to_string()
+            }
+            "unicodeChar" => {
+                let chars: Vec<u16> = bytes
+                    .chunks_exact(2)
+                    .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                    .collect();
+                String::from_utf16_lossy(&chars)
+                    .trim_end_matches(['\0', ' '])
+                    .to_string()
+            }
+            _ if count != 1 => return None,
+            "boolean" => match bytes[0] {
+                b'T' | b't' | b'1' => "1".to_string(),
+                b'F' | b'f' | b'0' => "0".to_string(),
+                _ => return None,
+            },
+            "unsignedByte" => bytes[0].to_string(),
+            "short" => i16::from_be_bytes([bytes[0], bytes[1]]).to_string(),
+            "int" => i32::from_be_bytes(bytes[0..4].try_into().unwrap()).to_string(),
+            "long" => i64::from_be_bytes(bytes[0..8].try_into().unwrap()).to_string(),
+            "float" => {
+                let val = f32::from_be_bytes(bytes[0..4].try_into().unwrap());
+                if val.is_nan() {
+                    return None;
+                }
+                val.to_string()
+            }
+            "double" => {
+                let val = f64::from_be_bytes(bytes[0..8].try_into().unwrap());
+                if val.is_nan() {
+                    return None;
+                }
+                val.to_string()
+            }
+            _ => return None,
+        };
+        if value.is_empty() || self.null.as_deref() == Some(value.as_str()) {
+            None
+        } else {
+            Some(value)
+        }
+    }
+}
+
+/// Gets the attributes of the element as (key, value) pairs.
+fn attributes(e: &BytesStart) -> Vec<(String, String)> {
+    e.attributes()
+        .filter_map(|a| a.ok())
+        .map(|a| {
+            (
+                a.key.as_ref().to_string(),
+                a.normalized_value(XmlVersion::Implicit1_0)
+                    .map(|v| v.to_string())
+                    .unwrap_or_default(),
+            )
+        })
+        .collect()
+}
+
+/// Reads the first TABLE of the given VOTable file, which may be gzipped.
+/// The TABLEDATA, BINARY and BINARY2 serializations are supported.
+pub fn read(file: &str) -> Result<Table, String> {
+    let f = File::open(file).map_err(|e| format!("{}: {}", file, e))?;
+    let reader: Box<dyn BufRead> = if file.ends_with(".gz") {
+        Box::new(BufReader::new(GzDecoder::new(f)))
+    } else {
+        Box::new(BufReader::new(f))
+    };
+    parse(reader).map_err(|e| format!("{}: {}", file, e))
+}
+
+/// Parses the first TABLE of the VOTable in the given reader.
+pub fn parse(reader: Box<dyn BufRead>) -> Result<Table, String> {
+    let mut xml = Reader::from_reader(reader);
+    let mut buf = Vec::new();
+
+    let mut fields: Vec<Field> = Vec::new();
+    let mut rows: Vec<Vec<Option<String>>> = Vec::new();
+    let mut in_table = false;
+    let mut in_field = false;
+    let mut binary2 = false;
+    // Current row, for TABLEDATA
+    let mut row: Vec<Option<String>> = Vec::new();
+    // Text being captured (TD or STREAM), if any
+    let mut text: Option<String> = None;
+
+    loop {
+        let event = xml
+            .read_event_into(&mut buf)
+            .map_err(|e| format!("XML error at {}: {}", xml.buffer_position(), e))?;
+        match event {
+            Event::Start(ref e) | Event::Empty(ref e) => {
+                let empty = matches!(event, Event::Empty(_));
+                match e.local_name().as_ref() {
+                    "TABLE" => in_table = true,
+                    "FIELD" if in_table => {
+                        fields.push(Field::from(e));
+                        in_field = !empty;
+                    }
+                    "VALUES" if in_field => {
+                        let field = fields.last_mut().unwrap();
+                        for (key, value) in attributes(e) {
+                            if key == "null" {
+                                field.null = Some(value);
+                            }
+                        }
+                    }
+                    "TR" if in_table => row.clear(),
+                    "TD" if in_table => {
+                        if empty {
+                            row.push(None);
+                        } else {
+                            text = Some(String::new());
+                        }
+                    }
+                    "STREAM" if in_table => {
+                        let encoding = attributes(e)
+                            .into_iter()
+                            .find(|(key, _)| key == "encoding")
+                            .map(|(_, value)| value);
+                        if encoding.as_deref() != Some("base64") {
+                            return Err("only base64-encoded streams are supported".to_string());
+                        }
+                        text = Some(String::new());
+                    }
+                    "BINARY2" => binary2 = true,
+                    "FITS" if in_table => {
+                        return Err("FITS serialization is not supported".to_string())
+                    }
+                    _ => (),
+                }
+            }
+            Event::Text(e) => {
+                if let Some(t) = text.as_mut() {
+                    t.push_str(&e);
+                }
+            }
+            Event::CData(e) => {
+                if let Some(t) = text.as_mut() {
+                    t.push_str(&e);
+                }
+            }
+            Event::GeneralRef(e) => {
+                if let Some(t) = text.as_mut() {
+                    match &*e {
+                        "lt" => t.push('<'),
+                        "gt" => t.push('>'),
+                        "amp" => t.push('&'),
+                        "quot" => t.push('"'),
+                        "apos" => t.push('\''),
+                        _ => {
+                            if let Ok(Some(c)) = e.resolve_char_ref() {
+                                t.push(c);
+                            }
+                        }
+                    }
+                }
+            }
+            Event::End(ref e) => match e.local_name().as_ref() {
+                "FIELD" => in_field = false,
+                "TD" if in_table => {
+                    let field = fields.get(row.len());
+                    let value = text.take().unwrap_or_default();
+                    row.push(field.and_then(|f| f.text_value(&value)));
+                }
+                "TR" if in_table => {
+                    row.resize(fields.len(), None);
+                    rows.push(std::mem::take(&mut row));
+                }
+                "STREAM" if in_table => {
+                    let stream: String = text
+                        .take()
+                        .unwrap_or_default()
+                        .chars()
+                        .filter(|c| !c.is_whitespace())
+                        .collect();
+                    let bytes = base64::engine::general_purpose::STANDARD
+                        .decode(stream)
+                        .map_err(|e| format!("error decoding stream: {}", e))?;
+                    decode_binary(&bytes, &fields, binary2, &mut rows)?;
+                }
+                // We only read the first table
+                "TABLE" => break,
+                _ => (),
+            },
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+
+    if fields.is_empty() {
+        return Err("no table found".to_string());
+    }
+    let columns = fields
+        .into_iter()
+        .map(|f| TableColumn {
+            name: f.name,
+            unit: f.unit,
+        })
+        .collect();
+    Ok(Table { columns, rows })
+}
+
+/// Decodes a BINARY or BINARY2 stream into rows.
+fn decode_binary(
+    bytes: &[u8],
+    fields: &[Field],
+    binary2: bool,
+    rows: &mut Vec<Vec<Option<String>>>,
+) -> Result<(), String> {
+    let truncated = || "binary stream is truncated".to_string();
+    let mask_len = if binary2 { fields.len().div_ceil(8) } else { 0 };
+    let mut pos: usize = 0;
+    while pos < bytes.len() {
+        let mask = bytes.get(pos..pos + mask_len).ok_or_else(truncated)?;
+        pos += mask_len;
+        let mut row = Vec::with_capacity(fields.len());
+        for (i, field) in fields.iter().enumerate() {
+            let count = if field.variable {
+                let prefix = bytes.get(pos..pos + 4).ok_or_else(truncated)?;
+                pos += 4;
+                u32::from_be_bytes(prefix.try_into().unwrap()) as usize * field.count
+            } else {
+                field.count
+            };
+            let size = if field.datatype == "bit" {
+                count.div_ceil(8)
+            } else {
+                count * field.element_size()
+            };
+            let value = bytes.get(pos..pos + size).ok_or_else(truncated)?;
+            pos += size;
+            let is_null = binary2 && mask[i / 8] & (0x80 >> (i % 8)) != 0;
+            if is_null || size == 0 {
+                row.push(None);
+            } else {
+                row.push(field.binary_value(value, count));
+            }
+        }
+        rows.push(row);
+    }
+    Ok(())
+}
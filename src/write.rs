@@ -1,4 +1,5 @@
 use crate::data;
+use crate::load;
 use crate::lod;
 
 use memmap::MmapMut;
@@ -6,7 +7,8 @@ use std::fs::OpenOptions;
 use std::io::Write;
 use std::path::Path;
 
-use data::Particle;
+use data::StarSource;
+use load::Epochs;
 use lod::Octree;
 
 pub fn write_metadata(octree: &Octree, output_dir: &str) {
@@ -92,8 +94,43 @@ pub fn write_metadata(octree: &Octree, output_dir: &str) {
     )
 }
 
+/// Writes the epoch of the star positions to catalog.json, to be used
+/// when packing the catalog. The epoch is only written when all the stars
+/// share it, and marked as "mixed" otherwise.
+pub fn write_catalog_info(epochs: Epochs, output_dir: &str) {
+    let epoch = match epochs {
+        Epochs::Single(epoch) => {
+            log::info!(
+                ":: Writing catalog info (epoch J{}) to {}/catalog.json",
+                epoch,
+                output_dir
+            );
+            format!("{:?}", epoch)
+        }
+        Epochs::Mixed => {
+            log::warn!(
+                ":: The stars are at different epochs, use --targetepoch to propagate them to the same one. Epoch of {}/catalog.json marked as mixed",
+                output_dir
+            );
+            "\"mixed\"".to_string()
+        }
+        Epochs::None => {
+            log::warn!(
+                ":: No stars loaded, no epoch in {}/catalog.json",
+                output_dir
+            );
+            "null".to_string()
+        }
+    };
+    std::fs::write(
+        format!("{}/{}", output_dir, "catalog.json"),
+        format!("{{\n  \"epoch\": {}\n}}\n", epoch),
+    )
+    .expect("Error writing catalog info");
+}
+
 #[allow(dead_code)]
-pub fn write_particles(octree: &Octree, list: Vec<Particle>, output_dir: &str) {
+pub fn write_particles<S: StarSource>(octree: &Octree, list: S, output_dir: &str) {
     let mut file_num = 0;
     let mut written = 1;
     let n_files = octree.nodes.borrow()[0].num_children_rec.get();
@@ -134,9 +171,7 @@ pub fn write_particles(octree: &Octree, list: Vec<Particle>, output_dir: &str) {
         // Particles
         for star_idx in node.objects.borrow().iter() {
             if list.len() > *star_idx {
-                let sb = list
-                    .get(*star_idx)
-                    .expect(&format!("Star not found: {}", *star_idx));
+                let sb = list.get(*star_idx);
 
                 // 64-bit floats
                 f.write_all(&(sb.x).to_be_bytes()).expect("Error writing");
@@ -167,12 +202,7 @@ pub fn write_particles(octree: &Octree, list: Vec<Particle>, output_dir: &str) {
                 f.write_all(&(sb.id).to_be_bytes()).expect("Error writing");
 
                 // Names
-                let mut names_concat = String::new();
-                for name in sb.names.iter() {
-                    names_concat.push_str(name);
-                    names_concat.push_str("|");
-                }
-                names_concat.pop();
+                let names_concat = sb.names.join("|");
 
                 // Names length
                 f.write_all(&(names_concat.len() as i16).to_be_bytes())
@@ -198,7 +228,7 @@ pub fn write_particles(octree: &Octree, list: Vec<Particle>, output_dir: &str) {
 }
 
 #[allow(dead_code)]
-pub fn write_particles_mmap(octree: &Octree, list: Vec<Particle>, output_dir: &str) {
+pub fn write_particles_mmap<S: StarSource>(octree: &Octree, list: S, output_dir: &str) {
     let mut file_num = 0;
     let mut written = 1;
     let n_files = octree.nodes.borrow()[0].num_children_rec.get();
@@ -214,9 +244,7 @@ pub fn write_particles_mmap(octree: &Octree, list: Vec<Particle>, output_dir: &s
         // particles
         for star_idx in node.objects.borrow().iter() {
             if list.len() > *star_idx {
-                let sb = list
-                    .get(*star_idx)
-                    .expect(&format!("Star not found: {}", *star_idx));
+                let sb = list.get(*star_idx);
 
                 // 3 * f64
                 size += 8 * 3;
@@ -228,13 +256,7 @@ pub fn write_particles_mmap(octree: &Octree, list: Vec<Particle>, output_dir: &s
                 // 1 * i32 name_len
                 size += 4 * 1;
 
-                let mut name_size = 0;
-                for name in sb.names.iter() {
-                    name_size += name.len() + 1;
-                }
-                if name_size > 0 {
-                    name_size -= 1;
-                }
+                let name_size = sb.names.join("|").len();
                 // 1 * u16 * name_len
                 size += 2 * name_size;
             }
@@ -290,9 +312,7 @@ pub fn write_particles_mmap(octree: &Octree, list: Vec<Particle>, output_dir: &s
         // Particles
         for star_idx in node.objects.borrow().iter() {
             if list.len() > *star_idx {
-                let sb = list
-                    .get(*star_idx)
-                    .expect(&format!("Star not found: {}", *star_idx));
+                let sb = list.get(*star_idx);
 
                 // 64-bit floats
                 (&mut mmap[i..i + 8])
@@ -361,12 +381,7 @@ pub fn write_particles_mmap(octree: &Octree, list: Vec<Particle>, output_dir: &s
                 i += 8;
 
                 // Names
-                let mut names_concat = String::new();
-                for name in sb.names.iter() {
-                    names_concat.push_str(name);
-                    names_concat.push_str("|");
-                }
-                names_concat.pop();
+                let names_concat = sb.names.join("|");
 
                 // Names length
                 (&mut mmap[i..i + 4])
@@ -1,25 +1,512 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io;
-use std::io::BufRead;
+use std::io::{BufRead, Write};
 
-use crate::parse;
+use flate2::read::GzDecoder;
 
-pub fn load_xmatch(file: &str, map: &mut HashMap<i64, i32>) {
-    // Read plain text file
-    let f = File::open(file).expect("Error: file not found");
-    let mut total = 0;
+use crate::constants;
+use crate::data::{Particle, StarStore};
+use crate::merge::{Field, MergeCounts, MergePolicy};
+use crate::util;
 
-    for line in io::BufReader::new(f).lines() {
-        let l = line.expect("Error reading line");
-        let tokens: Vec<&str> = l.split(',').collect();
+/**
+ * A conflicting entry of the crossmatch file. One-to-many conflicts are
+ * Gaia stars matched to several stars of the other catalog, and many-to-one
+ * conflicts are stars of the other catalog matched to several Gaia stars.
+ * Only the first entry of each conflict is kept.
+ **/
+pub struct XmatchConflict {
+    pub kind: &'static str,
+    pub source_id: i64,
+    pub id: i64,
+    pub kept: bool,
+}
+
+/// Loads the crossmatch file (source_id to the id of the other catalog, like
+/// the HIP number) into the map. The file may be gzipped, and its values
+/// separated by commas or whitespace. If there is a header row, the source_id
+/// and id columns are looked up in it, otherwise they are the first and second
+/// columns. Returns the conflicting entries.
+pub fn load_xmatch(file: &str, map: &mut HashMap<i64, i64>) -> Result<Vec<XmatchConflict>, String> {
+    let f = File::open(file).map_err(|e| format!("{}: {}", file, e))?;
+    let reader: Box<dyn BufRead> = if file.ends_with(".gz") {
+        Box::new(io::BufReader::new(GzDecoder::new(f)))
+    } else {
+        Box::new(io::BufReader::new(f))
+    };
+    let conflicts = parse_xmatch(reader, map).map_err(|e| format!("{}: {}", file, e))?;
+    log::info!("{} records loaded from {}", map.len(), file);
+    Ok(conflicts)
+}
+
+/// Parses the crossmatch in the given reader. See load_xmatch().
+pub fn parse_xmatch(
+    reader: Box<dyn BufRead>,
+    map: &mut HashMap<i64, i64>,
+) -> Result<Vec<XmatchConflict>, String> {
+    let mut conflicts = Vec::new();
+    // Source ID of each id of the other catalog in the map
+    let mut id_sources: HashMap<i64, i64> = HashMap::new();
+    let mut reported: HashSet<(i64, i64)> = HashSet::new();
+    let (mut source_col, mut id_col) = (0, 1);
+    let (mut first, mut malformed, mut duplicates) = (true, 0, 0);
+
+    for line in reader.lines() {
+        let l = line.map_err(|e| e.to_string())?;
+        let tokens: Vec<&str> = l
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|t| !t.is_empty())
+            .collect();
+        if tokens.is_empty() || tokens[0].starts_with('#') {
+            continue;
+        }
+        if first {
+            first = false;
+            if tokens[0].parse::<i64>().is_err() {
+                // Header row
+                let find = |names: &[&str]| {
+                    tokens
+                        .iter()
+                        .position(|t| names.contains(&t.to_lowercase().as_str()))
+                };
+                match (
+                    find(&["source_id", "sourceid", "gaia_source_id"]),
+                    find(&["hip", "hip_id", "original_ext_source_id", "id"]),
+                ) {
+                    (Some(s), Some(i)) => (source_col, id_col) = (s, i),
+                    _ => log::warn!(
+                        "Crossmatch header {:?} has no source_id and id columns, using the first two",
+                        tokens
+                    ),
+                }
+                continue;
+            }
+        }
+
+        let source_id = tokens.get(source_col).and_then(|t| t.parse::<i64>().ok());
+        let id = tokens.get(id_col).and_then(|t| t.parse::<i64>().ok());
+        let (source_id, id) = match (source_id, id) {
+            (Some(s), Some(i)) if s > 0 && i > 0 => (s, i),
+            _ => {
+                if malformed < 10 {
+                    log::warn!("Skipping malformed crossmatch line: {}", l);
+                }
+                malformed += 1;
+                continue;
+            }
+        };
+
+        let mut conflict = |kind, kept_source: i64, kept_id: i64| {
+            if reported.insert((kept_source, kept_id)) {
+                conflicts.push(XmatchConflict {
+                    kind,
+                    source_id: kept_source,
+                    id: kept_id,
+                    kept: true,
+                });
+            }
+            conflicts.push(XmatchConflict {
+                kind,
+                source_id,
+                id,
+                kept: false,
+            });
+        };
+        match (map.get(&source_id), id_sources.get(&id)) {
+            (Some(i), _) if *i == id => duplicates += 1,
+            (Some(i), _) => conflict("one-to-many", source_id, *i),
+            (None, Some(s)) => conflict("many-to-one", *s, id),
+            (None, None) => {
+                map.insert(source_id, id);
+                id_sources.insert(id, source_id);
+            }
+        }
+    }
+
+    if malformed > 0 {
+        log::warn!("Skipped {} malformed crossmatch lines", malformed);
+    }
+    if duplicates > 0 {
+        log::info!("Skipped {} duplicated crossmatch entries", duplicates);
+    }
+    if !conflicts.is_empty() {
+        log::warn!(
+            "Found {} conflicting crossmatch entries ({} ignored)",
+            conflicts.len(),
+            conflicts.iter().filter(|c| !c.kept).count()
+        );
+    }
+    Ok(conflicts)
+}
+
+/// Writes the conflicting crossmatch entries to a csv file.
+pub fn write_conflicts(conflicts: &[XmatchConflict], file: &str) {
+    let mut f = io::BufWriter::new(
+        File::create(file).unwrap_or_else(|e| panic!("Error creating {}: {}", file, e)),
+    );
+    writeln!(f, "conflict,source_id,id,kept").expect("Error writing");
+    for c in conflicts {
+        writeln!(f, "{},{},{},{}", c.kind, c.source_id, c.id, c.kept).expect("Error writing");
+    }
+    log::info!("Crossmatch conflicts written to {}", file);
+}
+
+/// How the Gaia stars are crossmatched to the stars of another catalog.
+pub enum Xmatch<'a> {
+    // Crossmatch table from source_id to the id of the other catalog
+    Table(HashMap<i64, i64>),
+    Positional(PositionalXmatch<'a>),
+}
+
+/**
+ * Merges Gaia stars with the stars of a secondary catalog (Hipparcos,
+ * Tycho-2, a list of nearby stars...) they are crossmatched to, following
+ * the given merge policy. The secondary stars are identified by their id,
+ * which is the HIP number for Hipparcos.
+ **/
+pub struct CatalogMerger<'a> {
+    pub name: String,
+    list: &'a StarStore,
+    // Map from id to index in list
+    id_map: HashMap<i64, usize>,
+    added: HashSet<i64>,
+    xmatch: Xmatch<'a>,
+    policy: MergePolicy,
+    pub counts: MergeCounts,
+    pub hit: usize,
+    // Counterparts of Gaia stars already merged with a catalog of higher precedence
+    pub duplicates: usize,
+}
+
+impl<'a> CatalogMerger<'a> {
+    pub fn new(name: &str, list: &'a StarStore, xmatch: Xmatch<'a>, policy: MergePolicy) -> Self {
+        let mut id_map: HashMap<i64, usize> = HashMap::new();
+        for (i, id) in list.id.iter().enumerate() {
+            id_map.insert(*id, i);
+        }
+        log::info!("{} stars added to the {} map", id_map.len(), name);
+        CatalogMerger {
+            name: name.to_string(),
+            list,
+            id_map,
+            added: HashSet::new(),
+            xmatch,
+            policy,
+            counts: MergeCounts::default(),
+            hit: 0,
+            duplicates: 0,
+        }
+    }
+
+    pub fn log_stats(&self) {
+        log::info!(
+            "{}: {} hits, {} duplicates.",
+            self.name,
+            self.hit,
+            self.duplicates
+        );
+        if let Xmatch::Positional(positional) = &self.xmatch {
+            positional.log_stats();
+        }
+        log::info!("Merge policy: {}", self.policy);
+        for field in Field::ALL {
+            let (gaia, other, both) = self.counts.get(field);
+            log::info!(
+                "   - {}: {} from gaia, {} from {}, {} from both",
+                field.to_str(),
+                gaia,
+                other,
+                self.name,
+                both
+            );
+        }
+    }
+}
+
+/**
+ * Merges Gaia stars with any number of secondary catalogs, in precedence
+ * order. Each Gaia star is merged with its counterpart in the first catalog
+ * that has one, and its counterparts in the rest of catalogs are dropped as
+ * duplicates. The Gaia stars may come in several chunks; the secondary stars
+ * which are not matched by any of them are added at the end with add_rest().
+ * Secondary catalogs are not crossmatched among themselves.
+ **/
+pub struct Merger<'a> {
+    pub catalogs: Vec<CatalogMerger<'a>>,
+    pub no_hit: usize,
+}
+
+impl<'a> Merger<'a> {
+    pub fn new(catalogs: Vec<CatalogMerger<'a>>) -> Self {
+        Merger {
+            catalogs,
+            no_hit: 0,
+        }
+    }
 
-        let source_id = parse::parse_i64(tokens.get(0));
-        let hip = parse::parse_i32(tokens.get(1));
+    /// Merges the given Gaia stars and adds them to main_list. Gaia stars
+    /// crossmatched to a star that has not been loaded are dropped.
+    pub fn merge(&mut self, list_gaia: &StarStore, main_list: &mut StarStore) {
+        // Crossmatch of this chunk for the positional crossmatches
+        let chunk_maps: Vec<Option<HashMap<i64, i64>>> = self
+            .catalogs
+            .iter_mut()
+            .map(|catalog| match &mut catalog.xmatch {
+                Xmatch::Positional(positional) => Some(positional.match_stars(list_gaia)),
+                Xmatch::Table(_) => None,
+            })
+            .collect();
 
-        map.insert(source_id, hip);
+        for gaia_i in 0..list_gaia.len() {
+            let gaia_id = list_gaia.id[gaia_i];
+            let mut merged: Option<Particle> = None;
+            let mut hit = false;
+            for (catalog, chunk_map) in self.catalogs.iter_mut().zip(&chunk_maps) {
+                let id = match (chunk_map, &catalog.xmatch) {
+                    (Some(map), _) | (None, Xmatch::Table(map)) => map.get(&gaia_id).copied(),
+                    _ => None,
+                };
+                let Some(id) = id else { continue };
+                hit = true;
+                catalog.hit += 1;
+                if let Some(i) = catalog.id_map.get(&id) {
+                    catalog.added.insert(id);
+                    if merged.is_none() {
+                        let gaia_star = list_gaia.get(gaia_i);
+                        let star = catalog.list.get(*i);
+                        merged = Some(catalog.policy.merge(&gaia_star, &star, &mut catalog.counts));
+                    } else {
+                        catalog.duplicates += 1;
+                    }
+                }
+            }
+            match merged {
+                Some(star) => main_list.push(star),
+                None if !hit => {
+                    // No hit, add directly to main list
+                    main_list.push(list_gaia.get(gaia_i));
+                    self.no_hit += 1;
+                }
+                None => (),
+            }
+        }
+    }
+
+    /// Adds the secondary stars that have not been merged to main_list.
+    pub fn add_rest(&self, main_list: &mut StarStore) {
+        for catalog in &self.catalogs {
+            for i in 0..catalog.list.len() {
+                if !catalog.added.contains(&catalog.list.id[i]) {
+                    main_list.push(catalog.list.get(i));
+                }
+            }
+        }
+    }
 
-        total += 1;
+    pub fn log_stats(&self) {
+        log::info!("{} Gaia stars without counterpart.", self.no_hit);
+        for catalog in &self.catalogs {
+            catalog.log_stats();
+        }
     }
-    log::info!("{} records loaded from {}", total, file);
+}
+
+/**
+ * Positional crossmatch between Gaia and another catalog, for when there is
+ * no crossmatch table. The positions of the other catalog are propagated to
+ * the Gaia epoch and put in a grid of cells of the size of the search radius,
+ * so that each cone search only looks at the neighbouring cells.
+ * If a maximum magnitude difference is given, matches are also required
+ * to have similar magnitudes, which only makes sense if both catalogs have
+ * G magnitudes (Hipparcos Hp and V differ from G depending on the colour).
+ * The Gaia stars
+ * may come in several chunks; each star of the other catalog is matched
+ * at most once.
+ **/
+pub struct PositionalXmatch<'a> {
+    list: &'a StarStore,
+    // Unit vectors of the stars at the Gaia epoch
+    dirs: Vec<[f64; 3]>,
+    grid: HashMap<[i64; 3], Vec<usize>>,
+    // Search radius as a chord length on the unit sphere
+    chord: f64,
+    max_dmag: f32,
+    matched: HashSet<i64>,
+    pub n_matches: usize,
+    pub n_ambiguous: usize,
+    pub n_rejected_mag: usize,
+}
+
+impl<'a> PositionalXmatch<'a> {
+    /// Creates the crossmatch with the given radius in arcseconds and maximum
+    /// magnitude difference, which is infinite for no magnitude check. dt is the time in years from the epoch of the
+    /// positions of the list to the epoch of the Gaia positions.
+    pub fn new(list: &'a StarStore, radius: f64, max_dmag: f32, dt: f64) -> Self {
+        let chord = 2.0 * ((radius / 3600.0).to_radians() / 2.0).sin();
+        let mut dirs = Vec::with_capacity(list.len());
+        let mut grid: HashMap<[i64; 3], Vec<usize>> = HashMap::new();
+        for i in 0..list.len() {
+            let sph = util::cartesian_to_spherical(list.x[i], list.y[i], list.z[i]);
+            let (mut ra, mut dec) = (sph.x, sph.y);
+            if dt != 0.0 && list.mualpha[i].is_finite() && list.mudelta[i].is_finite() {
+                let plx = 1000.0 / (sph.z * constants::U_TO_PC);
+                (ra, dec, ..) = util::propagate_epoch(
+                    ra,
+                    dec,
+                    plx,
+                    list.mualpha[i] as f64,
+                    list.mudelta[i] as f64,
+                    f64::NAN,
+                    dt,
+                );
+            }
+            let dir = util::spherical_to_cartesian(ra, dec, 1.0);
+            let dir = [dir.x, dir.y, dir.z];
+            grid.entry(cell(&dir, chord)).or_default().push(i);
+            dirs.push(dir);
+        }
+        if max_dmag.is_finite() {
+            log::info!(
+                "Positional crossmatch with radius {} arcsec and maximum magnitude difference {}",
+                radius,
+                max_dmag
+            );
+        } else {
+            log::info!(
+                "Positional crossmatch with radius {} arcsec, without magnitude check",
+                radius
+            );
+        }
+        PositionalXmatch {
+            list,
+            dirs,
+            grid,
+            chord,
+            max_dmag,
+            matched: HashSet::new(),
+            n_matches: 0,
+            n_ambiguous: 0,
+            n_rejected_mag: 0,
+        }
+    }
+
+    /// Matches the given Gaia stars, and returns the map from source_id to the
+    /// id of the other catalog that the merger uses. When several candidates are
+    /// found, the closest is taken and the match is logged as ambiguous.
+    pub fn match_stars(&mut self, list_gaia: &StarStore) -> HashMap<i64, i64> {
+        // Gaia candidates (distance, index) of each star of the other catalog
+        let mut candidates: HashMap<usize, Vec<(f64, usize)>> = HashMap::new();
+        for gaia_i in 0..list_gaia.len() {
+            let (x, y, z) = (
+                list_gaia.x[gaia_i],
+                list_gaia.y[gaia_i],
+                list_gaia.z[gaia_i],
+            );
+            let len = (x * x + y * y + z * z).sqrt();
+            if len == 0.0 || !len.is_finite() {
+                continue;
+            }
+            let dir = [x / len, y / len, z / len];
+            let mut hits = self.cone_search(&dir, list_gaia.appmag[gaia_i]);
+            if hits.is_empty() {
+                continue;
+            }
+            hits.sort_by(|a, b| a.0.total_cmp(&b.0));
+            if hits.len() > 1 {
+                self.n_ambiguous += 1;
+                log::info!(
+                    "Ambiguous crossmatch: Gaia {} has {} candidates {:?}, taking the closest",
+                    list_gaia.id[gaia_i],
+                    hits.len(),
+                    hits.iter()
+                        .map(|(_, i)| self.list.id[*i])
+                        .collect::<Vec<i64>>()
+                );
+            }
+            let (dist, i) = hits[0];
+            candidates.entry(i).or_default().push((dist, gaia_i));
+        }
+
+        let mut map = HashMap::new();
+        for (i, mut gaia_candidates) in candidates {
+            let id = self.list.id[i];
+            gaia_candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+            let gaia_ids: Vec<i64> = gaia_candidates
+                .iter()
+                .map(|(_, gaia_i)| list_gaia.id[*gaia_i])
+                .collect();
+            if self.matched.contains(&id) {
+                self.n_ambiguous += 1;
+                log::info!(
+                    "Ambiguous crossmatch: {} was already matched, ignoring Gaia candidates {:?}",
+                    id,
+                    gaia_ids
+                );
+                continue;
+            }
+            if gaia_ids.len() > 1 {
+                self.n_ambiguous += 1;
+                log::info!(
+                    "Ambiguous crossmatch: {} has {} Gaia candidates {:?}, taking the closest",
+                    id,
+                    gaia_ids.len(),
+                    gaia_ids
+                );
+            }
+            self.matched.insert(id);
+            map.insert(gaia_ids[0], id);
+        }
+        self.n_matches += map.len();
+        map
+    }
+
+    /// Gets the stars (chord distance, index) within the search radius
+    /// of the given direction which pass the magnitude check.
+    fn cone_search(&mut self, dir: &[f64; 3], appmag: f32) -> Vec<(f64, usize)> {
+        let c = cell(dir, self.chord);
+        let mut hits = Vec::new();
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                for dz in -1..=1 {
+                    let key = [c[0] + dx, c[1] + dy, c[2] + dz];
+                    for i in self.grid.get(&key).into_iter().flatten() {
+                        let h = &self.dirs[*i];
+                        let d = ((dir[0] - h[0]).powi(2)
+                            + (dir[1] - h[1]).powi(2)
+                            + (dir[2] - h[2]).powi(2))
+                        .sqrt();
+                        if d > self.chord {
+                            continue;
+                        }
+                        let dmag = (appmag - self.list.appmag[*i]).abs();
+                        if dmag > self.max_dmag {
+                            self.n_rejected_mag += 1;
+                            continue;
+                        }
+                        hits.push((d, *i));
+                    }
+                }
+            }
+        }
+        hits
+    }
+
+    pub fn log_stats(&self) {
+        log::info!(
+            "Positional crossmatch: {} matches, {} ambiguous, {} candidates rejected due to magnitude difference.",
+            self.n_matches,
+            self.n_ambiguous,
+            self.n_rejected_mag
+        );
+    }
+}
+
+/// Gets the grid cell of a unit vector.
+fn cell(dir: &[f64; 3], size: f64) -> [i64; 3] {
+    [
+        (dir[0] / size).floor() as i64,
+        (dir[1] / size).floor() as i64,
+        (dir[2] / size).floor() as i64,
+    ]
 }
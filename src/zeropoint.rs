@@ -0,0 +1,144 @@
+//! Parallax zero point of Gaia (E)DR3 as a function of magnitude, colour,
+//! ecliptic latitude and astrometric solution type, following the recipe of
+//! Lindegren et al. (2021), A&A 649, A4.
+
+// Obliquity of the ecliptic (ICRS), in degrees
+const OBLIQUITY: f64 = 23.439_291_1;
+
+// Magnitude nodes of the coefficient tables
+const G_NODES: [f64; 13] = [
+    6.0, 10.8, 11.2, 11.8, 12.2, 12.9, 13.1, 15.9, 16.1, 17.5, 19.0, 20.0, 21.0,
+];
+
+// Coefficients q00, q01, q02, q10, q11, q12, q20, q30, q40 (uas) at each
+// magnitude node for five-parameter solutions (Table 9 in the paper)
+const Q_5P: [[f64; 9]; 13] = [
+    [-26.98, -9.62, 27.40, -25.1, -0.0, 0.0, -1257.0, 0.0, 0.0],
+    [-27.23, -3.07, 23.04, 35.3, 15.7, 0.0, -1257.0, 0.0, 0.0],
+    [-30.33, -9.23, 9.08, -88.4, -11.8, 0.0, -1257.0, 0.0, 0.0],
+    [-33.54, -10.08, 13.28, -126.7, 11.6, 0.0, -1257.0, 0.0, 0.0],
+    [-13.65, -0.07, 9.35, -111.4, 40.6, 0.0, -1257.0, 0.0, 0.0],
+    [-19.53, -1.64, 15.86, -66.8, 20.6, 0.0, -1257.0, 0.0, 0.0],
+    [-37.99, 2.63, 16.14, -5.7, 14.0, 0.0, -1257.0, 107.9, 104.3],
+    [-38.33, 5.61, 15.42, 0.0, 18.7, 0.0, -1189.0, 243.8, 155.2],
+    [-36.83, 1.11, 14.67, 0.0, 27.3, 0.0, -1404.0, 105.5, 170.7],
+    [-28.37, -2.05, 10.77, 0.0, 22.1, 0.0, -1165.0, 189.7, 325.0],
+    [-18.07, -1.86, 10.20, 0.0, 8.0, 0.0, 0.0, 0.0, 0.0],
+    [-12.65, -6.93, -1.96, 0.0, 8.7, 0.0, 0.0, 0.0, 0.0],
+    [-18.22, -12.13, -4.86, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+];
+
+// Same for six-parameter solutions (Table 10 in the paper)
+const Q_6P: [[f64; 9]; 13] = [
+    [-27.85, -7.78, 27.47, -32.1, 14.4, 9.5, -67.0, 0.0, 0.0],
+    [-28.91, -3.57, 22.92, 7.7, 12.6, 1.6, -572.0, 0.0, 0.0],
+    [-26.72, -8.74, 9.36, -30.3, 5.6, 17.2, -1104.0, 0.0, 0.0],
+    [-29.04, -9.69, 13.63, -49.4, 36.3, 17.7, -1129.0, 0.0, 0.0],
+    [-12.39, -2.16, 10.23, -92.6, 19.8, 27.6, -365.0, 0.0, 0.0],
+    [-18.99, -1.93, 15.90, -57.2, -8.0, 19.9, -554.0, 0.0, 0.0],
+    [-38.29, 2.59, 16.20, -10.5, 1.4, 0.4, -960.0, 0.0, 0.0],
+    [-36.83, 4.20, 15.76, 22.3, 11.1, 10.0, -1367.0, 0.0, 0.0],
+    [-28.37, 1.99, 9.28, 50.4, 17.2, 13.7, -1351.0, 0.0, 0.0],
+    [-24.68, -1.37, 3.52, 86.8, 19.8, 21.3, -1380.0, 0.0, 0.0],
+    [-15.32, 4.01, 7.44, 29.2, 14.1, 0.4, -563.0, 0.0, 0.0],
+    [-13.73, -10.92, -9.23, -74.4, 196.4, -42.0, 536.0, 0.0, 0.0],
+    [
+        -29.53, -20.34, -18.74, -39.5, 326.8, -262.3, 1598.0, 0.0, 0.0,
+    ],
+];
+
+// Calibrated range of nu_eff_used_in_astrometry (five-parameter solutions)
+// and pseudocolour (six-parameter solutions), in 1/um
+const NU_EFF_RANGE: (f64, f64) = (1.1, 1.9);
+const PSEUDOCOLOUR_RANGE: (f64, f64) = (1.24, 1.72);
+
+/// Astrometric solution type, as given by astrometric_params_solved.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Solution {
+    FiveParam,
+    SixParam,
+}
+
+impl Solution {
+    /// Gets the solution type from the astrometric_params_solved value
+    /// (31 or 95). Two-parameter solutions (3) have no zero point.
+    pub fn from_params_solved(params_solved: i32) -> Option<Solution> {
+        match params_solved {
+            31 => Some(Solution::FiveParam),
+            95 => Some(Solution::SixParam),
+            _ => None,
+        }
+    }
+}
+
+/// Parallax zero point in mas, and whether the magnitude and colour of
+/// the star are in the range where it was calibrated. Outside of it, the
+/// magnitude and colour are clipped to the range.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ZeroPoint {
+    pub value: f64,
+    pub calibrated: bool,
+}
+
+/// Computes the ecliptic latitude in degrees from the equatorial
+/// coordinates in degrees.
+pub fn ecliptic_latitude(ra: f64, dec: f64) -> f64 {
+    let (ra, dec, eps) = (ra.to_radians(), dec.to_radians(), OBLIQUITY.to_radians());
+    (dec.sin() * eps.cos() - dec.cos() * eps.sin() * ra.sin())
+        .asin()
+        .to_degrees()
+}
+
+/// Computes the parallax zero point in mas, to be subtracted from the
+/// catalog parallax. The colour is nu_eff_used_in_astrometry for
+/// five-parameter solutions and pseudocolour for six-parameter solutions,
+/// both in 1/um. Returns None if any of the inputs is not finite.
+pub fn zero_point(gmag: f64, colour: f64, ecl_lat: f64, solution: Solution) -> Option<ZeroPoint> {
+    if !gmag.is_finite() || !colour.is_finite() || !ecl_lat.is_finite() {
+        return None;
+    }
+    let (table, colour_range) = match solution {
+        Solution::FiveParam => (&Q_5P, NU_EFF_RANGE),
+        Solution::SixParam => (&Q_6P, PSEUDOCOLOUR_RANGE),
+    };
+    let g_range = (G_NODES[0], G_NODES[G_NODES.len() - 1]);
+    let calibrated = (g_range.0..=g_range.1).contains(&gmag)
+        && (colour_range.0..=colour_range.1).contains(&colour);
+    let colour = colour.clamp(colour_range.0, colour_range.1);
+
+    // Colour basis functions
+    let c = [
+        1.0,
+        (colour - 1.48).clamp(-0.24, 0.24),
+        (1.48 - colour).clamp(0.0, 0.24).powi(3),
+        (colour - 1.24).min(0.0),
+        (colour - 1.72).max(0.0),
+    ];
+    // Ecliptic latitude basis functions
+    let sin_beta = ecl_lat.to_radians().sin();
+    let b = [1.0, sin_beta, sin_beta * sin_beta - 1.0 / 3.0];
+
+    // Linear interpolation of the coefficients in magnitude
+    let g = gmag.clamp(g_range.0, g_range.1);
+    let j = G_NODES[..G_NODES.len() - 1]
+        .iter()
+        .rposition(|node| *node <= g)
+        .unwrap_or(0);
+    let h = (g - G_NODES[j]) / (G_NODES[j + 1] - G_NODES[j]);
+    let q = |k: usize| (1.0 - h) * table[j][k] + h * table[j + 1][k];
+
+    let zpt = q(0) * c[0] * b[0]
+        + q(1) * c[0] * b[1]
+        + q(2) * c[0] * b[2]
+        + q(3) * c[1] * b[0]
+        + q(4) * c[1] * b[1]
+        + q(5) * c[1] * b[2]
+        + q(6) * c[2] * b[0]
+        + q(7) * c[3] * b[0]
+        + q(8) * c[4] * b[0];
+    // uas to mas
+    Some(ZeroPoint {
+        value: zpt / 1000.0,
+        calibrated,
+    })
+}